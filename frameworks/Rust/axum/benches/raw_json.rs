@@ -0,0 +1,122 @@
+//! The raw-json path of `axum-mongo-raw` against its typed path, over the same
+//! raw `world` documents: `write_raw_world` writes the JSON straight from the
+//! BSON bytes, the typed path reads a `World` out of them with
+//! `world_from_raw` and serializes it with serde, as `JsonFast` does.
+//!
+//! ```text
+//! cargo bench --features raw-json --bench raw_json
+//! ```
+//!
+//! Only the encoding is measured: both paths look the documents up the same
+//! way, which needs a database.
+
+// the modules are shared with the binaries, and cargo compiles benches with
+// `cfg(test)`, which leaves their unit tests' imports unused
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/circuit_breaker.rs"]
+mod circuit_breaker;
+#[path = "../src/clock.rs"]
+mod clock;
+#[path = "../src/common/mod.rs"]
+mod common;
+#[path = "../src/database_mongo_raw.rs"]
+mod database_mongo_raw;
+#[path = "../src/deadline.rs"]
+mod deadline;
+#[path = "../src/diagnostics.rs"]
+mod diagnostics;
+#[path = "../src/models_common.rs"]
+mod models_common;
+#[path = "../src/models_mongo.rs"]
+mod models_mongo;
+#[path = "../src/totals.rs"]
+mod totals;
+#[path = "../src/utils.rs"]
+mod utils;
+#[path = "../src/write_batch.rs"]
+mod write_batch;
+
+use bytes::{BufMut, BytesMut};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use mongodb::bson::{rawdoc, RawDocumentBuf};
+use rand::{rngs::SmallRng, SeedableRng};
+
+pub use self::models_mongo::World;
+use self::{
+    database_mongo_raw::{world_from_raw, write_raw_world},
+    models_common::WorldId,
+    utils::{random_id, random_number},
+};
+
+/// `count` world documents as the TFB setup scripts store them, with the `id`
+/// copy next to `_id` as a double.
+fn documents(count: usize) -> Vec<(WorldId, RawDocumentBuf)> {
+    let mut rng = SmallRng::seed_from_u64(141);
+
+    (0..count)
+        .map(|_| {
+            let id = random_id(&mut rng);
+            let document = rawdoc! {
+                "_id": id.get(),
+                "id": f64::from(id.get()),
+                "randomNumber": random_number(&mut rng),
+            };
+            (id, document)
+        })
+        .collect()
+}
+
+/// Writes `documents` into the buffer as a JSON array.
+type Encode = fn(&[(WorldId, RawDocumentBuf)], &mut BytesMut);
+
+fn raw(documents: &[(WorldId, RawDocumentBuf)], out: &mut BytesMut) {
+    out.put_u8(b'[');
+    for (index, (id, document)) in documents.iter().enumerate() {
+        if index > 0 {
+            out.put_u8(b',');
+        }
+        write_raw_world(document, *id, out).unwrap();
+    }
+    out.put_u8(b']');
+}
+
+fn typed(documents: &[(WorldId, RawDocumentBuf)], out: &mut BytesMut) {
+    let worlds: Vec<World> = documents
+        .iter()
+        .map(|(id, document)| world_from_raw(document, *id).unwrap())
+        .collect();
+    serde_json::to_writer(out.writer(), &worlds).unwrap();
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+
+    for count in [1, 20, 500] {
+        let documents = documents(count);
+        let mut expected = (BytesMut::new(), BytesMut::new());
+        raw(&documents, &mut expected.0);
+        typed(&documents, &mut expected.1);
+        assert_eq!(expected.0, expected.1, "the two paths disagree");
+
+        let paths: [(&str, Encode); 2] = [("raw", raw), ("typed", typed)];
+        for (name, path) in paths {
+            group.bench_with_input(
+                BenchmarkId::new(name, count),
+                &documents,
+                |b, documents| {
+                    b.iter_batched_ref(
+                        || BytesMut::with_capacity(64 * 1024),
+                        |out| path(documents, out),
+                        BatchSize::SmallInput,
+                    )
+                },
+            );
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, encode);
+criterion_main!(benches);
@@ -0,0 +1,63 @@
+fn main() {
+    build_info();
+
+    #[cfg(feature = "grpc")]
+    grpc();
+}
+
+/// Records the commit and the enabled features for `/info`, see
+/// `src/common/info.rs`. `GIT_SHA` overrides the commit, for builds without a
+/// `.git` directory such as the Docker images.
+fn build_info() {
+    use std::{env, process::Command};
+
+    let sha = env::var("GIT_SHA").ok().or_else(|| {
+        let output = Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+        Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+    });
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            let feature = key.strip_prefix("CARGO_FEATURE_")?;
+            Some(feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+
+    println!(
+        "cargo:rustc-env=AXUM_TECHEMPOWER_BUILD_GIT_SHA={}",
+        sha.as_deref().unwrap_or("unknown")
+    );
+    println!(
+        "cargo:rustc-env=AXUM_TECHEMPOWER_BUILD_FEATURES={}",
+        features.join(",")
+    );
+}
+
+/// Generates the gRPC server from `proto/`, using `protox` so no `protoc`
+/// install is needed.
+#[cfg(feature = "grpc")]
+fn grpc() {
+    use std::{env, fs, path::PathBuf};
+
+    use prost::Message;
+
+    println!("cargo:rerun-if-changed=proto");
+
+    let descriptors = protox::compile(["benchmark.proto"], ["proto"])
+        .expect("could not compile proto/benchmark.proto");
+    let descriptors_path =
+        PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("benchmark.bin");
+    fs::write(&descriptors_path, descriptors.encode_to_vec()).unwrap();
+
+    tonic_build::configure()
+        .build_client(false)
+        .file_descriptor_set_path(&descriptors_path)
+        .skip_protoc_run()
+        .compile(&["benchmark.proto"], &["proto"])
+        .expect("could not generate the gRPC service");
+}
@@ -0,0 +1,88 @@
+//! Dedicated threads for the synchronous work of the request path, so it
+//! can't stall the async workers.
+//!
+//! `AXUM_TECHEMPOWER_BLOCKING_THREADS` (default 2) threads are started on
+//! first use, and jobs queue while they are all busy. They are separate from
+//! Tokio's blocking pool, which the database drivers use for DNS lookups and
+//! which grows to hundreds of threads, so the work sent here stays bounded
+//! and can't crowd that out.
+//!
+//! Today that work is the yarte rendering of fortunes tables with more than
+//! `AXUM_TECHEMPOWER_BLOCKING_RENDER_ROWS` (default 64) rows. Smaller tables,
+//! including the 12 rows of the TFB one, are rendered in place, where the
+//! hand-off would cost more than the rendering.
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex, OnceLock},
+    thread,
+};
+
+use tokio::sync::oneshot;
+
+use crate::utils::get_environment_variable_or;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+fn jobs() -> &'static mpsc::Sender<Job> {
+    static JOBS: OnceLock<mpsc::Sender<Job>> = OnceLock::new();
+
+    JOBS.get_or_init(|| {
+        let threads: usize =
+            get_environment_variable_or("AXUM_TECHEMPOWER_BLOCKING_THREADS", 2);
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for n in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("axum-blocking-{n}"))
+                .spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    let Ok(job) = job else { return };
+                    // a panicking job fails its own request, not the thread
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                })
+                .expect("could not start blocking thread");
+        }
+
+        sender
+    })
+}
+
+/// Runs `f` on one of the dedicated threads. Panics if `f` panics.
+pub async fn run<T, F>(f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (result, receiver) = oneshot::channel();
+    let job: Job = Box::new(move || {
+        let _ = result.send(f());
+    });
+
+    jobs().send(job).expect("blocking threads are gone");
+    receiver.await.expect("blocking job panicked")
+}
+
+fn render_rows() -> usize {
+    static RENDER_ROWS: OnceLock<usize> = OnceLock::new();
+
+    *RENDER_ROWS.get_or_init(|| {
+        get_environment_variable_or("AXUM_TECHEMPOWER_BLOCKING_RENDER_ROWS", 64)
+    })
+}
+
+/// Renders `rows`, in place if there are few enough of them and on the
+/// dedicated threads otherwise.
+pub async fn render<T, R>(rows: Vec<T>, render: fn(&[T]) -> R) -> R
+where
+    T: Send + 'static,
+    R: Send + 'static,
+{
+    if rows.len() <= render_rows() {
+        return render(&rows);
+    }
+
+    run(move || render(&rows)).await
+}
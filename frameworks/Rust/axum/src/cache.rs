@@ -0,0 +1,281 @@
+//! In-process copy of the `world` table, served by `/cached-queries`.
+//!
+//! By default the whole table is loaded at startup. With
+//! `AXUM_TECHEMPOWER_WORLD_CACHE_CAPACITY` set, `/cached-queries` instead goes
+//! through an `LruWorldCache` of that many worlds, which starts out empty:
+//! misses are read from the database and added, updates are written through,
+//! and the least recently used worlds are evicted once it is full. That makes
+//! the miss path part of the benchmark, as it would be for a real cache. The
+//! preloaded table is kept for `/events` either way.
+//!
+//! Both report `CacheStats` for `/admin/cache/stats`. The preloaded table
+//! doesn't count hits and misses: it holds every world, and counting would
+//! add a contended atomic to the hot path of `/cached-queries`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{
+    clock,
+    models_common::{WorldId, WORLD_COUNT},
+    utils::get_optional_environment_variable,
+};
+
+/// Worlds indexed by `id - 1`.
+///
+/// Readers take a snapshot of the current table, so a rebuild swaps the whole
+/// table at once and never exposes a partially loaded cache.
+pub struct WorldCache<W> {
+    worlds: RwLock<Arc<Vec<Option<W>>>>,
+    loaded_at: Mutex<Option<Instant>>,
+}
+
+impl<W> Default for WorldCache<W> {
+    fn default() -> Self {
+        Self {
+            worlds: RwLock::new(Arc::new(Vec::new())),
+            loaded_at: Mutex::new(None),
+        }
+    }
+}
+
+impl<W: Clone> WorldCache<W> {
+    /// Replaces the cached worlds, returning how many entries were dropped.
+    pub fn replace(&self, worlds: impl IntoIterator<Item = (WorldId, W)>) -> usize {
+        let mut table = vec![None; WORLD_COUNT as usize];
+        for (id, world) in worlds {
+            table[id.get() as usize - 1] = Some(world);
+        }
+
+        let previous = mem::replace(&mut *self.worlds.write().unwrap(), Arc::new(table));
+        *self.loaded_at.lock().unwrap() = Some(clock::now());
+        previous.iter().flatten().count()
+    }
+
+    pub fn get(&self, id: WorldId) -> Option<W> {
+        self.snapshot().get(id.get() as usize - 1)?.clone()
+    }
+
+    /// Looks up `ids`, skipping the ones missing from the cache.
+    pub fn get_many(&self, ids: impl IntoIterator<Item = WorldId>) -> Vec<W> {
+        let worlds = self.snapshot();
+
+        ids.into_iter()
+            .filter_map(|id| worlds.get(id.get() as usize - 1)?.clone())
+            .collect()
+    }
+
+    fn snapshot(&self) -> Arc<Vec<Option<W>>> {
+        self.worlds.read().unwrap().clone()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let worlds = self.snapshot();
+        let entries = worlds.iter().flatten().count();
+
+        let mut age_secs = AgeBuckets::default();
+        if let Some(loaded_at) = *self.loaded_at.lock().unwrap() {
+            age_secs.add(clock::now().duration_since(loaded_at), entries as u64);
+        }
+
+        CacheStats {
+            backend: "preloaded",
+            entries,
+            capacity: worlds.len(),
+            hits: None,
+            misses: None,
+            hit_ratio: None,
+            memory_bytes: worlds.capacity() * mem::size_of::<Option<W>>(),
+            age_secs,
+        }
+    }
+}
+
+/// What `/admin/cache/stats` reports about the cache `/cached-queries` uses.
+#[derive(Serialize)]
+pub struct CacheStats {
+    backend: &'static str,
+    entries: usize,
+    capacity: usize,
+    hits: Option<u64>,
+    misses: Option<u64>,
+    hit_ratio: Option<f64>,
+    /// Estimated from the size of the entries and of the index over them,
+    /// without the allocator's overhead.
+    memory_bytes: usize,
+    /// Entries by how long ago they were loaded or last written.
+    age_secs: AgeBuckets,
+}
+
+#[derive(Default, Serialize)]
+struct AgeBuckets {
+    #[serde(rename = "<1")]
+    under_1: u64,
+    #[serde(rename = "<10")]
+    under_10: u64,
+    #[serde(rename = "<60")]
+    under_60: u64,
+    #[serde(rename = "<600")]
+    under_600: u64,
+    #[serde(rename = ">=600")]
+    older: u64,
+}
+
+impl AgeBuckets {
+    fn add(&mut self, age: Duration, entries: u64) {
+        let bucket = match age.as_secs() {
+            0 => &mut self.under_1,
+            1..=9 => &mut self.under_10,
+            10..=59 => &mut self.under_60,
+            60..=599 => &mut self.under_600,
+            _ => &mut self.older,
+        };
+        *bucket += entries;
+    }
+}
+
+/// Capacity of the LRU cache, `None` to preload the whole table instead.
+#[allow(dead_code)]
+pub fn lru_capacity() -> Option<usize> {
+    get_optional_environment_variable::<usize>("AXUM_TECHEMPOWER_WORLD_CACHE_CAPACITY")
+        .filter(|&capacity| capacity > 0)
+}
+
+/// Bounded cache of the most recently used worlds, counting hits and misses.
+pub struct LruWorldCache<W> {
+    capacity: usize,
+    entries: Mutex<Lru<W>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Lru<W> {
+    /// Each world with the tick it was last used at and when it was written.
+    worlds: HashMap<WorldId, (W, u64, Instant)>,
+    /// Worlds by the tick they were last used at, oldest first.
+    order: BTreeMap<u64, WorldId>,
+    tick: u64,
+}
+
+impl<W> Lru<W> {
+    fn touch(&mut self, id: WorldId, last_used: u64) -> u64 {
+        self.order.remove(&last_used);
+        self.tick += 1;
+        self.order.insert(self.tick, id);
+        self.tick
+    }
+}
+
+#[allow(dead_code)]
+impl<W: Clone> LruWorldCache<W> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(Lru {
+                worlds: HashMap::with_capacity(capacity),
+                order: BTreeMap::new(),
+                tick: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Looks up `ids`, returning the cached worlds and the ids that missed.
+    pub fn get_many(
+        &self,
+        ids: impl IntoIterator<Item = WorldId>,
+    ) -> (Vec<W>, Vec<WorldId>) {
+        let mut found = Vec::new();
+        let mut missing = Vec::new();
+
+        {
+            let mut lru = self.entries.lock().unwrap();
+            for id in ids {
+                let Some(&(_, last_used, _)) = lru.worlds.get(&id) else {
+                    missing.push(id);
+                    continue;
+                };
+
+                let tick = lru.touch(id, last_used);
+                let (world, last_used, _) = lru.worlds.get_mut(&id).unwrap();
+                *last_used = tick;
+                found.push(world.clone());
+            }
+        }
+
+        self.hits.fetch_add(found.len() as u64, Ordering::Relaxed);
+        self.misses
+            .fetch_add(missing.len() as u64, Ordering::Relaxed);
+        (found, missing)
+    }
+
+    /// Adds or replaces `worlds`, evicting the least recently used ones beyond
+    /// the capacity.
+    pub fn insert_many(&self, worlds: impl IntoIterator<Item = (WorldId, W)>) {
+        let mut lru = self.entries.lock().unwrap();
+        let now = clock::now();
+
+        for (id, world) in worlds {
+            let last_used = lru.worlds.get(&id).map_or(0, |&(_, tick, _)| tick);
+            let tick = lru.touch(id, last_used);
+            lru.worlds.insert(id, (world, tick, now));
+
+            while lru.worlds.len() > self.capacity {
+                let (_, oldest) = lru.order.pop_first().unwrap();
+                lru.worlds.remove(&oldest);
+            }
+        }
+    }
+
+    /// Empties the cache, returning how many entries were dropped.
+    pub fn clear(&self) -> usize {
+        let mut lru = self.entries.lock().unwrap();
+        lru.order.clear();
+        mem::take(&mut lru.worlds).len()
+    }
+
+    /// Hits and misses since startup.
+    pub fn counters(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (hits, misses) = self.counters();
+        let lookups = hits + misses;
+
+        let lru = self.entries.lock().unwrap();
+        let now = clock::now();
+        let mut age_secs = AgeBuckets::default();
+        for (_, _, written) in lru.worlds.values() {
+            age_secs.add(now.duration_since(*written), 1);
+        }
+
+        let entry = mem::size_of::<(WorldId, (W, u64, Instant))>();
+        let order = mem::size_of::<(u64, WorldId)>();
+
+        CacheStats {
+            backend: "lru",
+            entries: lru.worlds.len(),
+            capacity: self.capacity,
+            hits: Some(hits),
+            misses: Some(misses),
+            hit_ratio: (lookups > 0).then_some(hits as f64 / lookups as f64),
+            // the map also keeps a control byte per slot
+            memory_bytes: lru.worlds.capacity() * (entry + 1) + lru.order.len() * order,
+            age_secs,
+        }
+    }
+}
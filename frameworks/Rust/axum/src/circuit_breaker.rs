@@ -0,0 +1,319 @@
+//! Circuit breaker in front of the database endpoints, enabled by setting
+//! `AXUM_TECHEMPOWER_BREAKER_ERROR_RATE` to a fraction, e.g. `0.5`.
+//!
+//! Responses of the endpoints that need the database are counted over windows
+//! of `AXUM_TECHEMPOWER_BREAKER_WINDOW_MS` (default 1000). A 5xx other than
+//! 501, which binaries without a database answer with, counts as a failure,
+//! which includes requests cut off by the request deadline and those
+//! that found no free pooled connection, as does a handler panicking, which
+//! is how most of them fail on a database error. Once a window has seen at least
+//! `AXUM_TECHEMPOWER_BREAKER_MIN_REQUESTS` (default 20) requests and the given
+//! share of them failed, the breaker opens: database requests are answered
+//! with 503 right away for `AXUM_TECHEMPOWER_BREAKER_OPEN_MS` (default 5000).
+//! After that a single request is let through as a probe while the others
+//! keep getting 503; the breaker closes if the probe succeeds and opens again
+//! otherwise.
+//!
+//! A database that goes away mid-run then costs one fast 503 per request
+//! instead of a pile of requests each waiting out its timeout.
+
+use std::{
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+
+use crate::{
+    clock,
+    common::endpoints::{Requires, ENDPOINTS},
+    utils::{get_environment_variable_or, get_optional_environment_variable},
+};
+
+struct Breaker {
+    error_rate: f64,
+    min_requests: u64,
+    window: Duration,
+    open_for: Duration,
+    state: Mutex<State>,
+}
+
+enum State {
+    Closed {
+        since: Instant,
+        requests: u64,
+        failures: u64,
+    },
+    Open {
+        until: Instant,
+    },
+    HalfOpen {
+        probing: bool,
+    },
+}
+
+impl State {
+    fn closed() -> Self {
+        State::Closed {
+            since: clock::now(),
+            requests: 0,
+            failures: 0,
+        }
+    }
+}
+
+/// What a request may do, decided before it runs.
+enum Admission {
+    Pass,
+    Probe,
+    Reject(Duration),
+}
+
+impl Breaker {
+    fn admit(&self) -> Admission {
+        let mut state = self.state.lock().unwrap();
+
+        match *state {
+            State::Closed { .. } => Admission::Pass,
+            State::Open { until } => {
+                let now = clock::now();
+                if now < until {
+                    return Admission::Reject(until - now);
+                }
+                *state = State::HalfOpen { probing: true };
+                Admission::Probe
+            }
+            State::HalfOpen { probing: false } => {
+                *state = State::HalfOpen { probing: true };
+                Admission::Probe
+            }
+            State::HalfOpen { probing: true } => Admission::Reject(Duration::ZERO),
+        }
+    }
+
+    fn record(&self, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+
+        let State::Closed {
+            since,
+            requests,
+            failures,
+        } = &mut *state
+        else {
+            return;
+        };
+
+        let now = clock::now();
+        if now.duration_since(*since) >= self.window {
+            *since = now;
+            *requests = 0;
+            *failures = 0;
+        }
+        *requests += 1;
+        *failures += u64::from(failed);
+
+        if *requests >= self.min_requests
+            && *failures as f64 >= *requests as f64 * self.error_rate
+        {
+            eprintln!(
+                "circuit breaker: opening for {:?}, {failures} of {requests} database \
+                 requests failed",
+                self.open_for
+            );
+            *state = State::Open {
+                until: clock::now() + self.open_for,
+            };
+        }
+    }
+
+    fn finish_probe(&self, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+
+        if failed {
+            eprintln!("circuit breaker: probe failed, staying open");
+            *state = State::Open {
+                until: clock::now() + self.open_for,
+            };
+        } else {
+            eprintln!("circuit breaker: probe succeeded, closing");
+            *state = State::closed();
+        }
+    }
+}
+
+/// Reports the outcome of an admitted request, including one that never
+/// produced a response: a panicking handler counts as a failure, while a
+/// request dropped by its client only lets the next request probe again.
+struct Admitted {
+    breaker: &'static Breaker,
+    probe: bool,
+    done: bool,
+}
+
+impl Admitted {
+    fn finish(mut self, failed: bool) {
+        self.done = true;
+        self.report(failed);
+    }
+
+    fn report(&self, failed: bool) {
+        if self.probe {
+            self.breaker.finish_probe(failed);
+        } else {
+            self.breaker.record(failed);
+        }
+    }
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        if std::thread::panicking() {
+            self.report(true);
+        } else if self.probe {
+            let mut state = self.breaker.state.lock().unwrap();
+            if let State::HalfOpen { probing } = &mut *state {
+                *probing = false;
+            }
+        }
+    }
+}
+
+fn breaker() -> Option<&'static Breaker> {
+    static BREAKER: OnceLock<Option<Breaker>> = OnceLock::new();
+
+    BREAKER
+        .get_or_init(|| {
+            let error_rate: f64 = get_optional_environment_variable(
+                "AXUM_TECHEMPOWER_BREAKER_ERROR_RATE",
+            )?;
+            let window_ms: u64 =
+                get_environment_variable_or("AXUM_TECHEMPOWER_BREAKER_WINDOW_MS", 1000);
+            let open_ms: u64 =
+                get_environment_variable_or("AXUM_TECHEMPOWER_BREAKER_OPEN_MS", 5000);
+
+            Some(Breaker {
+                error_rate: error_rate.clamp(0.0, 1.0),
+                min_requests: get_environment_variable_or(
+                    "AXUM_TECHEMPOWER_BREAKER_MIN_REQUESTS",
+                    20,
+                ),
+                window: Duration::from_millis(window_ms),
+                open_for: Duration::from_millis(open_ms),
+                state: Mutex::new(State::closed()),
+            })
+        })
+        .as_ref()
+}
+
+/// Puts the database endpoints of `router` behind the breaker, if enabled.
+pub fn layer(router: Router) -> Router {
+    if breaker().is_none() {
+        return router;
+    }
+
+    router.layer(middleware::from_fn(guard))
+}
+
+fn uses_database(path: &str) -> bool {
+    ENDPOINTS
+        .iter()
+        .any(|endpoint| endpoint.requires == Requires::Database && endpoint.path == path)
+}
+
+fn failed(status: StatusCode) -> bool {
+    status.is_server_error() && status != StatusCode::NOT_IMPLEMENTED
+}
+
+async fn guard(request: Request<Body>, next: Next<Body>) -> Response {
+    let Some(breaker) = breaker().filter(|_| uses_database(request.uri().path())) else {
+        return next.run(request).await;
+    };
+
+    let probe = match breaker.admit() {
+        Admission::Pass => false,
+        Admission::Probe => true,
+        Admission::Reject(retry_in) => {
+            let retry_after = retry_in.as_secs().max(1).to_string();
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, retry_after)],
+                "database circuit open",
+            )
+                .into_response();
+        }
+    };
+
+    let admitted = Admitted {
+        breaker,
+        probe,
+        done: false,
+    };
+    let response = next.run(request).await;
+    admitted.finish(failed(response.status()));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> Breaker {
+        Breaker {
+            error_rate: 0.5,
+            min_requests: 2,
+            window: Duration::from_secs(1),
+            open_for: Duration::from_secs(5),
+            state: Mutex::new(State::closed()),
+        }
+    }
+
+    #[test]
+    fn opens_then_probes_once_open_for_has_passed() {
+        let (_held, clock) = clock::mock::hold();
+        let breaker = breaker();
+
+        breaker.record(true);
+        assert!(matches!(breaker.admit(), Admission::Pass));
+        breaker.record(true);
+        assert!(matches!(breaker.admit(), Admission::Reject(retry_in)
+            if retry_in == Duration::from_secs(5)));
+
+        clock.advance(Duration::from_secs(4));
+        assert!(matches!(breaker.admit(), Admission::Reject(_)));
+
+        clock.advance(Duration::from_secs(1));
+        assert!(matches!(breaker.admit(), Admission::Probe));
+        assert!(matches!(breaker.admit(), Admission::Reject(Duration::ZERO)));
+
+        breaker.finish_probe(true);
+        assert!(matches!(breaker.admit(), Admission::Reject(_)));
+
+        clock.advance(Duration::from_secs(5));
+        assert!(matches!(breaker.admit(), Admission::Probe));
+        breaker.finish_probe(false);
+        assert!(matches!(breaker.admit(), Admission::Pass));
+    }
+
+    #[test]
+    fn failures_of_past_windows_dont_count() {
+        let (_held, clock) = clock::mock::hold();
+        let breaker = breaker();
+
+        breaker.record(true);
+        clock.advance(Duration::from_secs(1));
+        breaker.record(false);
+        breaker.record(false);
+        breaker.record(true);
+        assert!(matches!(breaker.admit(), Admission::Pass));
+    }
+}
@@ -0,0 +1,177 @@
+//! Time source shared by the subsystems that only need coarse time: the
+//! `Date` header of the plaintext fast path, cache TTLs and ages, the rate
+//! limiter, the circuit breaker and request deadlines.
+//!
+//! By default that is a `CoarseClock`: a background thread reads `Instant`
+//! and `SystemTime` once every `AXUM_TECHEMPOWER_CLOCK_TICK_MS` (default 1)
+//! and every reader shares that reading, instead of each of them asking the
+//! OS on every request. Latency measurements keep reading `Instant` directly,
+//! where a tick of error would show.
+//!
+//! A `MockClock` can be installed in its place before first use, to drive
+//! time-dependent behavior by hand, as the unit tests do. Tokio's timers
+//! don't follow it: the deadline timer still fires in real time, or in
+//! Tokio's paused time.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::utils::get_environment_variable_or;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn system_now(&self) -> SystemTime;
+}
+
+static CLOCK: OnceLock<&'static dyn Clock> = OnceLock::new();
+
+fn clock() -> &'static dyn Clock {
+    #[cfg(test)]
+    mock::clock();
+
+    *CLOCK.get_or_init(CoarseClock::start)
+}
+
+/// Makes `clock` the time source. Panics if time has already been read.
+#[allow(dead_code)]
+pub fn install(clock: &'static dyn Clock) {
+    if CLOCK.set(clock).is_err() {
+        panic!("clock installed after first use");
+    }
+}
+
+/// Current monotonic time, as of the last tick.
+pub fn now() -> Instant {
+    clock().now()
+}
+
+/// Current wall-clock time, as of the last tick.
+#[allow(dead_code)]
+pub fn system_now() -> SystemTime {
+    clock().system_now()
+}
+
+/// The real clock, read once per tick.
+pub struct CoarseClock {
+    started: Instant,
+    /// Nanoseconds from `started` to the last tick.
+    elapsed: AtomicU64,
+    /// Nanoseconds from the Unix epoch to the last tick.
+    unix: AtomicU64,
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64)
+}
+
+impl CoarseClock {
+    /// Starts the thread that ticks the clock.
+    fn start() -> &'static dyn Clock {
+        let tick_ms: u64 =
+            get_environment_variable_or("AXUM_TECHEMPOWER_CLOCK_TICK_MS", 1);
+        let tick = Duration::from_millis(tick_ms.max(1));
+
+        let clock: &'static CoarseClock = Box::leak(Box::new(CoarseClock {
+            started: Instant::now(),
+            elapsed: AtomicU64::new(0),
+            unix: AtomicU64::new(unix_nanos(SystemTime::now())),
+        }));
+
+        thread::Builder::new()
+            .name("axum-clock".to_string())
+            .spawn(move || loop {
+                thread::sleep(tick);
+                clock.tick();
+            })
+            .expect("could not start clock thread");
+
+        clock
+    }
+
+    fn tick(&self) {
+        let elapsed = self.started.elapsed().as_nanos() as u64;
+        self.elapsed.store(elapsed, Ordering::Relaxed);
+        self.unix
+            .store(unix_nanos(SystemTime::now()), Ordering::Relaxed);
+    }
+}
+
+impl Clock for CoarseClock {
+    fn now(&self) -> Instant {
+        self.started + Duration::from_nanos(self.elapsed.load(Ordering::Relaxed))
+    }
+
+    fn system_now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_nanos(self.unix.load(Ordering::Relaxed))
+    }
+}
+
+/// A clock that only moves when it is told to.
+#[allow(dead_code)]
+pub struct MockClock {
+    now: Mutex<(Instant, SystemTime)>,
+}
+
+impl Default for MockClock {
+    /// A clock standing at the current time.
+    fn default() -> Self {
+        Self {
+            now: Mutex::new((Instant::now(), SystemTime::now())),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl MockClock {
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        now.0 += by;
+        now.1 += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.now.lock().unwrap().0
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.now.lock().unwrap().1
+    }
+}
+
+/// The clock of the unit tests: one `MockClock`, installed before anything
+/// reads the time, so no test depends on the real one.
+#[cfg(test)]
+pub mod mock {
+    use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+
+    use super::{install, MockClock};
+
+    pub fn clock() -> &'static MockClock {
+        static CLOCK: OnceLock<&'static MockClock> = OnceLock::new();
+
+        CLOCK.get_or_init(|| {
+            let clock: &'static MockClock = Box::leak(Box::default());
+            install(clock);
+            clock
+        })
+    }
+
+    /// The clock, for a test that moves it. Other tests holding it wait, so
+    /// none sees the time move under it.
+    pub fn hold() -> (MutexGuard<'static, ()>, &'static MockClock) {
+        static HELD: Mutex<()> = Mutex::new(());
+
+        let held = HELD.lock().unwrap_or_else(PoisonError::into_inner);
+        (held, clock())
+    }
+}
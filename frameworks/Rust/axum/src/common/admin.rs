@@ -0,0 +1,55 @@
+//! Guard for the admin routes.
+
+use std::sync::OnceLock;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+};
+
+use crate::utils::get_optional_environment_variable;
+
+/// Token from `AXUM_TECHEMPOWER_ADMIN_TOKEN`. Without it the admin routes are
+/// not served at all.
+pub fn admin_token() -> Option<&'static str> {
+    static TOKEN: OnceLock<Option<String>> = OnceLock::new();
+
+    TOKEN
+        .get_or_init(|| {
+            get_optional_environment_variable("AXUM_TECHEMPOWER_ADMIN_TOKEN")
+        })
+        .as_deref()
+}
+
+/// Extractor rejecting requests without `Authorization: Bearer <admin token>`.
+pub struct Admin;
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Admin {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let token = admin_token().ok_or(StatusCode::NOT_FOUND)?;
+
+        let provided = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        if constant_time_eq(provided.as_bytes(), token.as_bytes()) {
+            Ok(Self)
+        } else {
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
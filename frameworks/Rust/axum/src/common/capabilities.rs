@@ -0,0 +1,37 @@
+//! `--capabilities`: prints which benchmark endpoints the binary serves, as
+//! JSON, and exits before touching the database, for `axum-loadgen parity`:
+//!
+//! ```json
+//! {"backend": "postgres", "endpoints": ["db", "queries", "fortunes", "updates"]}
+//! ```
+//!
+//! Endpoints disabled through `AXUM_TECHEMPOWER_ENDPOINTS` are left out.
+
+use serde::Serialize;
+
+use crate::common::endpoints::Routes;
+
+#[derive(Serialize)]
+struct Capabilities {
+    backend: &'static str,
+    endpoints: Vec<&'static str>,
+}
+
+/// Whether the binary was started with `--capabilities`.
+pub fn requested() -> bool {
+    std::env::args().skip(1).any(|arg| arg == "--capabilities")
+}
+
+/// Prints the endpoints `routes` serves for `backend` and exits.
+pub fn print<S>(backend: &'static str, routes: &Routes<S>) -> !
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let capabilities = Capabilities {
+        backend,
+        endpoints: routes.served(),
+    };
+
+    println!("{}", serde_json::to_string(&capabilities).unwrap());
+    std::process::exit(0)
+}
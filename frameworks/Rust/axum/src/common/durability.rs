@@ -0,0 +1,61 @@
+//! Opt-in "benchmark durability" for the Postgres update path, enabled with
+//! `AXUM_TECHEMPOWER_BENCHMARK_DURABILITY=relaxed`. Not the default.
+//!
+//! Updates then commit with `synchronous_commit = off`: Postgres acknowledges
+//! them before their WAL is flushed, so a crash can lose the last moments of
+//! writes, though never corrupt the database. Some frameworks run the updates
+//! test this way, and this makes a like-for-like comparison possible.
+//!
+//! `axum-pg-pool` sets it on every connection checked out by an update, in
+//! the same round trip as the first statement; `axum-pg` sets it when its
+//! per-worker connection is opened. The setting then stays on the connection,
+//! which only matters for writes. Backends that don't support the mode ignore
+//! it, and `/info` reports `durability` as `relaxed (synchronous_commit=off)`
+//! only where it is applied, so results stay auditable.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    OnceLock,
+};
+
+use crate::utils::get_optional_environment_variable;
+
+/// Session setting that relaxes the durability of commits.
+#[allow(dead_code)]
+pub const RELAXED_COMMIT: &str = "SET synchronous_commit = off";
+
+static APPLIED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the relaxed mode is requested. Backends call this where they
+/// apply it, which is what `/info` goes by.
+#[allow(dead_code)]
+pub fn relaxed() -> bool {
+    static RELAXED: OnceLock<bool> = OnceLock::new();
+
+    let relaxed = *RELAXED.get_or_init(|| {
+        let mode: Option<String> =
+            get_optional_environment_variable("AXUM_TECHEMPOWER_BENCHMARK_DURABILITY");
+
+        match mode.as_deref() {
+            None | Some("full") => false,
+            Some("relaxed") => true,
+            Some(mode) => panic!(
+                "AXUM_TECHEMPOWER_BENCHMARK_DURABILITY must be full or relaxed, got {mode:?}"
+            ),
+        }
+    });
+
+    if relaxed {
+        APPLIED.store(true, Ordering::Relaxed);
+    }
+    relaxed
+}
+
+/// How commits are made durable, for `/info`.
+pub fn label() -> &'static str {
+    if APPLIED.load(Ordering::Relaxed) {
+        "relaxed (synchronous_commit=off)"
+    } else {
+        "full"
+    }
+}
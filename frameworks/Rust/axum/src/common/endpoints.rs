@@ -0,0 +1,229 @@
+//! Registry of the benchmark endpoints.
+//!
+//! The routers of every binary, the startup warm-up and the raw listener take
+//! their paths from `ENDPOINTS`, so adding a test type means adding one entry
+//! here and registering its handler with `Routes::serve`.
+//!
+//! `AXUM_TECHEMPOWER_ENDPOINTS` restricts a binary to some endpoints, as a
+//! comma-separated list of names, e.g. `plaintext,json`. The others answer
+//! 404, and are skipped by the warm-up and the latency histograms.
+
+use std::sync::OnceLock;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{any, MethodRouter},
+    Router,
+};
+use serde::Serialize;
+
+use crate::utils::{get_optional_environment_variable, JsonFast};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Requires {
+    Nothing,
+    Database,
+    Cache,
+}
+
+#[derive(Debug)]
+pub struct Endpoint {
+    pub name: &'static str,
+    pub path: &'static str,
+    /// Request sent by the startup warm-up, `None` for streaming endpoints.
+    pub warm_up: Option<&'static str>,
+    pub requires: Requires,
+}
+
+impl Endpoint {
+    /// Whether `AXUM_TECHEMPOWER_ENDPOINTS` leaves this endpoint enabled.
+    pub fn enabled(&self) -> bool {
+        enabled_names().map_or(true, |names| names.contains(&self.name))
+    }
+}
+
+fn enabled_names() -> Option<&'static [&'static str]> {
+    static NAMES: OnceLock<Option<Vec<&'static str>>> = OnceLock::new();
+
+    NAMES
+        .get_or_init(|| {
+            let names: String =
+                get_optional_environment_variable("AXUM_TECHEMPOWER_ENDPOINTS")?;
+
+            let names = names
+                .split(',')
+                .map(|name| {
+                    ENDPOINTS
+                        .iter()
+                        .find(|endpoint| endpoint.name == name.trim())
+                        .map(|endpoint| endpoint.name)
+                        .unwrap_or_else(|| {
+                            panic!("unknown endpoint {name:?} in AXUM_TECHEMPOWER_ENDPOINTS")
+                        })
+                })
+                .collect();
+            Some(names)
+        })
+        .as_deref()
+}
+
+pub const PLAINTEXT: Endpoint = Endpoint {
+    name: "plaintext",
+    path: "/plaintext",
+    warm_up: Some("/plaintext"),
+    requires: Requires::Nothing,
+};
+
+pub const JSON: Endpoint = Endpoint {
+    name: "json",
+    path: "/json",
+    warm_up: Some("/json"),
+    requires: Requires::Nothing,
+};
+
+pub const WS: Endpoint = Endpoint {
+    name: "ws",
+    path: "/ws",
+    warm_up: None,
+    requires: Requires::Nothing,
+};
+
+pub const DB: Endpoint = Endpoint {
+    name: "db",
+    path: "/db",
+    warm_up: Some("/db"),
+    requires: Requires::Database,
+};
+
+pub const QUERIES: Endpoint = Endpoint {
+    name: "queries",
+    path: "/queries",
+    warm_up: Some("/queries?queries=20"),
+    requires: Requires::Database,
+};
+
+pub const FORTUNES: Endpoint = Endpoint {
+    name: "fortunes",
+    path: "/fortunes",
+    warm_up: Some("/fortunes"),
+    requires: Requires::Database,
+};
+
+pub const UPDATES: Endpoint = Endpoint {
+    name: "updates",
+    path: "/updates",
+    warm_up: Some("/updates?queries=20"),
+    requires: Requires::Database,
+};
+
+pub const CACHED_QUERIES: Endpoint = Endpoint {
+    name: "cached-queries",
+    path: "/cached-queries",
+    warm_up: Some("/cached-queries?count=20"),
+    requires: Requires::Cache,
+};
+
+pub const EVENTS: Endpoint = Endpoint {
+    name: "events",
+    path: "/events",
+    warm_up: None,
+    requires: Requires::Cache,
+};
+
+pub const ENDPOINTS: &[Endpoint] = &[
+    PLAINTEXT,
+    JSON,
+    WS,
+    DB,
+    QUERIES,
+    FORTUNES,
+    UPDATES,
+    CACHED_QUERIES,
+    EVENTS,
+];
+
+/// Router builder registering handlers under their endpoint's path, alone or
+/// as groups combined with `merge`.
+///
+/// Endpoints the binary doesn't serve are answered with `501 Not Implemented`
+/// and a JSON body naming the missing capability, rather than a bare 404.
+/// Disabled endpoints aren't routed at all.
+pub struct Routes<S> {
+    router: Router<S>,
+    served: Vec<&'static str>,
+}
+
+impl<S> Default for Routes<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self {
+            router: Router::new(),
+            served: Vec::new(),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl<S> Routes<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    pub fn serve(mut self, endpoint: &Endpoint, handler: MethodRouter<S>) -> Self {
+        if !endpoint.enabled() {
+            return self;
+        }
+
+        self.router = self.router.route(endpoint.path, handler);
+        self.served.push(endpoint.path);
+        self
+    }
+
+    /// Adds the endpoints registered on `other`, so a binary can build its
+    /// routes from groups. Panics if both serve an endpoint.
+    pub fn merge(mut self, other: Routes<S>) -> Self {
+        self.router = self.router.merge(other.router);
+        self.served.extend(other.served);
+        self
+    }
+
+    /// Names of the endpoints registered so far.
+    pub fn served(&self) -> Vec<&'static str> {
+        ENDPOINTS
+            .iter()
+            .filter(|endpoint| self.served.contains(&endpoint.path))
+            .map(|endpoint| endpoint.name)
+            .collect()
+    }
+
+    pub fn into_router(self) -> Router<S> {
+        ENDPOINTS
+            .iter()
+            .filter(|endpoint| {
+                endpoint.enabled() && !self.served.contains(&endpoint.path)
+            })
+            .fold(self.router, |router, endpoint| {
+                router.route(endpoint.path, any(move || not_implemented(endpoint)))
+            })
+    }
+}
+
+#[derive(Serialize)]
+struct NotImplemented {
+    message: String,
+    capability: &'static str,
+    requires: Requires,
+}
+
+async fn not_implemented(endpoint: &'static Endpoint) -> Response {
+    let body = NotImplemented {
+        message: format!("{} is not implemented by this server", endpoint.path),
+        capability: endpoint.name,
+        requires: endpoint.requires,
+    };
+
+    (StatusCode::NOT_IMPLEMENTED, JsonFast::new(body)).into_response()
+}
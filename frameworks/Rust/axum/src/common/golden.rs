@@ -0,0 +1,110 @@
+//! Golden files of whole responses, for byte-level changes the field-by-field
+//! asserts miss: float formatting, header order, escaping.
+//!
+//! A response is recorded as its status line, its headers in the order they
+//! were set, a blank line and its body, in `tests/golden/NAME.http`. Requests
+//! run with the worker RNG seeded with `testing::SNAPSHOT_SEED`, so handlers
+//! draw the same ids on every run. With `AXUM_TECHEMPOWER_RECORD_GOLDEN=1` the
+//! files are written instead of checked; review their diff before committing.
+
+use std::{env, fmt::Write, fs, path::PathBuf};
+
+use axum::{
+    body::Body,
+    http::Request,
+    response::{IntoResponse, Response},
+    Router,
+};
+use tower::ServiceExt;
+
+use crate::{common::testing::SNAPSHOT_SEED, utils::seed_worker_rng};
+
+fn path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{name}.http"))
+}
+
+/// `response` in the golden file format. The body must be UTF-8.
+pub async fn record(response: Response) -> String {
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await.unwrap();
+
+    let mut recorded = format!("{:?} {}\n", parts.version, parts.status);
+    for (name, value) in &parts.headers {
+        writeln!(recorded, "{name}: {}", value.to_str().unwrap()).unwrap();
+    }
+    recorded.push('\n');
+    recorded.push_str(std::str::from_utf8(&body).unwrap());
+    recorded
+}
+
+/// The lines that differ between `expected` and `actual`, as `-`/`+` pairs.
+fn diff(expected: &str, actual: &str) -> String {
+    let (mut expected, mut actual) = (expected.lines(), actual.lines());
+    let mut diff = String::new();
+
+    for line in 1.. {
+        match (expected.next(), actual.next()) {
+            (None, None) => break,
+            (expected, actual) if expected == actual => {}
+            (expected, actual) => {
+                writeln!(diff, "line {line}:").unwrap();
+                if let Some(expected) = expected {
+                    writeln!(diff, "- {expected}").unwrap();
+                }
+                if let Some(actual) = actual {
+                    writeln!(diff, "+ {actual}").unwrap();
+                }
+            }
+        }
+    }
+    diff
+}
+
+/// Checks `response` against the golden file `name`, or records it.
+pub async fn assert_response(name: &str, response: impl IntoResponse) {
+    let actual = record(response.into_response()).await;
+    let path = path(name);
+
+    if env::var_os("AXUM_TECHEMPOWER_RECORD_GOLDEN").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, &actual).unwrap();
+        return;
+    }
+
+    let expected = fs::read_to_string(&path).unwrap_or_else(|err| {
+        panic!(
+            "could not read {}: {err}; record it with AXUM_TECHEMPOWER_RECORD_GOLDEN=1",
+            path.display()
+        )
+    });
+    assert!(
+        expected == actual,
+        "{name} differs from {}:\n{}",
+        path.display(),
+        diff(&expected, &actual)
+    );
+}
+
+/// Sends `GET uri` to `router` with a seeded worker RNG, and checks the
+/// response against the golden file `name`, or records it.
+pub async fn assert_endpoint(name: &str, router: Router, uri: &str) {
+    seed_worker_rng(SNAPSHOT_SEED);
+
+    let request = Request::get(uri).body(Body::empty()).unwrap();
+    let response = router.oneshot(request).await.unwrap();
+    assert_response(name, response).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_only_the_lines_that_changed() {
+        assert_eq!(diff("a\nb\nc", "a\nB\nc"), "line 2:\n- b\n+ B\n");
+        assert_eq!(diff("a", "a\nb"), "line 2:\n+ b\n");
+        assert_eq!(diff("a\nb", "a\nb"), "");
+    }
+}
@@ -0,0 +1,14 @@
+//! `Content-Type` values of the benchmark responses, as the TFB requirements
+//! spell them, shared by the handlers, the raw listener and the fast path.
+
+use axum::http::HeaderValue;
+
+/// `/json`, the database tests, and JSON error bodies.
+pub static APPLICATION_JSON: HeaderValue = HeaderValue::from_static("application/json");
+
+/// `/plaintext`.
+pub static TEXT_PLAIN: HeaderValue = HeaderValue::from_static("text/plain");
+
+/// `/fortunes`.
+pub static TEXT_HTML_UTF8: HeaderValue =
+    HeaderValue::from_static("text/html; charset=utf-8");
@@ -0,0 +1,222 @@
+//! Hand-rolled HTML writer for the fortunes test, enabled with the
+//! `html-writer` feature as an alternative to the yarte template.
+
+use std::fmt::Write;
+
+use axum::body::Bytes;
+use bytes::{BufMut, BytesMut};
+
+const FORTUNES_HEAD: &[u8] =
+    b"<!DOCTYPE html><html><head><title>Fortunes</title></head>\
+<body><table><tr><th>id</th><th>message</th></tr>";
+const FORTUNES_TAIL: &[u8] = b"</table></body></html>";
+const ROW_HEAD: &[u8] = b"<tr><td>";
+const ROW_MIDDLE: &[u8] = b"</td><td>";
+const ROW_TAIL: &[u8] = b"</td></tr>";
+
+/// Rows rendered between yields to the runtime.
+const ROWS_PER_YIELD: usize = 1024;
+
+/// Appends `text` to `out`, escaping the characters that are significant in
+/// HTML text and attribute values.
+///
+/// Only ASCII bytes are ever replaced, so multi-byte UTF-8 sequences are
+/// copied through untouched.
+pub fn html_escape_into(text: &str, out: &mut BytesMut) {
+    let bytes = text.as_bytes();
+
+    // with `simd-escape`, rows without anything to escape are copied in one go
+    #[cfg(feature = "simd-escape")]
+    if memchr::memchr3(b'&', b'<', b'>', bytes).is_none()
+        && memchr::memchr2(b'"', b'\'', bytes).is_none()
+    {
+        out.put_slice(bytes);
+        return;
+    }
+
+    let mut start = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        let escaped: &[u8] = match byte {
+            b'&' => b"&amp;",
+            b'<' => b"&lt;",
+            b'>' => b"&gt;",
+            b'"' => b"&quot;",
+            b'\'' => b"&#x27;",
+            _ => continue,
+        };
+
+        out.put_slice(&bytes[start..i]);
+        out.put_slice(escaped);
+        start = i + 1;
+    }
+    out.put_slice(&bytes[start..]);
+}
+
+/// Length of `text` once escaped by `html_escape_into`.
+fn escaped_len(text: &str) -> usize {
+    text.bytes()
+        .map(|byte| match byte {
+            b'&' => 5,
+            b'<' | b'>' => 4,
+            b'"' | b'\'' => 6,
+            _ => 1,
+        })
+        .sum()
+}
+
+/// Number of characters `id` is formatted with.
+fn decimal_len(id: i32) -> usize {
+    let sign = usize::from(id < 0);
+    let digits = id.unsigned_abs().checked_ilog10().unwrap_or(0) as usize + 1;
+
+    sign + digits
+}
+
+/// Exact length of the page `render_fortunes` produces for `fortunes`,
+/// yielding to the runtime as often as the rendering does.
+async fn rendered_len<'a>(fortunes: impl Iterator<Item = (i32, &'a str)>) -> usize {
+    let row = ROW_HEAD.len() + ROW_MIDDLE.len() + ROW_TAIL.len();
+    let mut len = FORTUNES_HEAD.len() + FORTUNES_TAIL.len();
+
+    for (index, (id, message)) in fortunes.enumerate() {
+        if index > 0 && index % ROWS_PER_YIELD == 0 {
+            tokio::task::yield_now().await;
+        }
+        len += row + decimal_len(id) + escaped_len(message);
+    }
+    len
+}
+
+/// Renders the same markup as `templates/fortunes.html.hbs`.
+///
+/// The page is measured in a first pass over `fortunes`, so the output is
+/// written into a single allocation of exactly the right size.
+///
+/// Yields to the runtime every `ROWS_PER_YIELD` rows of either pass, so
+/// rendering a custom, very large fortunes table doesn't hold up everything
+/// else queued on the worker. The 12 rows of the TFB table never yield.
+pub async fn render_fortunes<'a>(
+    fortunes: impl Iterator<Item = (i32, &'a str)> + Clone,
+) -> Bytes {
+    let len = rendered_len(fortunes.clone()).await;
+    let mut out = BytesMut::with_capacity(len);
+
+    out.put_slice(FORTUNES_HEAD);
+    for (row, (fortune_id, message)) in fortunes.enumerate() {
+        if row > 0 && row % ROWS_PER_YIELD == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        out.put_slice(ROW_HEAD);
+        let _ = write!(out, "{fortune_id}");
+        out.put_slice(ROW_MIDDLE);
+        html_escape_into(message, &mut out);
+        out.put_slice(ROW_TAIL);
+    }
+    out.put_slice(FORTUNES_TAIL);
+
+    debug_assert!(
+        out.len() <= len,
+        "rendered {} bytes, predicted {len}",
+        out.len()
+    );
+
+    out.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::SmallRng, seq::SliceRandom, Rng, SeedableRng};
+
+    use super::*;
+    use crate::common::testing;
+
+    const PLAIN: &[char] = &['a', 'Z', '0', ' ', '/', '=', '\n', 'é', 'ß', 'フ', '😀'];
+    const SPECIAL: &[char] = &['&', '<', '>', '"', '\''];
+
+    /// Escapes one character at a time, the obvious way.
+    fn naive_escape(text: &str) -> String {
+        let mut out = String::new();
+        for c in text.chars() {
+            match c {
+                '&' => out.push_str("&amp;"),
+                '<' => out.push_str("&lt;"),
+                '>' => out.push_str("&gt;"),
+                '"' => out.push_str("&quot;"),
+                '\'' => out.push_str("&#x27;"),
+                c => out.push(c),
+            }
+        }
+        out
+    }
+
+    /// Random text of up to 64 characters, half of the time without anything
+    /// to escape, so both paths of `html_escape_into` are taken.
+    fn random_text(rng: &mut SmallRng) -> String {
+        let len = rng.gen_range(0..=64);
+        let alphabet: Vec<char> = if rng.gen() {
+            PLAIN.to_vec()
+        } else {
+            PLAIN.iter().chain(SPECIAL).copied().collect()
+        };
+
+        (0..len).map(|_| *alphabet.choose(rng).unwrap()).collect()
+    }
+
+    #[test]
+    fn escaping_matches_the_naive_escaper() {
+        let mut rng = SmallRng::seed_from_u64(0);
+
+        for _ in 0..10_000 {
+            let text = random_text(&mut rng);
+            let mut out = BytesMut::new();
+            html_escape_into(&text, &mut out);
+
+            assert_eq!(out, naive_escape(&text).as_bytes(), "escaping {text:?}");
+            assert!(std::str::from_utf8(&out).is_ok(), "escaping {text:?}");
+            assert_eq!(escaped_len(&text), out.len(), "escaping {text:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rendered_len_predicts_random_pages() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let ids = [0, 1, 9, 10, -1, i32::MAX, i32::MIN];
+
+        for _ in 0..1_000 {
+            let fortunes: Vec<(i32, String)> = (0..rng.gen_range(0..16))
+                .map(|_| {
+                    let id = if rng.gen() {
+                        *ids.choose(&mut rng).unwrap()
+                    } else {
+                        rng.gen()
+                    };
+                    (id, random_text(&mut rng))
+                })
+                .collect();
+            let rows = fortunes.iter().map(|(id, message)| (*id, message.as_str()));
+
+            let page = render_fortunes(rows.clone()).await;
+            assert!(rendered_len(rows).await >= page.len());
+
+            let mut expected = String::from_utf8(FORTUNES_HEAD.to_vec()).unwrap();
+            for (id, message) in &fortunes {
+                let message = naive_escape(message);
+                expected.push_str(&format!("<tr><td>{id}</td><td>{message}</td></tr>"));
+            }
+            expected.push_str("</table></body></html>");
+            assert_eq!(page, expected.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn renders_fortunes_byte_for_byte() {
+        let page = render_fortunes(testing::FORTUNES.iter().copied()).await;
+
+        assert_eq!(page, testing::WRITER_PAGE.as_bytes());
+        assert_eq!(
+            page.len(),
+            rendered_len(testing::FORTUNES.iter().copied()).await
+        );
+    }
+}
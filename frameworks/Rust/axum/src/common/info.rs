@@ -0,0 +1,48 @@
+//! `GET /info`: which build and configuration is answering, so benchmark
+//! result artifacts can record exactly what produced them.
+//!
+//! The commit and the enabled cargo features are captured by `build.rs`; the
+//! rest is passed in by each binary.
+
+use axum::{routing::get, Router};
+use serde::Serialize;
+
+use crate::{common::durability, utils::JsonFast};
+
+/// No binary installs a `#[global_allocator]`.
+const ALLOCATOR: &str = "system";
+
+#[derive(Clone, Debug, Serialize)]
+struct Info {
+    version: &'static str,
+    git_sha: &'static str,
+    features: Vec<&'static str>,
+    backend: &'static str,
+    pool_size: Option<u32>,
+    workers: usize,
+    allocator: &'static str,
+    durability: &'static str,
+}
+
+/// Serves `/info` for a binary using `backend`, with at most `pool_size`
+/// database connections per pool. Call it after the database is set up, so
+/// `durability` reflects whether the backend relaxed it.
+pub fn routes<S>(backend: &'static str, pool_size: Option<u32>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let features = env!("AXUM_TECHEMPOWER_BUILD_FEATURES");
+
+    let info = Info {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("AXUM_TECHEMPOWER_BUILD_GIT_SHA"),
+        features: features.split(',').filter(|f| !f.is_empty()).collect(),
+        backend,
+        pool_size,
+        workers: num_cpus::get(),
+        allocator: ALLOCATOR,
+        durability: durability::label(),
+    };
+
+    Router::new().route("/info", get(move || async move { JsonFast::new(info) }))
+}
@@ -0,0 +1,114 @@
+//! Schema migrations for the Postgres backends, run at startup when the
+//! binary is started with `--migrate`, so a fresh database is ready for local
+//! benchmarking without the SQL of the upstream toolset.
+//!
+//! The scripts in `migrations/` create the `world` and `fortune` tables and
+//! fill them the way the toolset does. Each is applied once and recorded in
+//! `schema_migrations`, prefixed like the other tables. Tables that already
+//! exist, e.g. ones created by the toolset, are kept and only get the rows
+//! they are missing. Pending scripts are applied in one transaction under an
+//! advisory lock, so processes started together don't apply them twice, and
+//! only simple queries are sent, which PgBouncer's transaction pooling can
+//! pass on.
+
+use tokio::sync::OnceCell;
+use tokio_postgres::{Client, Error, SimpleQueryMessage};
+
+use super::tables::Sql;
+
+struct Migration {
+    version: i32,
+    name: &'static str,
+    sql: Sql,
+}
+
+static MIGRATIONS: [Migration; 2] = [
+    Migration {
+        version: 1,
+        name: "tables",
+        sql: Sql::new(include_str!("migrations/1_tables.sql")),
+    },
+    Migration {
+        version: 2,
+        name: "rows",
+        sql: Sql::new(include_str!("migrations/2_rows.sql")),
+    },
+];
+
+static PREPARE: Sql = Sql::new(
+    "SELECT pg_advisory_xact_lock(hashtext('{migrations}')); \
+     CREATE TABLE IF NOT EXISTS {migrations} (\
+     version integer PRIMARY KEY, \
+     name text NOT NULL, \
+     applied_at timestamptz NOT NULL DEFAULT now())",
+);
+
+static APPLIED: Sql = Sql::new("SELECT version FROM {migrations}");
+
+static RECORD: Sql = Sql::new("INSERT INTO {migrations} (version, name) VALUES");
+
+/// Whether the binary was started with `--migrate`.
+pub fn requested() -> bool {
+    std::env::args().skip(1).any(|arg| arg == "--migrate")
+}
+
+/// Applies the pending migrations. Only the first call of the process does,
+/// and the others wait for it, so per-core workers all start on the same
+/// schema.
+pub async fn run(client: &Client) -> Result<(), Error> {
+    static MIGRATED: OnceCell<()> = OnceCell::const_new();
+
+    MIGRATED
+        .get_or_try_init(|| async {
+            client.batch_execute("BEGIN").await?;
+            match apply(client).await {
+                Ok(()) => client.batch_execute("COMMIT").await,
+                Err(err) => {
+                    let _ = client.batch_execute("ROLLBACK").await;
+                    Err(err)
+                }
+            }
+        })
+        .await
+        .map(drop)
+}
+
+async fn apply(client: &Client) -> Result<(), Error> {
+    client.batch_execute(PREPARE.get()).await?;
+
+    let applied: Vec<i32> = client
+        .simple_query(APPLIED.get())
+        .await?
+        .iter()
+        .filter_map(|message| match message {
+            SimpleQueryMessage::Row(row) => row.get(0)?.parse().ok(),
+            _ => None,
+        })
+        .collect();
+
+    let pending: Vec<&Migration> = MIGRATIONS
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .collect();
+    if pending.is_empty() {
+        println!("migrations: schema is up to date");
+    }
+
+    for migration in pending {
+        client.batch_execute(migration.sql.get()).await?;
+        client
+            .batch_execute(&format!(
+                "{} ({}, '{}')",
+                RECORD.get(),
+                migration.version,
+                migration.name
+            ))
+            .await?;
+        println!(
+            "migrations: applied {}_{}",
+            migration.version, migration.name
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS {world} (
+  id integer NOT NULL,
+  randomNumber integer NOT NULL default 0,
+  PRIMARY KEY (id)
+);
+
+CREATE TABLE IF NOT EXISTS {fortune} (
+  id integer NOT NULL,
+  message varchar(2048) NOT NULL,
+  PRIMARY KEY (id)
+);
@@ -0,0 +1,26 @@
+//! Code shared by the handler sets of every binary.
+
+pub mod admin;
+pub mod capabilities;
+pub mod durability;
+pub mod endpoints;
+#[cfg(test)]
+#[allow(dead_code)]
+pub mod golden;
+pub mod headers;
+#[cfg(feature = "html-writer")]
+#[allow(dead_code)]
+pub mod html;
+pub mod info;
+#[allow(dead_code)]
+pub mod migrate;
+pub mod params;
+#[allow(dead_code)]
+pub mod schema;
+#[allow(dead_code)]
+pub mod sort;
+#[allow(dead_code)]
+pub mod tables;
+#[cfg(test)]
+#[allow(dead_code)]
+pub mod testing;
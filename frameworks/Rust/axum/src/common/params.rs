@@ -0,0 +1,179 @@
+//! Query parameter extractors for the multi-row tests.
+//!
+//! Both extractors follow the verifier's contract: a missing or non-numeric
+//! value counts as 1, and numeric values are clamped to `1..=500`. Private runs
+//! that need larger fan-outs can raise the upper bound with
+//! `AXUM_TECHEMPOWER_MAX_QUERIES`, which fails verification. For those,
+//! `axum-pg-pool` streams `/queries` responses of more than
+//! `AXUM_TECHEMPOWER_STREAM_QUERIES_ABOVE` worlds (unset, it never does),
+//! writing the worlds out as they arrive instead of buffering them all.
+
+use std::{convert::Infallible, sync::OnceLock};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::request::Parts,
+};
+use serde::Deserialize;
+
+use crate::utils::{get_environment_variable_or, get_optional_environment_variable};
+
+pub const MIN: usize = 1;
+
+/// Upper bound of the clamp, from `AXUM_TECHEMPOWER_MAX_QUERIES` (default 500).
+pub fn max() -> usize {
+    static MAX: OnceLock<usize> = OnceLock::new();
+
+    *MAX.get_or_init(|| {
+        get_environment_variable_or("AXUM_TECHEMPOWER_MAX_QUERIES", 500).max(MIN)
+    })
+}
+
+/// Counts above which `/queries` responses are streamed, if any.
+#[allow(dead_code)]
+pub fn stream_above() -> Option<usize> {
+    static STREAM_ABOVE: OnceLock<Option<usize>> = OnceLock::new();
+
+    *STREAM_ABOVE.get_or_init(|| {
+        get_optional_environment_variable("AXUM_TECHEMPOWER_STREAM_QUERIES_ABOVE")
+    })
+}
+
+/// Number of worlds requested through `?queries=`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Queries(pub usize);
+
+/// Number of worlds requested through `?count=`.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Count(pub usize);
+
+#[derive(Debug, Default, Deserialize)]
+struct RawParams {
+    queries: Option<String>,
+    count: Option<String>,
+}
+
+/// Clamps a requested number of worlds to `MIN..=max()`.
+#[allow(dead_code)]
+pub fn clamp(value: i64) -> usize {
+    clamp_to(value, max())
+}
+
+fn clamp_to(value: i64, max: usize) -> usize {
+    value.clamp(MIN as i64, max as i64) as usize
+}
+
+fn parse(value: Option<&str>) -> usize {
+    parse_to(value, max())
+}
+
+fn parse_to(value: Option<&str>, max: usize) -> usize {
+    value
+        .and_then(|value| value.trim().parse::<i64>().ok())
+        .map_or(MIN, |value| clamp_to(value, max))
+}
+
+fn raw_params(parts: &Parts) -> RawParams {
+    Query::try_from_uri(&parts.uri)
+        .map_or_else(|_| RawParams::default(), |Query(params)| params)
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Queries {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self(parse(raw_params(parts).queries.as_deref())))
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Count {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self(parse(raw_params(parts).count.as_deref())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Request;
+
+    use super::*;
+
+    const TFB_MAX: usize = 500;
+
+    #[test]
+    fn missing_empty_and_non_numeric_values_count_as_one() {
+        assert_eq!(parse_to(None, TFB_MAX), 1);
+        assert_eq!(parse_to(Some(""), TFB_MAX), 1);
+        assert_eq!(parse_to(Some("foo"), TFB_MAX), 1);
+        assert_eq!(parse_to(Some("1.5"), TFB_MAX), 1);
+        assert_eq!(parse_to(Some("0x10"), TFB_MAX), 1);
+    }
+
+    #[test]
+    fn values_are_clamped_to_the_tfb_range() {
+        assert_eq!(parse_to(Some("0"), TFB_MAX), 1);
+        assert_eq!(parse_to(Some("-1"), TFB_MAX), 1);
+        assert_eq!(parse_to(Some("1"), TFB_MAX), 1);
+        assert_eq!(parse_to(Some("20"), TFB_MAX), 20);
+        assert_eq!(parse_to(Some(" 20 "), TFB_MAX), 20);
+        assert_eq!(parse_to(Some("500"), TFB_MAX), 500);
+        assert_eq!(parse_to(Some("501"), TFB_MAX), 500);
+    }
+
+    #[test]
+    fn values_overflowing_i64_count_as_one() {
+        assert_eq!(parse_to(Some(&i64::MAX.to_string()), TFB_MAX), 500);
+        assert_eq!(parse_to(Some("9223372036854775808"), TFB_MAX), 1);
+        assert_eq!(parse_to(Some("-9223372036854775809"), TFB_MAX), 1);
+        assert_eq!(clamp_to(i64::MIN, TFB_MAX), 1);
+        assert_eq!(clamp_to(i64::MAX, TFB_MAX), 500);
+    }
+
+    #[test]
+    fn a_raised_maximum_moves_the_upper_bound() {
+        assert_eq!(parse_to(Some("501"), 1000), 501);
+        assert_eq!(parse_to(Some("5000"), 1000), 1000);
+        assert_eq!(parse_to(Some("0"), 1000), 1);
+        assert_eq!(clamp_to(20, 10), 10);
+    }
+
+    async fn extract<T: FromRequestParts<()>>(uri: &str) -> T {
+        let (mut parts, ()) = Request::get(uri).body(()).unwrap().into_parts();
+
+        match T::from_request_parts(&mut parts, &()).await {
+            Ok(value) => value,
+            Err(_) => panic!("{uri} rejected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn extractors_read_their_own_parameter() {
+        assert_eq!(extract::<Queries>("/queries").await, Queries(1));
+        assert_eq!(extract::<Queries>("/queries?queries=").await, Queries(1));
+        assert_eq!(extract::<Queries>("/queries?queries=20").await, Queries(20));
+        assert_eq!(extract::<Queries>("/queries?count=20").await, Queries(1));
+        assert_eq!(extract::<Count>("/cached?count=20").await, Count(20));
+        assert_eq!(extract::<Count>("/cached?queries=20").await, Count(1));
+    }
+
+    #[tokio::test]
+    async fn malformed_query_strings_count_as_one() {
+        assert_eq!(extract::<Queries>("/queries?queries=%FF").await, Queries(1));
+        assert_eq!(
+            extract::<Queries>("/queries?queries=1&queries=2").await,
+            Queries(1)
+        );
+    }
+}
@@ -0,0 +1,84 @@
+//! Startup check that `world` lookups by id can use an index.
+//!
+//! Without one, every `/db`, `/queries` and `/updates` lookup is a sequential
+//! scan of the table, which is by far the most common cause of unexpectedly
+//! bad numbers. The Postgres backends run the check once at startup and warn
+//! when the index is missing, or create it when
+//! `AXUM_TECHEMPOWER_CREATE_MISSING_INDEXES` is `true`. MongoDB always
+//! indexes `_id`, which is what its lookups use.
+
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use sqlx::{Executor, PgPool};
+
+use super::tables::{self, Sql};
+use crate::utils::get_environment_variable_or;
+
+/// Whether some index on `world` has `id` as its leading column.
+static WORLD_ID_INDEXED: Sql = Sql::new(
+    "SELECT EXISTS (\
+    SELECT 1 FROM pg_index i \
+    JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = i.indkey[0] \
+    WHERE i.indrelid = '{world}'::regclass AND a.attname = 'id')",
+);
+
+static CREATE_WORLD_ID_INDEX: Sql =
+    Sql::new("CREATE INDEX IF NOT EXISTS {world}_id_idx ON {world} (id)");
+
+/// Checks for the index over a `tokio_postgres` connection.
+pub async fn check_world_index(
+    client: &tokio_postgres::Client,
+) -> Result<(), tokio_postgres::Error> {
+    check(
+        async {
+            // unnamed, so the check also works through PgBouncer in
+            // transaction pooling mode
+            let rows = client.query_typed(WORLD_ID_INDEXED.get(), &[]).await?;
+            Ok(rows.first().is_some_and(|row| row.get(0)))
+        },
+        || client.batch_execute(CREATE_WORLD_ID_INDEX.get()),
+    )
+    .await
+}
+
+/// Checks for the index over an sqlx pool.
+pub async fn check_world_index_sqlx(pool: &PgPool) -> Result<(), sqlx::Error> {
+    check(
+        sqlx::query_scalar(WORLD_ID_INDEXED.get()).fetch_one(pool),
+        || async { pool.execute(CREATE_WORLD_ID_INDEX.get()).await.map(drop) },
+    )
+    .await
+}
+
+/// Only the first call of the process checks, so per-core workers don't all
+/// warn, or race to create the index.
+async fn check<E, F>(
+    indexed: impl Future<Output = Result<bool, E>>,
+    create: impl FnOnce() -> F,
+) -> Result<(), E>
+where
+    F: Future<Output = Result<(), E>>,
+{
+    static CHECKED: AtomicBool = AtomicBool::new(false);
+
+    if CHECKED.swap(true, Ordering::Relaxed) || indexed.await? {
+        return Ok(());
+    }
+
+    if get_environment_variable_or("AXUM_TECHEMPOWER_CREATE_MISSING_INDEXES", false) {
+        create().await?;
+        println!("created index on {} (id)", tables::world());
+    } else {
+        eprintln!(
+            "WARNING: {}.id is not indexed, every world lookup scans the whole \
+             table. Set AXUM_TECHEMPOWER_CREATE_MISSING_INDEXES=true to create \
+             the index at startup.",
+            tables::world()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,46 @@
+//! Fortune ordering.
+//!
+//! TFB requires fortunes sorted by the bytes of their message, which is what
+//! every backend does by default. Built with the `collation` feature, setting
+//! `AXUM_TECHEMPOWER_FORTUNES_LOCALE` (e.g. `de` or `sv`) sorts them by that
+//! locale's collation rules instead, for deployments that care about how the
+//! page reads rather than about passing verification.
+
+/// Sorts `items` by the string `message` returns for each of them.
+#[cfg(not(feature = "collation"))]
+pub fn sort_by_message<T>(items: &mut [T], message: fn(&T) -> &str) {
+    items.sort_by(|a, b| message(a).cmp(message(b)));
+}
+
+/// Sorts `items` by the string `message` returns for each of them.
+#[cfg(feature = "collation")]
+pub fn sort_by_message<T>(items: &mut [T], message: fn(&T) -> &str) {
+    COLLATOR.with(|collator| match collator {
+        Some(collator) => items.sort_by(|a, b| collator.compare(message(a), message(b))),
+        None => items.sort_by(|a, b| message(a).cmp(message(b))),
+    });
+}
+
+#[cfg(feature = "collation")]
+thread_local! {
+    static COLLATOR: Option<icu_collator::Collator> = collator();
+}
+
+#[cfg(feature = "collation")]
+fn collator() -> Option<icu_collator::Collator> {
+    let locale: String = crate::utils::get_optional_environment_variable(
+        "AXUM_TECHEMPOWER_FORTUNES_LOCALE",
+    )?;
+
+    let locale: icu_locid::Locale = locale
+        .parse()
+        .unwrap_or_else(|err| panic!("invalid fortunes locale {locale:?}: {err}"));
+
+    let collator = icu_collator::Collator::try_new(
+        &(&locale).into(),
+        icu_collator::CollatorOptions::new(),
+    )
+    .unwrap_or_else(|err| panic!("no collation for fortunes locale {locale}: {err}"));
+
+    Some(collator)
+}
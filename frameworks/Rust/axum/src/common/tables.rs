@@ -0,0 +1,76 @@
+//! Names of the `world` and `fortune` tables, or collections for MongoDB, and
+//! of the `schema_migrations` table kept by `--migrate`.
+//!
+//! `AXUM_TECHEMPOWER_TABLE_PREFIX` prefixes all of them, e.g. `run42_` for
+//! `run42_world`, so several benchmark variants can share one database server
+//! without clobbering each other's data. The prefix may only contain ASCII
+//! letters, digits and `_`, since it ends up in SQL unquoted.
+
+use std::sync::OnceLock;
+
+use crate::utils::get_environment_variable_or;
+
+struct Tables {
+    world: String,
+    fortune: String,
+    migrations: String,
+}
+
+fn tables() -> &'static Tables {
+    static TABLES: OnceLock<Tables> = OnceLock::new();
+
+    TABLES.get_or_init(|| {
+        let prefix: String =
+            get_environment_variable_or("AXUM_TECHEMPOWER_TABLE_PREFIX", String::new());
+
+        assert!(
+            prefix
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "AXUM_TECHEMPOWER_TABLE_PREFIX may only contain ASCII letters, digits and _"
+        );
+
+        Tables {
+            world: format!("{prefix}world"),
+            fortune: format!("{prefix}fortune"),
+            migrations: format!("{prefix}schema_migrations"),
+        }
+    })
+}
+
+pub fn world() -> &'static str {
+    &tables().world
+}
+
+pub fn fortune() -> &'static str {
+    &tables().fortune
+}
+
+pub fn migrations() -> &'static str {
+    &tables().migrations
+}
+
+/// SQL statement naming its tables `{world}`, `{fortune}` and `{migrations}`,
+/// rendered with the actual names on first use.
+pub struct Sql {
+    template: &'static str,
+    rendered: OnceLock<String>,
+}
+
+impl Sql {
+    pub const fn new(template: &'static str) -> Self {
+        Self {
+            template,
+            rendered: OnceLock::new(),
+        }
+    }
+
+    pub fn get(&self) -> &str {
+        self.rendered.get_or_init(|| {
+            self.template
+                .replace("{world}", world())
+                .replace("{fortune}", fortune())
+                .replace("{migrations}", migrations())
+        })
+    }
+}
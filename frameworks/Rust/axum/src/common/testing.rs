@@ -0,0 +1,177 @@
+//! Fixtures shared by the unit tests of several binaries.
+
+use std::convert::Infallible;
+
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    response::{IntoResponse, Response},
+    Router,
+};
+use rand::{rngs::SmallRng, SeedableRng};
+use serde::Serialize;
+use tower::{Service, ServiceExt};
+
+use crate::{
+    common::endpoints::{Requires, ENDPOINTS},
+    models_common::{WorldId, WORLD_COUNT},
+    utils::{random_id, random_number, JsonFast},
+};
+
+/// Fortunes that exercise the renderers: the one added at request time, the
+/// `<script>` fortune of the TFB table, which must come out escaped, and its
+/// Japanese one, whose multi-byte characters must come out untouched.
+pub const FORTUNES: &[(i32, &str)] = &[
+    (0, "Additional fortune added at request time."),
+    (
+        11,
+        "<script>alert(\"This should not be displayed in a browser alert box.\");</script>",
+    ),
+    (12, "フレームワークのベンチマーク"),
+];
+
+/// `FORTUNES` as rendered by the yarte template, which escapes `/` too.
+pub const TEMPLATE_PAGE: &str =
+    "<!DOCTYPE html><html><head><title>Fortunes</title></head>\
+<body><table><tr><th>id</th><th>message</th></tr>\
+<tr><td>0</td><td>Additional fortune added at request time.</td></tr>\
+<tr><td>11</td><td>&lt;script&gt;alert(&quot;This should not be displayed in a browser \
+alert box.&quot;);&lt;&#x2f;script&gt;</td></tr>\
+<tr><td>12</td><td>フレームワークのベンチマーク</td></tr>\
+</table></body></html>";
+
+/// `FORTUNES` as rendered by `html::render_fortunes`.
+pub const WRITER_PAGE: &str =
+    "<!DOCTYPE html><html><head><title>Fortunes</title></head>\
+<body><table><tr><th>id</th><th>message</th></tr>\
+<tr><td>0</td><td>Additional fortune added at request time.</td></tr>\
+<tr><td>11</td><td>&lt;script&gt;alert(&quot;This should not be displayed in a browser \
+alert box.&quot;);&lt;/script&gt;</td></tr>\
+<tr><td>12</td><td>フレームワークのベンチマーク</td></tr>\
+</table></body></html>";
+
+/// `Content-Type` the TFB requirements give each test type, by endpoint
+/// name. The streaming endpoints aren't TFB tests and have none.
+pub const TFB_CONTENT_TYPES: &[(&str, &str)] = &[
+    ("plaintext", "text/plain"),
+    ("json", "application/json"),
+    ("db", "application/json"),
+    ("queries", "application/json"),
+    ("fortunes", "text/html; charset=utf-8"),
+    ("updates", "application/json"),
+    ("cached-queries", "application/json"),
+];
+
+pub fn tfb_content_type(name: &str) -> Option<&'static str> {
+    TFB_CONTENT_TYPES
+        .iter()
+        .find(|(endpoint, _)| *endpoint == name)
+        .map(|&(_, content_type)| content_type)
+}
+
+/// Sends `GET` to `uri` and returns the status and `Content-Type` of the
+/// response.
+pub async fn content_type<S>(service: S, uri: &str) -> (StatusCode, Option<String>)
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible>,
+{
+    let request = Request::get(uri).body(Body::empty()).unwrap();
+    let response = service.oneshot(request).await.unwrap();
+
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map(|value| value.to_str().unwrap().to_string());
+    (response.status(), content_type)
+}
+
+/// Requests every endpoint of `router` that answers without a database and
+/// checks its `Content-Type`: the TFB one for those in `served`, JSON for
+/// the 501 of the others.
+pub async fn assert_content_types(router: Router, served: &[&str]) {
+    for endpoint in ENDPOINTS {
+        let uri = endpoint.warm_up.unwrap_or(endpoint.path);
+
+        if !served.contains(&endpoint.name) {
+            assert_eq!(
+                content_type(router.clone(), uri).await,
+                (
+                    StatusCode::NOT_IMPLEMENTED,
+                    Some("application/json".to_string())
+                ),
+                "{uri}"
+            );
+            continue;
+        }
+
+        let Some(expected) = tfb_content_type(endpoint.name) else {
+            continue;
+        };
+        if endpoint.requires != Requires::Nothing {
+            continue;
+        }
+        assert_eq!(
+            content_type(router.clone(), uri).await,
+            (StatusCode::OK, Some(expected.to_string())),
+            "{uri}"
+        );
+    }
+}
+
+/// Seed of the request RNG the JSON snapshots are taken with, as
+/// `AXUM_TECHEMPOWER_RNG_SEED` would seed a worker's.
+pub const SNAPSHOT_SEED: u64 = 129;
+
+/// `/json` as `JsonFast` writes it.
+pub const JSON_SNAPSHOT: &str = r#"{"message":"Hello, World!"}"#;
+
+/// `/db` for the first of `seeded_worlds`.
+pub const DB_SNAPSHOT: &str = r#"{"id":9156,"randomNumber":6908}"#;
+
+/// `/queries?queries=3` for `seeded_worlds(3)`.
+pub const QUERIES_SNAPSHOT: &str = concat!(
+    r#"[{"id":9156,"randomNumber":6908},"#,
+    r#"{"id":6569,"randomNumber":8901},"#,
+    r#"{"id":5998,"randomNumber":8098}]"#,
+);
+
+/// Worlds of a request drawing `count` ids and random numbers from a request
+/// RNG seeded with `SNAPSHOT_SEED`, the random numbers standing in for the
+/// stored ones.
+pub fn seeded_worlds(count: usize) -> Vec<(WorldId, i32)> {
+    let mut rng = SmallRng::seed_from_u64(SNAPSHOT_SEED);
+
+    (0..count)
+        .map(|_| (random_id(&mut rng), random_number(&mut rng)))
+        .collect()
+}
+
+/// Every world with a random number drawn from an RNG seeded with
+/// `SNAPSHOT_SEED`, standing in for the world table.
+pub fn seeded_store() -> Vec<(WorldId, i32)> {
+    let mut rng = SmallRng::seed_from_u64(SNAPSHOT_SEED);
+
+    (1..=WORLD_COUNT)
+        .map(|id| (WorldId::try_from(id).unwrap(), random_number(&mut rng)))
+        .collect()
+}
+
+/// The body of `response`, which must be UTF-8.
+pub async fn body_text(response: impl IntoResponse) -> String {
+    let body = hyper::body::to_bytes(response.into_response().into_body())
+        .await
+        .unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+/// Checks the `/db` and `/queries` bodies of a backend whose worlds `world`
+/// builds from an id and a random number.
+pub async fn assert_world_snapshots<W: Serialize>(world: impl Fn(WorldId, i32) -> W) {
+    let worlds: Vec<W> = seeded_worlds(3)
+        .into_iter()
+        .map(|(id, random_number)| world(id, random_number))
+        .collect();
+
+    assert_eq!(body_text(JsonFast::new(&worlds[0])).await, DB_SNAPSHOT);
+    assert_eq!(body_text(JsonFast::new(&worlds)).await, QUERIES_SNAPSHOT);
+}
@@ -0,0 +1,67 @@
+//! Optional gzip/brotli compression of `/fortunes` and `/queries` responses,
+//! enabled with the `compression` feature.
+//!
+//! TFB runs must not compress, so even when built in, compression stays off
+//! unless `AXUM_TECHEMPOWER_COMPRESSION=true`. Only responses of at least
+//! `AXUM_TECHEMPOWER_COMPRESSION_MIN_BYTES` (default 1024) are compressed,
+//! which in practice leaves out `/queries` asking for a handful of rows, where
+//! the CPU spent compressing isn't won back on the wire.
+
+use axum::{
+    http::{Extensions, HeaderMap, Request, StatusCode, Version},
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use tower_http::compression::{
+    predicate::{Predicate, SizeAbove},
+    CompressionLayer,
+};
+
+use crate::{
+    common::endpoints::{FORTUNES, QUERIES},
+    utils::get_environment_variable_or,
+};
+
+/// Marks responses of the endpoints that may be compressed.
+#[derive(Clone, Copy)]
+struct Compressible;
+
+/// Compresses the responses `router` gives to `/fortunes` and `/queries`, if
+/// enabled.
+pub fn layer(router: Router) -> Router {
+    if !get_environment_variable_or("AXUM_TECHEMPOWER_COMPRESSION", false) {
+        return router;
+    }
+
+    let min_bytes: u16 =
+        get_environment_variable_or("AXUM_TECHEMPOWER_COMPRESSION_MIN_BYTES", 1024);
+    let predicate = SizeAbove::new(min_bytes).and(compressible);
+
+    router.layer(middleware::from_fn(mark)).layer(
+        CompressionLayer::new()
+            .gzip(true)
+            .br(true)
+            .compress_when(predicate),
+    )
+}
+
+fn compressible(
+    _: StatusCode,
+    _: Version,
+    _: &HeaderMap,
+    extensions: &Extensions,
+) -> bool {
+    extensions.get::<Compressible>().is_some()
+}
+
+async fn mark<B>(request: Request<B>, next: Next<B>) -> Response {
+    let path = request.uri().path();
+    let marked = path == FORTUNES.path || path == QUERIES.path;
+
+    let mut response = next.run(request).await;
+    if marked {
+        response.extensions_mut().insert(Compressible);
+    }
+    response
+}
@@ -1,20 +1,28 @@
-use std::{convert::Infallible, io};
+use std::{convert::Infallible, error::Error, fmt, io};
 
 use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
-use futures_util::{stream::FuturesUnordered, StreamExt, TryStreamExt};
-use mongodb::{bson::doc, Database};
+use futures_util::{TryStream, TryStreamExt};
+use mongodb::bson::to_document;
 
-use crate::{Fortune, World};
+use crate::{
+    common::{sort::sort_by_message, tables},
+    models_common::WorldId,
+    models_mongo::{
+        find_all, fortune_find_options, world_find_options, Databases, WorldDocument,
+        WorldFilter,
+    },
+    Fortune, World,
+};
 
-pub struct DatabaseConnection(pub Database);
+pub struct DatabaseConnection(pub Databases);
 
 #[async_trait]
-impl FromRequestParts<Database> for DatabaseConnection {
+impl FromRequestParts<Databases> for DatabaseConnection {
     type Rejection = Infallible;
 
     async fn from_request_parts(
         _parts: &mut Parts,
-        db: &Database,
+        db: &Databases,
     ) -> Result<Self, Self::Rejection> {
         Ok(Self(db.clone()))
     }
@@ -24,85 +32,139 @@ impl FromRequestParts<Database> for DatabaseConnection {
 pub enum MongoError {
     Io(io::Error),
     Mongo(mongodb::error::Error),
+    BsonSer(mongodb::bson::ser::Error),
+    BsonDe(mongodb::bson::de::Error),
+    NotFound(WorldId),
 }
 
+impl fmt::Display for MongoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MongoError::Io(err) => err.fmt(f),
+            MongoError::Mongo(err) => err.fmt(f),
+            MongoError::BsonSer(err) => err.fmt(f),
+            MongoError::BsonDe(err) => err.fmt(f),
+            MongoError::NotFound(id) => write!(f, "world {} not found", id.get()),
+        }
+    }
+}
+
+impl Error for MongoError {}
+
 impl From<io::Error> for MongoError {
     fn from(err: io::Error) -> Self {
         MongoError::Io(err)
     }
 }
 
+impl From<mongodb::bson::ser::Error> for MongoError {
+    fn from(err: mongodb::bson::ser::Error) -> Self {
+        MongoError::BsonSer(err)
+    }
+}
+
+impl From<mongodb::bson::de::Error> for MongoError {
+    fn from(err: mongodb::bson::de::Error) -> Self {
+        MongoError::BsonDe(err)
+    }
+}
+
 impl From<mongodb::error::Error> for MongoError {
     fn from(err: mongodb::error::Error) -> Self {
         MongoError::Mongo(err)
     }
 }
 
-pub async fn find_world_by_id(db: Database, id: i32) -> Result<World, MongoError> {
-    let world_collection = db.collection::<World>("world");
+pub async fn find_world_by_id(db: &Databases, id: WorldId) -> Result<World, MongoError> {
+    let world_collection = db.read().collection::<WorldDocument>(tables::world());
 
-    let filter = doc! { "_id": id as f32 };
+    let filter = to_document(&WorldFilter::new(id))?;
 
-    let world: World = world_collection
-        .find_one(Some(filter), None)
-        .await
-        .unwrap()
-        .expect("expected world, found none");
-    Ok(world)
+    crate::totals::db_read();
+    let world = world_collection
+        .find_one(Some(filter), world_find_options())
+        .await?
+        .ok_or(MongoError::NotFound(id))?;
+    Ok(world.into())
 }
 
-pub async fn find_worlds(db: Database, ids: Vec<i32>) -> Result<Vec<World>, MongoError> {
-    let future_worlds = FuturesUnordered::new();
-
-    for id in ids {
-        future_worlds.push(find_world_by_id(db.clone(), id));
-    }
-
-    let worlds: Result<Vec<World>, MongoError> = future_worlds.try_collect().await;
-    worlds
+/// Looks up all `ids` concurrently, see `models_mongo::find_all`.
+pub async fn find_worlds(
+    db: &Databases,
+    ids: Vec<WorldId>,
+) -> Result<Vec<World>, MongoError> {
+    find_all(ids, |id| find_world_by_id(db, id)).await
 }
 
-pub async fn fetch_fortunes(db: Database) -> Result<Vec<Fortune>, MongoError> {
-    let fortune_collection = db.collection::<Fortune>("fortune");
+/// Loads every fortune and adds the one added at request time, see
+/// `collect_fortunes`.
+pub async fn fetch_fortunes(db: &Databases) -> Result<Vec<Fortune>, MongoError> {
+    let fortune_collection = db.read().collection::<Fortune>(tables::fortune());
 
-    let mut fortune_cursor = fortune_collection
-        .find(None, None)
-        .await
-        .expect("fortunes could not be loaded");
+    crate::totals::db_read();
+    let cursor = fortune_collection
+        .find(None, fortune_find_options())
+        .await?;
+    Ok(collect_fortunes(cursor).await?)
+}
 
-    let mut fortunes: Vec<Fortune> = Vec::new();
-
-    while let Some(doc) = fortune_cursor.next().await {
-        fortunes.push(doc.expect("could not load fortune"));
-    }
+/// Reads the fortunes off `cursor`, adds the one added at request time and
+/// sorts them.
+///
+/// An error part-way through the cursor, e.g. a document that doesn't decode
+/// or a failed `getMore`, fails the whole call: a partial list would render a
+/// page that looks valid but isn't.
+async fn collect_fortunes<E>(
+    cursor: impl TryStream<Ok = Fortune, Error = E>,
+) -> Result<Vec<Fortune>, E> {
+    let mut fortunes: Vec<Fortune> = cursor.try_collect().await?;
 
     fortunes.push(Fortune {
         id: 0,
         message: "Additional fortune added at request time.".to_string(),
     });
 
-    fortunes.sort_by(|a, b| a.message.cmp(&b.message));
+    sort_by_message(&mut fortunes, |fortune| &fortune.message);
     Ok(fortunes)
 }
 
-pub async fn update_worlds(
-    db: Database,
-    worlds: Vec<World>,
-) -> Result<bool, MongoError> {
-    let mut updates = Vec::new();
+#[cfg(test)]
+mod tests {
+    use futures_util::stream;
+
+    use super::*;
 
-    for world in worlds {
-        updates.push(doc! {
-        "q": { "id": world.id }, "u": { "$set": { "randomNumber": world.random_number }}
-        });
+    fn fortune(id: i32, message: &str) -> Fortune {
+        Fortune {
+            id,
+            message: message.to_string(),
+        }
     }
 
-    db.run_command(
-        doc! {"update": "world", "updates": updates, "ordered": false},
-        None,
-    )
-    .await
-    .expect("could not update worlds");
+    #[tokio::test]
+    async fn adds_the_request_time_fortune_and_sorts() {
+        let cursor = stream::iter([Ok::<_, ()>(fortune(2, "b")), Ok(fortune(1, "B"))]);
+
+        let ids: Vec<i32> = collect_fortunes(cursor)
+            .await
+            .unwrap()
+            .iter()
+            .map(|fortune| fortune.id)
+            .collect();
+        assert_eq!(ids, [0, 1, 2]);
+    }
 
-    Ok(true)
+    #[tokio::test]
+    async fn an_error_mid_cursor_fails_the_whole_list() {
+        let cursor = stream::iter([
+            Ok(fortune(1, "fortune: No such file or directory")),
+            Err("getMore failed"),
+            Ok(fortune(
+                2,
+                "A computer scientist is someone who fixes things",
+            )),
+        ]);
+
+        assert_eq!(collect_fortunes(cursor).await, Err("getMore failed"));
+    }
 }
@@ -1,23 +1,30 @@
-use std::{convert::Infallible, io};
+#[cfg(feature = "raw-json")]
+use std::fmt::Write;
+use std::{convert::Infallible, error::Error, fmt, io};
 
 use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+#[cfg(feature = "raw-json")]
+use bytes::{BufMut, BytesMut};
+#[cfg(feature = "raw-json")]
 use futures_util::{stream::FuturesUnordered, TryStreamExt};
-use mongodb::{
-    bson::{doc, RawDocumentBuf},
-    Database,
-};
+use mongodb::bson::{to_document, RawDocument, RawDocumentBuf};
 
-use crate::World;
+use crate::{
+    common::tables,
+    models_common::WorldId,
+    models_mongo::{bson_integer, find_all, world_find_options, Databases, WorldFilter},
+    World,
+};
 
-pub struct DatabaseConnection(pub Database);
+pub struct DatabaseConnection(pub Databases);
 
 #[async_trait]
-impl FromRequestParts<Database> for DatabaseConnection {
+impl FromRequestParts<Databases> for DatabaseConnection {
     type Rejection = Infallible;
 
     async fn from_request_parts(
         _parts: &mut Parts,
-        db: &Database,
+        db: &Databases,
     ) -> Result<Self, Self::Rejection> {
         Ok(Self(db.clone()))
     }
@@ -27,76 +34,191 @@ impl FromRequestParts<Database> for DatabaseConnection {
 pub enum MongoError {
     Io(io::Error),
     Mongo(mongodb::error::Error),
+    BsonSer(mongodb::bson::ser::Error),
+    BsonDe(mongodb::bson::de::Error),
+    NotFound(WorldId),
+    MalformedWorld(WorldId),
+}
+
+impl fmt::Display for MongoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MongoError::Io(err) => err.fmt(f),
+            MongoError::Mongo(err) => err.fmt(f),
+            MongoError::BsonSer(err) => err.fmt(f),
+            MongoError::BsonDe(err) => err.fmt(f),
+            MongoError::NotFound(id) => write!(f, "world {} not found", id.get()),
+            MongoError::MalformedWorld(id) => {
+                write!(f, "world {} is not a valid world document", id.get())
+            }
+        }
+    }
 }
 
+impl Error for MongoError {}
+
 impl From<io::Error> for MongoError {
     fn from(err: io::Error) -> Self {
         MongoError::Io(err)
     }
 }
 
+impl From<mongodb::bson::ser::Error> for MongoError {
+    fn from(err: mongodb::bson::ser::Error) -> Self {
+        MongoError::BsonSer(err)
+    }
+}
+
+impl From<mongodb::bson::de::Error> for MongoError {
+    fn from(err: mongodb::bson::de::Error) -> Self {
+        MongoError::BsonDe(err)
+    }
+}
+
 impl From<mongodb::error::Error> for MongoError {
     fn from(err: mongodb::error::Error) -> Self {
         MongoError::Mongo(err)
     }
 }
 
-pub async fn find_world_by_id(db: Database, id: i32) -> Result<World, MongoError> {
-    let world_collection = db.collection::<RawDocumentBuf>("world");
+async fn find_raw_world(
+    db: &Databases,
+    id: WorldId,
+) -> Result<RawDocumentBuf, MongoError> {
+    let world_collection = db.read().collection::<RawDocumentBuf>(tables::world());
+
+    let filter = to_document(&WorldFilter::new(id))?;
+
+    crate::totals::db_read();
+    let raw = world_collection
+        .find_one(Some(filter), world_find_options())
+        .await?
+        .ok_or(MongoError::NotFound(id))?;
+    Ok(raw)
+}
 
-    let filter = doc! { "_id": id as f32 };
+pub async fn find_world_by_id(db: &Databases, id: WorldId) -> Result<World, MongoError> {
+    let raw = find_raw_world(db, id).await?;
+    world_from_raw(&raw, id)
+}
 
-    let raw: RawDocumentBuf = world_collection
-        .find_one(Some(filter), None)
-        .await
-        .unwrap()
-        .expect("expected world, found none");
+/// Reads the `World` looked up as `id` out of the raw BSON document.
+pub fn world_from_raw(raw: &RawDocument, id: WorldId) -> Result<World, MongoError> {
+    let field = |key| raw.get(key).ok().flatten().and_then(bson_integer);
 
     Ok(World {
-        id: raw
-            .get("id")
-            .expect("expected to parse world id")
-            .expect("could not get world id")
-            .as_i32()
-            .expect("could not extract world id"),
-        random_number: raw
-            .get("id")
-            .expect("expected to parse world id")
-            .expect("could not get world id")
-            .as_i32()
-            .expect("could not extract world id"),
+        id: field("_id")
+            .and_then(|id| id.try_into().ok())
+            .ok_or(MongoError::MalformedWorld(id))?,
+        random_number: field("randomNumber").ok_or(MongoError::MalformedWorld(id))?,
     })
 }
 
-pub async fn find_worlds(db: Database, ids: Vec<i32>) -> Result<Vec<World>, MongoError> {
-    let future_worlds = FuturesUnordered::new();
+/// Like `find_world_by_id`, but appends the world to `out` as JSON, see
+/// `write_raw_world`.
+#[cfg(feature = "raw-json")]
+pub async fn write_world_json(
+    db: &Databases,
+    id: WorldId,
+    out: &mut BytesMut,
+) -> Result<(), MongoError> {
+    let raw = find_raw_world(db, id).await?;
+    write_raw_world(&raw, id, out)
+}
 
-    for id in ids {
-        future_worlds.push(find_world_by_id(db.clone(), id));
+/// Like `find_worlds`, but appends the worlds to `out` as a JSON array, see
+/// `write_raw_world`. Each world is written as soon as its lookup completes.
+#[cfg(feature = "raw-json")]
+pub async fn write_worlds_json(
+    db: &Databases,
+    ids: Vec<WorldId>,
+    out: &mut BytesMut,
+) -> Result<(), MongoError> {
+    let mut lookups: FuturesUnordered<_> = ids
+        .into_iter()
+        .map(
+            |id| async move { Ok::<_, MongoError>((id, find_raw_world(db, id).await?)) },
+        )
+        .collect();
+
+    out.put_u8(b'[');
+    let mut first = true;
+    while let Some((id, raw)) = lookups.try_next().await? {
+        if !first {
+            out.put_u8(b',');
+        }
+        first = false;
+        write_raw_world(&raw, id, out)?;
     }
+    out.put_u8(b']');
 
-    let worlds: Result<Vec<World>, MongoError> = future_worlds.try_collect().await;
-    worlds
+    Ok(())
 }
 
-pub async fn update_worlds(
-    db: Database,
-    worlds: Vec<World>,
-) -> Result<bool, MongoError> {
-    let mut updates = Vec::new();
+/// Walks the raw BSON document and writes its `_id` (as `id`) and
+/// `randomNumber` fields as a JSON object, without building a `World` or going
+/// through serde. Other fields, such as the `id` copy the TFB setup scripts
+/// store, are skipped.
+#[cfg(feature = "raw-json")]
+pub fn write_raw_world(
+    raw: &RawDocument,
+    id: WorldId,
+    out: &mut BytesMut,
+) -> Result<(), MongoError> {
+    let mut separator = b'{';
+    let mut written = 0;
+
+    for element in raw {
+        let (key, value) = element.map_err(|_| MongoError::MalformedWorld(id))?;
+        let name = match key {
+            "_id" => "id",
+            "randomNumber" => "randomNumber",
+            _ => continue,
+        };
+        let value = bson_integer(value).ok_or(MongoError::MalformedWorld(id))?;
+
+        out.put_u8(separator);
+        separator = b',';
+        written += 1;
+        let _ = write!(out, "\"{name}\":{value}");
+    }
 
-    for world in worlds {
-        updates.push(doc! {
-        "q": { "id": world.id }, "u": { "$set": { "randomNumber": world.random_number }}
-        });
+    if written != 2 {
+        return Err(MongoError::MalformedWorld(id));
     }
+    out.put_u8(b'}');
 
-    db.run_command(
-        doc! {"update": "world", "updates": updates, "ordered": false},
-        None,
-    )
-    .await
-    .expect("could not update worlds");
+    Ok(())
+}
 
-    Ok(true)
+/// Looks up all `ids` concurrently, see `models_mongo::find_all`.
+pub async fn find_worlds(
+    db: &Databases,
+    ids: Vec<WorldId>,
+) -> Result<Vec<World>, MongoError> {
+    find_all(ids, |id| find_world_by_id(db, id)).await
+}
+
+#[cfg(all(test, feature = "raw-json"))]
+mod tests {
+    use mongodb::bson::rawdoc;
+
+    use super::*;
+    use crate::common::testing;
+
+    /// The raw path has to write what the serde path does, including for the
+    /// `id` copy the TFB setup scripts store next to `_id`.
+    #[test]
+    fn writes_worlds_byte_for_byte() {
+        let (id, random_number) = testing::seeded_worlds(1)[0];
+        let raw = rawdoc! {
+            "_id": id.get(),
+            "id": f64::from(id.get()),
+            "randomNumber": random_number,
+        };
+
+        let mut out = BytesMut::new();
+        write_raw_world(&raw, id, &mut out).unwrap();
+        assert_eq!(out, testing::DB_SNAPSHOT.as_bytes());
+    }
 }
@@ -1,14 +1,25 @@
-use std::{collections::HashMap, convert::Infallible, fmt::Write, io, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::{self, Write},
+    io,
+    sync::Arc,
+};
 
 use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
 use futures::{
     stream::futures_unordered::FuturesUnordered, FutureExt, StreamExt, TryStreamExt,
 };
-use rand::{rngs::SmallRng, thread_rng, Rng, SeedableRng};
+use rand::rngs::SmallRng;
 use tokio::pin;
-use tokio_postgres::{connect, types::ToSql, Client, NoTls, Statement};
+use tokio_postgres::{types::ToSql, Client, Config, NoTls, Statement};
 
-use crate::models_pg::{Fortune, World};
+use crate::{
+    common::{durability, migrate, params, schema, sort::sort_by_message, tables},
+    models_common::WorldId,
+    models_pg::{Fortune, World},
+    utils::{random_id, random_number},
+};
 
 #[derive(Debug)]
 pub enum PgError {
@@ -28,6 +39,19 @@ impl From<tokio_postgres::Error> for PgError {
     }
 }
 
+impl fmt::Display for PgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PgError::Io(err) => err.fmt(f),
+            PgError::Pg(err) => err.fmt(f),
+        }
+    }
+}
+
+/// Largest batch `update` can prepare a statement for: Postgres accepts at most
+/// 65535 bind parameters and the statement binds three per world.
+const MAX_UPDATE_BATCH: usize = u16::MAX as usize / 3;
+
 /// Postgres interface
 pub struct PgConnection {
     client: Client,
@@ -37,10 +61,32 @@ pub struct PgConnection {
 }
 
 impl PgConnection {
-    pub async fn connect(db_url: String) -> Arc<PgConnection> {
-        let (cl, conn) = connect(&db_url, NoTls)
-            .await
-            .expect("can not connect to postgresql");
+    /// Connects, migrates the schema if started with `--migrate`, and
+    /// prepares every statement up front, so an unreachable database or a
+    /// wrong schema fails at startup rather than on the first request.
+    ///
+    /// All requests of a worker share this connection, so abandoned requests
+    /// are not cancelled server-side (that would hit whichever query happens to
+    /// be running); `statement_timeout_ms` bounds them instead. With relaxed
+    /// durability the connection commits with `synchronous_commit = off`;
+    /// only the updates write.
+    pub async fn connect(
+        db_url: String,
+        statement_timeout_ms: Option<u64>,
+    ) -> Result<Arc<PgConnection>, PgError> {
+        let mut config: Config = db_url.parse()?;
+        let mut options = Vec::new();
+        if let Some(timeout) = statement_timeout_ms {
+            options.push(format!("-c statement_timeout={timeout}"));
+        }
+        if durability::relaxed() {
+            options.push("-c synchronous_commit=off".to_string());
+        }
+        if !options.is_empty() {
+            config.options(&options.join(" "));
+        }
+
+        let (cl, conn) = config.connect(NoTls).await?;
 
         // Spawn connection
         tokio::spawn(async move {
@@ -49,14 +95,27 @@ impl PgConnection {
             }
         });
 
-        let fortune = cl.prepare("SELECT * FROM fortune").await.unwrap();
+        if migrate::requested() {
+            migrate::run(&cl).await?;
+        }
+        schema::check_world_index(&cl).await?;
+
+        let fortune = cl
+            .prepare(&format!("SELECT * FROM {}", tables::fortune()))
+            .await?;
         let mut updates = HashMap::new();
 
-        for num in 1..=500u16 {
+        let max_queries = params::max();
+        assert!(
+            max_queries <= MAX_UPDATE_BATCH,
+            "AXUM_TECHEMPOWER_MAX_QUERIES can be at most {MAX_UPDATE_BATCH} for axum-pg"
+        );
+
+        for num in 1..=max_queries as u16 {
             let mut pl = 1;
             let mut q = String::new();
 
-            q.push_str("UPDATE world SET randomnumber = CASE id ");
+            let _ = write!(q, "UPDATE {} SET randomnumber = CASE id ", tables::world());
 
             for _ in 1..=num {
                 let _ = write!(q, "when ${pl} then ${} ", pl + 1);
@@ -73,22 +132,25 @@ impl PgConnection {
             q.pop();
             q.push(')');
 
-            updates.insert(num, cl.prepare(&q).await.unwrap());
+            updates.insert(num, cl.prepare(&q).await?);
         }
 
-        let world = cl.prepare("SELECT * FROM world WHERE id=$1").await.unwrap();
+        let world = cl
+            .prepare(&format!("SELECT * FROM {} WHERE id=$1", tables::world()))
+            .await?;
 
-        Arc::new(PgConnection {
+        Ok(Arc::new(PgConnection {
             client: cl,
             fortune,
             world,
             updates,
-        })
+        }))
     }
 }
 
 impl PgConnection {
-    async fn query_one_world(&self, id: i32) -> Result<World, PgError> {
+    async fn query_one_world(&self, id: WorldId) -> Result<World, PgError> {
+        crate::totals::db_read();
         let stream = self.client.query_raw(&self.world, &[&id]).await?;
         pin!(stream);
         let row = stream.next().await.unwrap()?;
@@ -98,40 +160,39 @@ impl PgConnection {
         })
     }
 
-    pub async fn get_world(&self) -> Result<World, PgError> {
-        let mut rng = SmallRng::from_rng(&mut thread_rng()).unwrap();
-
-        let random_id = (rng.gen::<u32>() % 10_000 + 1) as i32;
-
-        let world = self.query_one_world(random_id).await?;
+    pub async fn get_world(&self, rng: &mut SmallRng) -> Result<World, PgError> {
+        let world = self.query_one_world(random_id(rng)).await?;
         Ok(world)
     }
 
-    pub async fn get_worlds(&self, num: usize) -> Result<Vec<World>, PgError> {
-        let mut rng = SmallRng::from_rng(&mut thread_rng()).unwrap();
-
+    pub async fn get_worlds(
+        &self,
+        rng: &mut SmallRng,
+        num: usize,
+    ) -> Result<Vec<World>, PgError> {
         let worlds = FuturesUnordered::new();
 
         for _ in 0..num {
-            let w_id = (rng.gen::<u32>() % 10_000 + 1) as i32;
-            worlds.push(self.query_one_world(w_id));
+            worlds.push(self.query_one_world(random_id(rng)));
         }
 
         worlds.try_collect().await
     }
 
-    pub async fn update(&self, num: u16) -> Result<Vec<World>, PgError> {
-        let mut rng = SmallRng::from_rng(&mut thread_rng()).unwrap();
-
+    pub async fn update(
+        &self,
+        rng: &mut SmallRng,
+        num: u16,
+    ) -> Result<Vec<World>, PgError> {
         let worlds = FuturesUnordered::new();
 
         for _ in 0..num {
-            let id = (rng.gen::<u32>() % 10_000 + 1) as i32;
-            let w_id = (rng.gen::<u32>() % 10_000 + 1) as i32;
+            let random_number = random_number(rng);
+            let w_id = random_id(rng);
 
             worlds.push(self.query_one_world(w_id).map(move |res| match res {
                 Ok(mut world) => {
-                    world.randomnumber = id;
+                    world.randomnumber = random_number;
                     Ok(world)
                 }
 
@@ -154,6 +215,7 @@ impl PgConnection {
             params.push(&w.id);
         }
 
+        crate::totals::db_write();
         self.client.query(&st, &params[..]).await?;
 
         Ok(worlds)
@@ -165,6 +227,7 @@ impl PgConnection {
             message: "Additional fortune added at request time.".parse().unwrap(),
         }];
 
+        crate::totals::db_read();
         let fut = self.client.query_raw::<_, _, &[i32; 0]>(&self.fortune, &[]);
 
         let stream = fut.await?;
@@ -179,7 +242,7 @@ impl PgConnection {
             });
         }
 
-        items.sort_by(|it, next| it.message.cmp(&next.message));
+        sort_by_message(&mut items, |fortune| &fortune.message);
         Ok(items)
     }
 }
@@ -1,20 +1,197 @@
-use std::io;
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    io,
+    ops::Deref,
+    pin::pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
 
 use axum::{
     async_trait,
-    extract::FromRequestParts,
+    extract::{FromRef, FromRequestParts},
     http::{request::Parts, StatusCode},
 };
-use deadpool_postgres::{Client, Manager, ManagerConfig, RecyclingMethod};
+use deadpool::managed::Object;
+use deadpool_postgres::{
+    Client, Manager, ManagerConfig, PoolError, RecyclingMethod, Runtime,
+};
+use futures_util::{
+    future::{join, try_join_all},
+    stream::{self, FuturesUnordered},
+    Stream, StreamExt, TryStreamExt,
+};
+use rand::rngs::SmallRng;
+use tokio::runtime::Handle;
 use tokio_pg_mapper::FromTokioPostgresRow;
-use tokio_postgres::{NoTls, Row, Statement};
+use tokio_postgres::{
+    error::SqlState,
+    types::{ToSql, Type},
+    NoTls, Row, SimpleQueryMessage, Statement,
+};
+
+use crate::{
+    cache::WorldCache,
+    common::{durability, sort::sort_by_message, tables::Sql},
+    deadline,
+    models_common::WorldId,
+    pool_tuning,
+    utils::{get_environment_variable_or, internal_error, random_number},
+    Fortune, World,
+};
+
+static FETCH_ALL_FORTUNES: Sql = Sql::new("SELECT * FROM {fortune}");
+static FETCH_ALL_WORLDS: Sql = Sql::new("SELECT id, randomnumber FROM {world}");
+static FETCH_WORLD_BY_ID: Sql =
+    Sql::new("SELECT id, randomnumber FROM {world} WHERE id = $1");
+static UPDATE_WORLD_BY_ID: Sql =
+    Sql::new("UPDATE {world} SET randomnumber = $1 WHERE id = $2");
+
+const NO_PARAMS: &[Type] = &[];
+const WORLD_ID_PARAMS: &[Type] = &[Type::INT4];
+const UPDATE_PARAMS: &[Type] = &[Type::INT4, Type::INT4];
+
+/// Whether the pool connects through PgBouncer in transaction pooling mode,
+/// set with `AXUM_TECHEMPOWER_TRANSACTION_POOLING=true`.
+///
+/// PgBouncer then hands each transaction of a client to whichever server
+/// connection is free, so nothing may rely on session state: statements are
+/// sent unnamed with their parameter types, parsed along with every execution
+/// instead of prepared once per connection, and no session settings are sent
+/// at connect time. Only this backend supports it; `axum-pg` and `axum-sqlx`
+/// need session pooling or a direct connection.
+pub fn transaction_pooling() -> bool {
+    static POOLING: OnceLock<bool> = OnceLock::new();
+
+    *POOLING.get_or_init(|| {
+        get_environment_variable_or("AXUM_TECHEMPOWER_TRANSACTION_POOLING", false)
+    })
+}
+
+/// A statement of this backend, prepared on the connection unless
+/// `transaction_pooling` is on.
+pub enum Query {
+    Prepared(Statement),
+    Unnamed(&'static str, &'static [Type]),
+}
+
+/// What the queries of this module run on: a pooled client as checked out
+/// at startup, or a `DatabaseClient`, which notes whether one of its queries
+/// is outstanding.
+pub trait Connection: Sync {
+    fn client(&self) -> &Client;
+
+    /// Number of requests sent on the connection that haven't completed, if
+    /// it keeps count.
+    fn outstanding(&self) -> Option<&AtomicUsize> {
+        None
+    }
+}
+
+impl Connection for Client {
+    fn client(&self) -> &Client {
+        self
+    }
+}
+
+/// Runs `request`, counting it as outstanding until it completes. A request
+/// dropped before that stays counted, which is how `DatabaseClient` tells
+/// that it was abandoned mid-query.
+async fn tracked<T>(
+    connection: &impl Connection,
+    request: impl Future<Output = T>,
+) -> T {
+    let outstanding = connection.outstanding();
+    if let Some(outstanding) = outstanding {
+        outstanding.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let result = request.await;
+
+    if let Some(outstanding) = outstanding {
+        outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+    result
+}
+
+async fn prepare(
+    connection: &impl Connection,
+    sql: &'static Sql,
+    types: &'static [Type],
+) -> Result<Query, PgError> {
+    if transaction_pooling() {
+        return Ok(Query::Unnamed(sql.get(), types));
+    }
+
+    let client = connection.client();
+    let statement = tracked(connection, client.prepare_cached(sql.get())).await?;
+    Ok(Query::Prepared(statement))
+}
+
+async fn query(
+    connection: &impl Connection,
+    query: &Query,
+    params: &[&(dyn ToSql + Sync)],
+) -> Result<Vec<Row>, PgError> {
+    crate::totals::db_read();
+
+    let client = connection.client();
+    let rows = tracked(connection, async {
+        let rows = match query {
+            Query::Prepared(statement) => client.query(statement, params).await?,
+            Query::Unnamed(sql, types) => {
+                let params = params.iter().copied().zip(types.iter().cloned());
+                client
+                    .query_typed_raw(sql, params)
+                    .await?
+                    .try_collect()
+                    .await?
+            }
+        };
+        Ok::<_, PgError>(rows)
+    })
+    .await?;
 
-use crate::{utils::internal_error, Fortune, World};
+    Ok(rows)
+}
+
+async fn execute(
+    connection: &impl Connection,
+    query: &Query,
+    params: &[&(dyn ToSql + Sync)],
+) -> Result<u64, PgError> {
+    crate::totals::db_write();
+
+    let client = connection.client();
+    let modified = tracked(connection, async {
+        let modified = match query {
+            Query::Prepared(statement) => client.execute(statement, params).await?,
+            Query::Unnamed(sql, types) => {
+                let params = params.iter().copied().zip(types.iter().cloned());
+                let mut rows = pin!(client.query_typed_raw(sql, params).await?);
+                while rows.try_next().await?.is_some() {}
+                rows.rows_affected().unwrap_or(0)
+            }
+        };
+        Ok::<_, PgError>(modified)
+    })
+    .await?;
+
+    Ok(modified)
+}
 
 #[derive(Debug)]
 pub enum PgError {
     Io(io::Error),
     Pg(tokio_postgres::Error),
+    Pool(PoolError),
+    Mapping(tokio_pg_mapper::Error),
+    MissingWorld(WorldId),
 }
 
 impl From<io::Error> for PgError {
@@ -29,12 +206,72 @@ impl From<tokio_postgres::Error> for PgError {
     }
 }
 
+impl From<PoolError> for PgError {
+    fn from(err: PoolError) -> Self {
+        PgError::Pool(err)
+    }
+}
+
+impl From<tokio_pg_mapper::Error> for PgError {
+    fn from(err: tokio_pg_mapper::Error) -> Self {
+        PgError::Mapping(err)
+    }
+}
+
+impl fmt::Display for PgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PgError::Io(err) => err.fmt(f),
+            PgError::Pg(err) => err.fmt(f),
+            PgError::Pool(err) => err.fmt(f),
+            PgError::Mapping(err) => err.fmt(f),
+            PgError::MissingWorld(id) => write!(f, "world {} not found", id.get()),
+        }
+    }
+}
+
+impl Error for PgError {}
+
+/// Answers a failed query with 503 if Postgres cancelled it, which is what a
+/// statement timeout or a request deadline does, and with 500 otherwise.
+pub fn query_error(err: PgError) -> (StatusCode, String) {
+    let cancelled = matches!(
+        &err,
+        PgError::Pg(err) if err.code() == Some(&SqlState::QUERY_CANCELED)
+    );
+    if cancelled {
+        return (StatusCode::SERVICE_UNAVAILABLE, err.to_string());
+    }
+
+    internal_error(err)
+}
+
+/// Without `wait_timeout_ms`, requests wait for a free connection for as long
+/// as it takes; with it, a checkout that waits longer fails with
+/// `PoolError::Timeout`, which requests answer with 503.
 pub async fn create_pool(
     database_url: String,
     max_pool_size: u32,
+    statement_timeout_ms: Option<u64>,
+    wait_timeout_ms: Option<u64>,
 ) -> deadpool_postgres::Pool {
-    let pg_config: tokio_postgres::Config =
+    let mut pg_config: tokio_postgres::Config =
         database_url.parse().expect("invalid database url");
+    assert!(
+        !(durability::relaxed() && transaction_pooling()),
+        "AXUM_TECHEMPOWER_BENCHMARK_DURABILITY=relaxed needs session state, which \
+         transaction pooling doesn't keep"
+    );
+    match statement_timeout_ms {
+        Some(_) if transaction_pooling() => eprintln!(
+            "WARNING: AXUM_TECHEMPOWER_STATEMENT_TIMEOUT_MS is ignored with \
+             transaction pooling, set statement_timeout on the database role instead"
+        ),
+        Some(timeout) => {
+            pg_config.options(&format!("-c statement_timeout={timeout}"));
+        }
+        None => {}
+    }
 
     let mgr_config = ManagerConfig {
         recycling_method: RecyclingMethod::Fast,
@@ -42,81 +279,399 @@ pub async fn create_pool(
     let mgr = Manager::from_config(pg_config, NoTls, mgr_config);
     let pool: deadpool_postgres::Pool = deadpool_postgres::Pool::builder(mgr)
         .max_size(max_pool_size as usize)
+        .wait_timeout(wait_timeout_ms.map(Duration::from_millis))
+        .runtime(Runtime::Tokio1)
         .build()
         .unwrap();
 
     pool
 }
 
-pub struct DatabaseClient(pub Client);
+/// Opens `size` connections and prepares every statement on each of them, so
+/// the first requests don't pay for connection setup and schema problems show
+/// up at startup.
+pub async fn warm_up_pool(
+    pool: &deadpool_postgres::Pool,
+    size: usize,
+) -> Result<(), PgError> {
+    let clients = try_join_all((0..size).map(|_| pool.get())).await?;
+
+    try_join_all(clients.iter().map(|client| async move {
+        prepare(client, &FETCH_ALL_FORTUNES, NO_PARAMS).await?;
+        prepare(client, &FETCH_WORLD_BY_ID, WORLD_ID_PARAMS).await?;
+        prepare(client, &UPDATE_WORLD_BY_ID, UPDATE_PARAMS).await?;
+        Ok::<_, PgError>(())
+    }))
+    .await?;
+
+    Ok(())
+}
+
+async fn backend_pid(client: &Client) -> Result<String, PgError> {
+    let messages = client.simple_query("SELECT pg_backend_pid()").await?;
+
+    Ok(messages
+        .iter()
+        .find_map(|message| match message {
+            SimpleQueryMessage::Row(row) => row.get(0).map(str::to_string),
+            _ => None,
+        })
+        .unwrap_or_default())
+}
+
+/// Checks whether the connections of `pool` stay bound to one server session,
+/// and fails if they don't while prepared statements are in use.
+///
+/// One client holds a transaction while a second runs a statement, so the two
+/// are on different server connections. Once the first commits, PgBouncer in
+/// transaction pooling mode usually hands its server connection to the next
+/// transaction of the second, which then reports a different backend pid than
+/// before; a session-bound connection never changes its pid. Pools of fewer
+/// than two connections aren't checked.
+pub async fn check_pooling_mode(pool: &deadpool_postgres::Pool) -> Result<(), PgError> {
+    if pool.status().max_size < 2 {
+        return Ok(());
+    }
+
+    let (first, second) = (pool.get().await?, pool.get().await?);
+
+    first.batch_execute("BEGIN").await?;
+    let first_pid = backend_pid(&first).await?;
+    let second_pid = backend_pid(&second).await?;
+    first.batch_execute("COMMIT").await?;
+
+    second.batch_execute("BEGIN").await?;
+    let second_moved = backend_pid(&second).await? != second_pid;
+    let first_moved = backend_pid(&first).await? != first_pid;
+    second.batch_execute("COMMIT").await?;
+
+    let shared = first_moved || second_moved;
+    match (shared, transaction_pooling()) {
+        (true, false) => Err(PgError::Io(io::Error::new(
+            io::ErrorKind::Other,
+            "connections switch server sessions between transactions, which \
+             looks like PgBouncer in transaction pooling mode; set \
+             AXUM_TECHEMPOWER_TRANSACTION_POOLING=true",
+        ))),
+        (false, true) => {
+            eprintln!(
+                "note: connections look bound to their server sessions, \
+                 AXUM_TECHEMPOWER_TRANSACTION_POOLING=true may not be needed"
+            );
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Pooled client checked out for a single request.
+///
+/// If the request has a deadline, its statements run in a transaction that
+/// starts with `SET LOCAL statement_timeout` set to what is left of it, so
+/// Postgres gives up on them once nobody waits for the answer any more; it
+/// costs a round trip at checkout and the `COMMIT` in `finish`. `SET LOCAL`
+/// ends with the transaction, so it is also safe with transaction pooling.
+///
+/// If the request is dropped while one of its queries is outstanding, e.g.
+/// because the client disconnected, that query is cancelled server-side and
+/// the connection is closed rather than returned to the pool, since the
+/// cancel is delivered asynchronously and could otherwise hit whatever the
+/// connection runs next. A connection dropped inside its transaction is
+/// closed too, which rolls the transaction back. Dropped with neither,
+/// including on error paths that skip `finish`, it just goes back to the pool.
+///
+/// With pool tuning on, it also times the checkout: how long it waited for
+/// the connection, and when it got it.
+pub struct DatabaseClient {
+    client: Option<Client>,
+    outstanding: AtomicUsize,
+    transaction: bool,
+    timing: Option<(Duration, Instant)>,
+}
+
+impl DatabaseClient {
+    pub async fn checkout(pool: &deadpool_postgres::Pool) -> Result<Self, PoolError> {
+        let requested = pool_tuning::enabled().then(Instant::now);
+        let client = pool.get().await?;
+
+        let mut client = Self {
+            client: Some(client),
+            outstanding: AtomicUsize::new(0),
+            transaction: false,
+            timing: requested.map(|requested| (requested.elapsed(), Instant::now())),
+        };
+
+        if let Some(timeout) = deadline::statement_timeout_ms() {
+            // set first, so a checkout dropped halfway doesn't go back to the pool
+            client.transaction = true;
+            let begin = format!("BEGIN; SET LOCAL statement_timeout = {timeout}");
+            tracked(&client, client.batch_execute(&begin))
+                .await
+                .map_err(PoolError::Backend)?;
+        }
+
+        Ok(client)
+    }
+
+    /// Commits the request's transaction, if it has one, and returns the
+    /// client to the pool without cancelling anything.
+    pub async fn finish(mut self) -> Result<(), PgError> {
+        if self.transaction {
+            tracked(&self, self.batch_execute("COMMIT")).await?;
+            self.transaction = false;
+        }
+
+        self.client.take();
+        Ok(())
+    }
+}
+
+impl Deref for DatabaseClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Self::Target {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl Connection for DatabaseClient {
+    fn client(&self) -> &Client {
+        self
+    }
+
+    fn outstanding(&self) -> Option<&AtomicUsize> {
+        Some(&self.outstanding)
+    }
+}
+
+impl Drop for DatabaseClient {
+    fn drop(&mut self) {
+        if let Some((wait, checked_out)) = self.timing {
+            pool_tuning::record(wait, checked_out.elapsed());
+        }
+
+        let Some(client) = self.client.take() else {
+            return;
+        };
+        let outstanding = *self.outstanding.get_mut() > 0;
+        if !outstanding && !self.transaction {
+            return;
+        }
+
+        // closed once the cancel is sent, or right away if there is nothing to
+        // cancel or no runtime to send it on
+        let connection = Object::take(client);
+        if !outstanding {
+            return;
+        }
+        if let Ok(runtime) = Handle::try_current() {
+            runtime.spawn(async move {
+                let _ = connection.cancel_token().cancel_query(NoTls).await;
+            });
+        }
+    }
+}
 
 #[async_trait]
-impl FromRequestParts<deadpool_postgres::Pool> for DatabaseClient {
+impl<S> FromRequestParts<S> for DatabaseClient
+where
+    deadpool_postgres::Pool: FromRef<S>,
+    S: Send + Sync,
+{
     type Rejection = (StatusCode, String);
 
     async fn from_request_parts(
         _parts: &mut Parts,
-        pool: &deadpool_postgres::Pool,
+        state: &S,
     ) -> Result<Self, Self::Rejection> {
-        let conn = pool.get().await.map_err(internal_error)?;
+        let pool = deadpool_postgres::Pool::from_ref(state);
+
+        Self::checkout(&pool).await.map_err(checkout_error)
+    }
+}
+
+/// Answers a failed checkout with 503 if the pool had no free connection in
+/// time, and with 500 otherwise.
+pub fn checkout_error(err: PoolError) -> (StatusCode, String) {
+    match err {
+        PoolError::Timeout(_) => {
+            #[cfg(feature = "runtime-metrics")]
+            crate::metrics::record_pool_shed();
 
-        Ok(Self(conn))
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "no database connection available".to_string(),
+            )
+        }
+        err => internal_error(err),
     }
 }
 
 pub async fn fetch_world_by_id(
-    client: &Client,
-    number: i32,
-    select: &Statement,
+    client: &impl Connection,
+    id: WorldId,
+    select: &Query,
 ) -> Result<World, PgError> {
-    let row: Row = client.query_one(select, &[&number]).await.unwrap();
+    let row: Row = query(client, select, &[&id])
+        .await?
+        .pop()
+        .ok_or(PgError::MissingWorld(id))?;
 
-    Ok(World::from_row(row).unwrap())
+    Ok(World::from_row(row)?)
+}
+
+pub async fn fetch_worlds(
+    client: &impl Connection,
+    ids: Vec<WorldId>,
+) -> Result<Vec<World>, PgError> {
+    let select = prepare_fetch_world_by_id_statement(client).await?;
+
+    ids.into_iter()
+        .map(|id| fetch_world_by_id(client, id, &select))
+        .collect::<FuturesUnordered<_>>()
+        .try_collect()
+        .await
+}
+
+/// Queries in flight at once while streaming worlds.
+const STREAM_WINDOW: usize = 128;
+
+/// Fetches `ids` with at most `STREAM_WINDOW` queries in flight, yielding
+/// the worlds that have arrived since the last poll.
+pub fn stream_worlds<'a>(
+    client: &'a impl Connection,
+    select: &'a Query,
+    ids: Vec<WorldId>,
+) -> impl Stream<Item = Vec<Result<World, PgError>>> + 'a {
+    stream::iter(ids)
+        .map(move |id| fetch_world_by_id(client, id, select))
+        .buffer_unordered(STREAM_WINDOW)
+        .ready_chunks(STREAM_WINDOW)
+}
+
+/// Gives every world in `worlds` a new random number and writes them back.
+///
+/// With relaxed durability the checked-out connection is switched to
+/// `synchronous_commit = off` first, pipelined ahead of the update statement.
+/// The updates are sent in id order: inside a request's transaction their row
+/// locks are held until `COMMIT`, and two requests taking them in different
+/// orders could deadlock.
+pub async fn update_worlds(
+    client: &impl Connection,
+    worlds: &mut [World],
+    rng: &mut SmallRng,
+) -> Result<(), PgError> {
+    for world in worlds.iter_mut() {
+        world.randomnumber = random_number(rng);
+    }
+
+    let update = if durability::relaxed() {
+        let relax = tracked(
+            client,
+            client.client().batch_execute(durability::RELAXED_COMMIT),
+        );
+        let (relaxed, update) =
+            join(relax, prepare_update_world_by_id_statement(client)).await;
+        relaxed?;
+        update?
+    } else {
+        prepare_update_world_by_id_statement(client).await?
+    };
+
+    let mut in_id_order: Vec<&World> = worlds.iter().collect();
+    in_id_order.sort_unstable_by_key(|world| world.id.get());
+
+    in_id_order
+        .into_iter()
+        .map(|world| update_world(client, &update, world.randomnumber, world.id))
+        .collect::<FuturesUnordered<_>>()
+        .try_collect::<Vec<u64>>()
+        .await?;
+
+    Ok(())
 }
 
 pub async fn update_world(
-    client: &Client,
-    update: &Statement,
-    random_id: i32,
-    w_id: i32,
+    client: &impl Connection,
+    update: &Query,
+    random_number: i32,
+    w_id: WorldId,
 ) -> Result<u64, PgError> {
-    let rows_modified: u64 = client.execute(update, &[&random_id, &w_id]).await.unwrap();
+    let rows_modified: u64 = execute(client, update, &[&random_number, &w_id]).await?;
 
     Ok(rows_modified)
 }
 
+pub async fn fetch_all_worlds(client: &impl Connection) -> Result<Vec<World>, PgError> {
+    let select = prepare(client, &FETCH_ALL_WORLDS, NO_PARAMS).await?;
+    let rows: Vec<Row> = query(client, &select, &[]).await?;
+
+    let worlds = rows
+        .into_iter()
+        .map(World::from_row)
+        .collect::<Result<_, _>>()?;
+
+    Ok(worlds)
+}
+
+/// Reloads `cache` from the `world` table, returning how many entries were
+/// dropped and loaded.
+pub async fn load_world_cache(
+    client: &impl Connection,
+    cache: &WorldCache<World>,
+) -> Result<(usize, usize), PgError> {
+    let worlds = fetch_all_worlds(client).await?;
+    let loaded = worlds.len();
+    let cleared = cache.replace(worlds.into_iter().map(|world| (world.id, world)));
+
+    Ok((cleared, loaded))
+}
+
 pub async fn fetch_all_fortunes(
-    client: Client,
-    select: &Statement,
+    client: &impl Connection,
+    select: &Query,
 ) -> Result<Vec<Fortune>, PgError> {
-    let rows: Vec<Row> = client.query(select, &[]).await.unwrap();
+    let rows: Vec<Row> = query(client, select, &[]).await?;
 
     let mut fortunes: Vec<Fortune> = Vec::with_capacity(rows.capacity());
 
     for row in rows {
-        fortunes.push(Fortune::from_row(row).unwrap());
+        fortunes.push(Fortune::from_row(row)?);
     }
 
     Ok(fortunes)
 }
 
-pub async fn prepare_fetch_all_fortunes_statement(client: &Client) -> Statement {
-    client
-        .prepare_cached("SELECT * FROM Fortune")
-        .await
-        .unwrap()
+/// All fortunes plus the one added at request time, sorted by message.
+pub async fn fetch_sorted_fortunes(
+    client: &impl Connection,
+) -> Result<Vec<Fortune>, PgError> {
+    let select = prepare_fetch_all_fortunes_statement(client).await?;
+    let mut fortunes = fetch_all_fortunes(client, &select).await?;
+
+    fortunes.push(Fortune {
+        id: 0,
+        message: "Additional fortune added at request time.".to_string(),
+    });
+
+    sort_by_message(&mut fortunes, |fortune| &fortune.message);
+    Ok(fortunes)
 }
 
-pub async fn prepare_fetch_world_by_id_statement(client: &Client) -> Statement {
-    client
-        .prepare_cached("SELECT id, randomnumber FROM World WHERE id = $1")
-        .await
-        .unwrap()
+pub async fn prepare_fetch_all_fortunes_statement(
+    client: &impl Connection,
+) -> Result<Query, PgError> {
+    prepare(client, &FETCH_ALL_FORTUNES, NO_PARAMS).await
 }
 
-pub async fn prepare_update_world_by_id_statement(client: &Client) -> Statement {
-    client
-        .prepare_cached("UPDATE World SET randomnumber = $1 WHERE id = $2")
-        .await
-        .unwrap()
+pub async fn prepare_fetch_world_by_id_statement(
+    client: &impl Connection,
+) -> Result<Query, PgError> {
+    prepare(client, &FETCH_WORLD_BY_ID, WORLD_ID_PARAMS).await
+}
+
+pub async fn prepare_update_world_by_id_statement(
+    client: &impl Connection,
+) -> Result<Query, PgError> {
+    prepare(client, &UPDATE_WORLD_BY_ID, UPDATE_PARAMS).await
 }
@@ -1,17 +1,21 @@
-use std::io;
+use std::{fmt, io};
 
 use axum::{
     async_trait,
     extract::FromRequestParts,
     http::{request::Parts, StatusCode},
 };
+use futures_util::future::try_join_all;
 use sqlx::{
     pool::PoolConnection,
-    postgres::{PgArguments, PgPoolOptions},
-    Arguments, PgPool, Postgres,
+    postgres::{PgArguments, PgConnectOptions, PgPoolOptions},
+    Arguments, Executor, PgPool, Postgres,
 };
 
-use crate::{utils::internal_error, Fortune, World};
+use crate::{
+    common::tables::Sql, deadline, models_common::WorldId, utils::internal_error,
+    Fortune, World,
+};
 
 #[derive(Debug)]
 pub enum PgError {
@@ -31,17 +35,76 @@ impl From<sqlx::Error> for PgError {
     }
 }
 
+impl fmt::Display for PgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PgError::Io(err) => err.fmt(f),
+            PgError::Pg(err) => err.fmt(f),
+        }
+    }
+}
+
+static FETCH_WORLD: Sql = Sql::new("SELECT id, randomnumber FROM {world} WHERE id = $1");
+static FETCH_FORTUNES: Sql = Sql::new("SELECT * FROM {fortune}");
+
 pub async fn create_pool(
     database_url: String,
     max_pool_size: u32,
     min_pool_size: u32,
-) -> PgPool {
-    PgPoolOptions::new()
+    statement_timeout_ms: Option<u64>,
+) -> Result<PgPool, PgError> {
+    let mut options: PgConnectOptions = database_url.parse()?;
+    if let Some(timeout) = statement_timeout_ms {
+        options = options.options([("statement_timeout", timeout.to_string())]);
+    }
+
+    let pool = PgPoolOptions::new()
         .max_connections(max_pool_size)
         .min_connections(min_pool_size)
-        .connect(&database_url)
-        .await
-        .unwrap()
+        .connect_with(options)
+        .await?;
+
+    Ok(pool)
+}
+
+/// Opens `size` connections and prepares every statement on each of them, so
+/// the first requests don't pay for connection setup and schema problems show
+/// up at startup.
+pub async fn warm_up_pool(pool: &PgPool, size: u32) -> Result<(), PgError> {
+    let mut conns = try_join_all((0..size).map(|_| pool.acquire())).await?;
+
+    for conn in &mut conns {
+        conn.prepare(FETCH_WORLD.get()).await?;
+        conn.prepare(FETCH_FORTUNES.get()).await?;
+    }
+
+    Ok(())
+}
+
+/// Lowers `statement_timeout` on `conn` to what is left of the request's
+/// deadline, returning whether it did, in which case `lift_deadline` must
+/// follow the request's statements. A connection dropped in between goes back
+/// to the pool with the lowered timeout, which the next request with a
+/// deadline overrides before its own statement.
+async fn bound_by_deadline(
+    conn: &mut PoolConnection<Postgres>,
+) -> Result<bool, PgError> {
+    let Some(timeout) = deadline::statement_timeout_ms() else {
+        return Ok(false);
+    };
+
+    sqlx::query(&format!("SET statement_timeout = {timeout}"))
+        .execute(&mut **conn)
+        .await?;
+    Ok(true)
+}
+
+/// Restores the `statement_timeout` the connection was opened with.
+async fn lift_deadline(conn: &mut PoolConnection<Postgres>) -> Result<(), PgError> {
+    sqlx::query("RESET statement_timeout")
+        .execute(&mut **conn)
+        .await?;
+    Ok(())
 }
 
 pub struct DatabaseConnection(pub PoolConnection<Postgres>);
@@ -62,25 +125,34 @@ impl FromRequestParts<PgPool> for DatabaseConnection {
 
 pub async fn fetch_world(
     mut conn: PoolConnection<Postgres>,
-    number: i32,
+    id: WorldId,
 ) -> Result<World, PgError> {
     let mut args = PgArguments::default();
-    args.add(number);
+    args.add(id);
 
-    let world: World =
-        sqlx::query_as_with("SELECT id, randomnumber FROM World WHERE id = $1", args)
-            .fetch_one(&mut *conn)
-            .await
-            .expect("error loading world");
+    let bounded = bound_by_deadline(&mut conn).await?;
+    crate::totals::db_read();
+    let world: World = sqlx::query_as_with(FETCH_WORLD.get(), args)
+        .fetch_one(&mut *conn)
+        .await
+        .expect("error loading world");
+    if bounded {
+        lift_deadline(&mut conn).await?;
+    }
     Ok(world)
 }
 
 pub async fn fetch_fortunes(
     mut conn: PoolConnection<Postgres>,
 ) -> Result<Vec<Fortune>, PgError> {
-    let fortunes: Vec<Fortune> = sqlx::query_as("SELECT * FROM Fortune")
+    let bounded = bound_by_deadline(&mut conn).await?;
+    crate::totals::db_read();
+    let fortunes: Vec<Fortune> = sqlx::query_as(FETCH_FORTUNES.get())
         .fetch_all(&mut *conn)
         .await
         .expect("error loading Fortunes");
+    if bounded {
+        lift_deadline(&mut conn).await?;
+    }
     Ok(fortunes)
 }
@@ -0,0 +1,129 @@
+//! Per-request deadline from `AXUM_TECHEMPOWER_REQUEST_TIMEOUT_MS`.
+//!
+//! Requests still running when it passes are dropped and answered with 503.
+//! Within a request, `remaining` tells database calls how much of the budget
+//! is left, so they can be cut short server-side instead of running on after
+//! nobody waits for them any more:
+//!
+//! - the MongoDB backends pass it as `max_time_ms` to each `find`
+//! - `axum-pg-pool` runs the request in a transaction that starts with
+//!   `SET LOCAL statement_timeout`, see `DatabaseClient`
+//! - `axum-sqlx` sets `statement_timeout` on the checked-out connection and
+//!   resets it once the request's statement is done
+//!
+//! `axum-pg` shares one connection, and so one session, between all requests
+//! of a worker, so it is only bounded by `AXUM_TECHEMPOWER_STATEMENT_TIMEOUT_MS`.
+//! Unset, requests have no deadline.
+
+use std::{sync::OnceLock, time::Duration};
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use tokio::time::{timeout_at, Instant};
+
+use crate::{clock, utils::get_optional_environment_variable};
+
+tokio::task_local! {
+    static DEADLINE: Instant;
+}
+
+/// Enforces the request timeout on `router`, if one is set.
+pub fn layer(router: Router) -> Router {
+    let Some(timeout_ms) =
+        get_optional_environment_variable::<u64>("AXUM_TECHEMPOWER_REQUEST_TIMEOUT_MS")
+    else {
+        return router;
+    };
+
+    let timeout = Duration::from_millis(timeout_ms);
+    router.layer(middleware::from_fn(
+        move |request: Request<Body>, next: Next<Body>| enforce(timeout, request, next),
+    ))
+}
+
+async fn enforce<B>(timeout: Duration, request: Request<B>, next: Next<B>) -> Response {
+    let deadline = Instant::from_std(clock::now()) + timeout;
+
+    match DEADLINE
+        .scope(deadline, timeout_at(deadline, next.run(request)))
+        .await
+    {
+        Ok(response) => response,
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "request timed out").into_response(),
+    }
+}
+
+/// Time left until the current request's deadline, `None` without one or
+/// outside of a request.
+#[allow(dead_code)]
+pub fn remaining() -> Option<Duration> {
+    DEADLINE
+        .try_with(|deadline| deadline.into_std().saturating_duration_since(clock::now()))
+        .ok()
+}
+
+/// `statement_timeout` for the current request's statements, in
+/// milliseconds: what is left of its deadline, but never more than
+/// `AXUM_TECHEMPOWER_STATEMENT_TIMEOUT_MS`. `None` without a deadline.
+#[allow(dead_code)]
+pub fn statement_timeout_ms() -> Option<u64> {
+    static CONFIGURED: OnceLock<Option<u64>> = OnceLock::new();
+
+    let configured = *CONFIGURED.get_or_init(|| {
+        get_optional_environment_variable("AXUM_TECHEMPOWER_STATEMENT_TIMEOUT_MS")
+    });
+    remaining().map(|remaining| statement_timeout(remaining, configured))
+}
+
+/// Rounds `remaining` up to whole milliseconds, and to at least one, since
+/// Postgres takes 0 to mean no timeout at all.
+fn statement_timeout(remaining: Duration, configured: Option<u64>) -> u64 {
+    let remaining = u64::try_from(remaining.as_micros().div_ceil(1000))
+        .unwrap_or(u64::MAX)
+        .max(1);
+
+    match configured {
+        Some(configured) if configured > 0 => remaining.min(configured),
+        _ => remaining,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statement_timeout_rounds_up_and_keeps_the_configured_bound() {
+        let ms = Duration::from_millis;
+
+        assert_eq!(statement_timeout(ms(250), None), 250);
+        assert_eq!(statement_timeout(Duration::from_micros(1), None), 1);
+        assert_eq!(statement_timeout(Duration::from_micros(2500), None), 3);
+        assert_eq!(statement_timeout(Duration::ZERO, None), 1);
+        assert_eq!(statement_timeout(ms(250), Some(100)), 100);
+        assert_eq!(statement_timeout(ms(50), Some(100)), 50);
+        assert_eq!(statement_timeout(ms(50), Some(0)), 50);
+    }
+
+    #[test]
+    fn statement_timeout_follows_the_request_deadline() {
+        let (_held, clock) = clock::mock::hold();
+        assert_eq!(statement_timeout_ms(), None);
+
+        let deadline = Instant::from_std(clock::now()) + Duration::from_millis(250);
+        let (before, after) = DEADLINE.sync_scope(deadline, || {
+            let before = statement_timeout_ms();
+            clock.advance(Duration::from_millis(100));
+            (before, statement_timeout_ms())
+        });
+        assert_eq!((before, after), (Some(250), Some(150)));
+
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(DEADLINE.sync_scope(deadline, statement_timeout_ms), Some(1));
+    }
+}
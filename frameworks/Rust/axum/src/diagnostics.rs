@@ -0,0 +1,84 @@
+//! Startup snapshot of the settings actually in effect.
+//!
+//! Once the listener is up, before any worker starts, the process prints the enabled features,
+//! the worker count, the socket options read back from the listening socket,
+//! and every `AXUM_TECHEMPOWER_*` variable read so far with the value it was
+//! read with. Variables that are set but haven't been read are called out,
+//! which catches typos and settings that don't apply to the binary; some are
+//! only read on first use, so a setting for an endpoint that hasn't been
+//! requested yet can show up there too.
+
+use std::{collections::BTreeMap, env, io, sync::Mutex};
+
+use socket2::SockRef;
+use tokio::net::TcpListener;
+
+const PREFIX: &str = "AXUM_TECHEMPOWER_";
+
+/// Variables read so far, with their value or `None` if unset.
+static READ: Mutex<BTreeMap<String, Option<String>>> = Mutex::new(BTreeMap::new());
+
+/// Notes that `key` was read, and what it was set to.
+pub fn record(key: &str, value: Option<&str>) {
+    if key.starts_with(PREFIX) {
+        READ.lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_insert_with(|| value.map(str::to_string));
+    }
+}
+
+/// Prints the snapshot.
+pub fn report(listener: &TcpListener) {
+    let features = env!("AXUM_TECHEMPOWER_BUILD_FEATURES");
+    println!(
+        "settings: features=[{features}] cpus={} tokio_worker_threads={}",
+        num_cpus::get(),
+        env::var("TOKIO_WORKER_THREADS").unwrap_or_else(|_| "default".into())
+    );
+
+    match socket_options(listener) {
+        Ok(options) => println!("settings: listener {options}"),
+        Err(err) => eprintln!("settings: could not read listener options: {err}"),
+    }
+
+    let read = READ.lock().unwrap();
+    for (key, value) in read.iter() {
+        match value {
+            Some(_) if secret(key) => println!("settings: {key}=<redacted>"),
+            Some(value) => println!("settings: {key}={value}"),
+            None => println!("settings: {key} unset, default in effect"),
+        }
+    }
+
+    for (key, _) in env::vars().filter(|(key, _)| key.starts_with(PREFIX)) {
+        if !read.contains_key(&key) {
+            eprintln!(
+                "settings: WARNING: {key} is set but was not read (yet); check the \
+                 name, and whether it applies to this binary"
+            );
+        }
+    }
+}
+
+/// Connection URLs may carry passwords, tokens are credentials.
+fn secret(key: &str) -> bool {
+    key.ends_with("_URL") || key.contains("TOKEN")
+}
+
+fn socket_options(listener: &TcpListener) -> io::Result<String> {
+    let socket = SockRef::from(listener);
+    let addr = listener.local_addr()?;
+
+    let mut options = format!(
+        "addr={addr} reuseport={} reuseaddr={} rcvbuf={} sndbuf={}",
+        socket.reuse_port()?,
+        socket.reuse_address()?,
+        socket.recv_buffer_size()?,
+        socket.send_buffer_size()?,
+    );
+    if addr.is_ipv6() {
+        options.push_str(&format!(" v6only={}", socket.only_v6()?));
+    }
+    Ok(options)
+}
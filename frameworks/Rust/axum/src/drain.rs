@@ -0,0 +1,163 @@
+//! In-place upgrades: a new process takes over the port and the old one
+//! drains and exits, so a long-running soak can switch binaries, features or
+//! settings without a restart.
+//!
+//! Listeners are bound with `SO_REUSEPORT`, so the new process can be started
+//! on the same ports while the old one still runs; the kernel then spreads new
+//! connections over both. Once the new process answers, send SIGUSR2 to the
+//! old one:
+//!
+//! ```sh
+//! ./axum-pg-pool &                # the new process
+//! kill -USR2 "$old_pid"
+//! ```
+//!
+//! For the first `AXUM_TECHEMPOWER_DRAIN_GRACE_MS` (default 1000) the old
+//! process keeps serving, but every response it sends carries
+//! `Connection: close`, so clients close their connections after it and
+//! reconnect, most likely to the new process. Then it closes its listeners,
+//! and with them the connections that are idle; connections still on a
+//! request are closed once it has been answered. When the last one is gone,
+//! or `AXUM_TECHEMPOWER_DRAIN_TIMEOUT_MS` (default 30000) after the signal,
+//! the process writes its totals report, if enabled, and exits.
+//!
+//! A client whose request crosses the close of an idle connection sees it
+//! fail, as with any keep-alive connection a server closes; under load the
+//! grace period leaves hardly any connection idle. Connections still in the
+//! old listeners' accept queues when they close are reset by the kernel.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use axum::{
+    http::{header, HeaderValue, Request},
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+use crate::utils::get_environment_variable_or;
+
+/// Servers that haven't finished draining yet.
+static SERVING: AtomicUsize = AtomicUsize::new(0);
+
+fn draining() -> &'static watch::Sender<bool> {
+    static DRAINING: OnceLock<watch::Sender<bool>> = OnceLock::new();
+
+    DRAINING.get_or_init(|| watch::channel(false).0)
+}
+
+/// Whether the process is draining, so responses should close their
+/// connections.
+pub fn started() -> bool {
+    *draining().borrow()
+}
+
+fn accepting() -> &'static watch::Sender<bool> {
+    static ACCEPTING: OnceLock<watch::Sender<bool>> = OnceLock::new();
+
+    ACCEPTING.get_or_init(|| watch::channel(true).0)
+}
+
+/// Resolves once the grace period of a drain is over and servers should stop
+/// accepting. Servers pass it to `with_graceful_shutdown`.
+pub async fn requested() {
+    let _ = accepting()
+        .subscribe()
+        .wait_for(|accepting| !accepting)
+        .await;
+}
+
+/// Asks clients to close their connections while the process drains.
+pub fn layer(router: Router) -> Router {
+    router.layer(middleware::from_fn(close_when_draining))
+}
+
+async fn close_when_draining<B>(request: Request<B>, next: Next<B>) -> Response {
+    let mut response = next.run(request).await;
+    if started() {
+        response
+            .headers_mut()
+            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+    }
+    response
+}
+
+/// Something the process waits for before it exits: a server, or a
+/// connection the server doesn't track itself. The last one dropped while
+/// draining exits the process.
+pub struct Serving(());
+
+impl Serving {
+    pub fn start() -> Self {
+        SERVING.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+
+    /// Drops the server's registration once it has returned from draining,
+    /// and then never returns itself, so a worker thread or `main` doesn't
+    /// end the process while other servers still drain.
+    pub async fn finished(self) {
+        drop(self);
+        std::future::pending::<()>().await;
+    }
+}
+
+impl Drop for Serving {
+    fn drop(&mut self) {
+        if SERVING.fetch_sub(1, Ordering::Relaxed) == 1 && started() {
+            exit();
+        }
+    }
+}
+
+fn exit() -> ! {
+    crate::totals::write_report_if_enabled();
+    println!("drain: done, exiting");
+    std::process::exit(0)
+}
+
+/// Starts draining on SIGUSR2. Only the first call installs the handler.
+pub fn spawn_on_sigusr2() {
+    static SPAWNED: AtomicBool = AtomicBool::new(false);
+
+    if SPAWNED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let mut upgrades = match signal(SignalKind::user_defined2()) {
+        Ok(upgrades) => upgrades,
+        Err(err) => {
+            eprintln!("drain: could not install SIGUSR2 handler: {err}");
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        upgrades.recv().await;
+
+        let grace_ms: u64 =
+            get_environment_variable_or("AXUM_TECHEMPOWER_DRAIN_GRACE_MS", 1000);
+        let timeout_ms: u64 =
+            get_environment_variable_or("AXUM_TECHEMPOWER_DRAIN_TIMEOUT_MS", 30000);
+        println!("drain: closing connections, exiting at the latest in {timeout_ms}ms");
+        draining().send_replace(true);
+
+        let grace = Duration::from_millis(grace_ms.min(timeout_ms));
+        tokio::time::sleep(grace).await;
+        println!("drain: stopped accepting");
+        accepting().send_replace(false);
+
+        tokio::time::sleep(Duration::from_millis(timeout_ms) - grace).await;
+        exit();
+    });
+}
@@ -0,0 +1,63 @@
+//! Server-Sent Events stream of random cached worlds.
+//!
+//! `/events?interval_ms=N` sends one random world as JSON every `N`
+//! milliseconds (default `AXUM_TECHEMPOWER_SSE_INTERVAL_MS`, or 1000). Events
+//! are only produced when the connection can take them: a subscriber that
+//! falls behind skips ticks instead of having events queue up in memory.
+
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Query, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::{stream, Stream};
+use serde::{Deserialize, Serialize};
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::{
+    cache::WorldCache,
+    utils::{get_environment_variable_or, random_id, request_rng},
+};
+
+const MIN_INTERVAL_MS: u64 = 10;
+const MAX_INTERVAL_MS: u64 = 60_000;
+
+#[derive(Debug, Deserialize)]
+pub struct EventsParams {
+    interval_ms: Option<u64>,
+}
+
+pub async fn events<W>(
+    State(cache): State<Arc<WorldCache<W>>>,
+    Query(params): Query<EventsParams>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>>
+where
+    W: Clone + Serialize + Send + Sync + 'static,
+{
+    let interval_ms = params
+        .interval_ms
+        .unwrap_or_else(|| {
+            get_environment_variable_or("AXUM_TECHEMPOWER_SSE_INTERVAL_MS", 1000)
+        })
+        .clamp(MIN_INTERVAL_MS, MAX_INTERVAL_MS);
+
+    let mut ticks = interval(Duration::from_millis(interval_ms));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    let events = stream::unfold(
+        (ticks, request_rng(), cache),
+        |(mut ticks, mut rng, cache)| async move {
+            ticks.tick().await;
+
+            let event = match cache.get(random_id(&mut rng)) {
+                Some(world) => Event::default().json_data(world).unwrap(),
+                None => Event::default().comment("world not cached"),
+            };
+
+            Some((Ok(event), (ticks, rng, cache)))
+        },
+    );
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
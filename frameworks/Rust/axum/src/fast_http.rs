@@ -0,0 +1,358 @@
+//! Hand-rolled HTTP/1.1 handling for the plaintext test.
+//!
+//! Requests for `/plaintext` are parsed with `httparse` and answered with a
+//! canned response, skipping hyper's connection state machine entirely. As soon
+//! as a connection sends anything else, the bytes buffered so far are handed to
+//! hyper and the connection is served by the router from then on.
+//!
+//! The fast path only understands what the plaintext test sends: `GET` requests
+//! without a body or `Connection` header, with at most `MAX_HEADERS` headers.
+//! Everything else falls back to hyper, but a client relying on finer HTTP
+//! semantics (e.g. `Expect`, upgrades, HTTP/1.0 keep-alive) may still observe
+//! differences, which is why hyper stays the default and this path is only
+//! available behind the `unsafe-fast-http` feature.
+//!
+//! On a drain, fast-path connections are closed after their next response,
+//! without a `Connection` header; those handed to hyper stay open until their
+//! client closes them or the drain times out.
+
+use std::{
+    cell::RefCell,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::UNIX_EPOCH,
+};
+
+use axum::{body::Bytes, Router};
+use bytes::{Buf, BufMut, BytesMut};
+use hyper::server::conn::Http;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::{
+    clock,
+    common::{endpoints::PLAINTEXT, headers::TEXT_PLAIN},
+    drain,
+    idle_timeout::{idle_timeout, IdleStream},
+    max_requests::{max_requests, CloseAfter},
+    server::{header_read_timeout, max_header_bytes, reuse_listener, unspecified_addr},
+};
+
+const MAX_HEADERS: usize = 32;
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// Connections buffering more than this without a complete request are handed
+/// to hyper, which enforces its own limits.
+const MAX_BUFFERED: usize = 64 * 1024;
+
+const PLAINTEXT_BODY: &[u8] = b"Hello, World!";
+
+fn plaintext_head() -> Vec<u8> {
+    format!(
+        "HTTP/1.1 200 OK\r\nServer: Axum\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+        TEXT_PLAIN.to_str().unwrap(),
+        PLAINTEXT_BODY.len()
+    )
+    .into_bytes()
+}
+
+pub async fn serve(port: u16, app: Router) -> io::Result<()> {
+    let addr = unspecified_addr(port);
+    let listener = reuse_listener(addr)?;
+
+    println!("Started axum server at {port} (unsafe-fast-http)");
+    crate::server::prime_settings();
+    crate::diagnostics::report(&listener);
+
+    #[cfg(feature = "runtime-metrics")]
+    crate::metrics::spawn_reporter();
+
+    let serving = drain::Serving::start();
+    let draining = drain::requested();
+    tokio::pin!(draining);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut draining => break,
+        };
+        let (stream, _) = match accepted {
+            Ok(conn) => conn,
+            Err(err) => {
+                eprintln!("accept error: {err}");
+                continue;
+            }
+        };
+
+        let app = app.clone();
+        let connection = drain::Serving::start();
+        tokio::spawn(async move {
+            #[cfg(feature = "runtime-metrics")]
+            let _guard = crate::metrics::ConnectionGuard::new();
+
+            let _ = stream.set_nodelay(true);
+            let _ = handle(IdleStream::new(stream, idle_timeout()), app).await;
+            drop(connection);
+        });
+    }
+
+    drop(listener);
+    serving.finished().await;
+    Ok(())
+}
+
+enum Parsed {
+    Plaintext(usize),
+    Partial,
+    Other,
+}
+
+fn parse(buf: &[u8]) -> Parsed {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+
+    match req.parse(buf) {
+        Ok(httparse::Status::Complete(len)) => {
+            let fast = req.method == Some("GET")
+                && req.path == Some(PLAINTEXT.path)
+                && PLAINTEXT.enabled()
+                && req.version == Some(1)
+                && !req.headers.iter().any(|h| {
+                    h.name.eq_ignore_ascii_case("content-length")
+                        || h.name.eq_ignore_ascii_case("transfer-encoding")
+                        || h.name.eq_ignore_ascii_case("connection")
+                });
+
+            if fast {
+                Parsed::Plaintext(len)
+            } else {
+                Parsed::Other
+            }
+        }
+        Ok(httparse::Status::Partial) => Parsed::Partial,
+        Err(_) => Parsed::Other,
+    }
+}
+
+async fn handle(mut stream: IdleStream<TcpStream>, app: Router) -> io::Result<()> {
+    let mut buf = BytesMut::with_capacity(BUFFER_SIZE);
+    let mut out = BytesMut::with_capacity(BUFFER_SIZE);
+    let mut served = 0;
+
+    loop {
+        // between requests, a drain closes the connection
+        let read = if buf.is_empty() {
+            tokio::select! {
+                read = stream.read_buf(&mut buf) => read?,
+                _ = drain::requested() => return Ok(()),
+            }
+        } else {
+            stream.read_buf(&mut buf).await?
+        };
+        if read == 0 {
+            return Ok(());
+        }
+
+        loop {
+            match parse(&buf) {
+                Parsed::Plaintext(len) => {
+                    buf.advance(len);
+                    served += 1;
+                    if max_requests().is_some_and(|max| served >= max) {
+                        write_plaintext_closing(&mut out);
+                        return stream.write_all(&out).await;
+                    }
+                    write_plaintext(&mut out);
+                }
+                Parsed::Partial if buf.len() < MAX_BUFFERED => break,
+                Parsed::Partial | Parsed::Other => {
+                    stream.write_all(&out).await?;
+                    return hand_off(stream, buf.freeze(), app, served).await;
+                }
+            }
+        }
+
+        if !out.is_empty() {
+            stream.write_all(&out).await?;
+            out.clear();
+        }
+        if buf.is_empty() && drain::started() {
+            return Ok(());
+        }
+    }
+}
+
+async fn hand_off(
+    stream: IdleStream<TcpStream>,
+    prefix: Bytes,
+    app: Router,
+    served: u64,
+) -> io::Result<()> {
+    Http::new()
+        .http1_only(true)
+        .pipeline_flush(true)
+        .http1_header_read_timeout(header_read_timeout())
+        .max_buf_size(max_header_bytes())
+        .serve_connection(Rewind { prefix, stream }, CloseAfter::new(app, served))
+        .with_upgrades()
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+}
+
+fn write_plaintext(out: &mut BytesMut) {
+    PLAINTEXT_HEADERS.with(|headers| out.put_slice(headers.borrow_mut().get()));
+    out.put_slice(PLAINTEXT_BODY);
+}
+
+/// Like `write_plaintext`, for the last response of the connection.
+fn write_plaintext_closing(out: &mut BytesMut) {
+    PLAINTEXT_HEADERS.with(|headers| {
+        let mut headers = headers.borrow_mut();
+        let head = headers.get();
+        // the head ends with the blank line
+        out.put_slice(&head[..head.len() - 2]);
+    });
+    out.put_slice(b"Connection: close\r\n\r\n");
+    out.put_slice(PLAINTEXT_BODY);
+}
+
+thread_local! {
+    static PLAINTEXT_HEADERS: RefCell<StaticHeaders> =
+        RefCell::new(StaticHeaders::new(&plaintext_head()));
+}
+
+/// Length of an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+const DATE_LEN: usize = 29;
+
+/// Complete head of a fixed response: the status line and headers given to
+/// `new`, then `Date` and the blank line, kept as one contiguous slab so every
+/// response starts with a single copy. Only the `Date` value changes, and it
+/// is re-rendered in place at most once per second.
+///
+/// Each worker thread keeps its own, so reading it never synchronizes.
+struct StaticHeaders {
+    secs: u64,
+    head: Vec<u8>,
+    date_at: usize,
+}
+
+impl StaticHeaders {
+    fn new(fixed: &[u8]) -> Self {
+        let mut head = Vec::with_capacity(fixed.len() + DATE_LEN + 10);
+        head.extend_from_slice(fixed);
+        head.extend_from_slice(b"Date: ");
+        let date_at = head.len();
+        head.extend_from_slice(&[0; DATE_LEN]);
+        head.extend_from_slice(b"\r\n\r\n");
+
+        Self {
+            secs: u64::MAX,
+            head,
+            date_at,
+        }
+    }
+
+    fn get(&mut self) -> &[u8] {
+        let now = clock::system_now();
+        let secs = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+
+        if secs != self.secs {
+            self.secs = secs;
+            self.head[self.date_at..self.date_at + DATE_LEN]
+                .copy_from_slice(httpdate::fmt_http_date(now).as_bytes());
+        }
+        &self.head
+    }
+}
+
+/// Replays bytes already read by the fast path before reading from the socket.
+struct Rewind {
+    prefix: Bytes,
+    stream: IdleStream<TcpStream>,
+}
+
+impl AsyncRead for Rewind {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let len = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..len]);
+            self.prefix.advance(len);
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Rewind {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testing;
+
+    fn content_type(write: fn(&mut BytesMut)) -> Option<String> {
+        let mut out = BytesMut::new();
+        write(&mut out);
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut response = httparse::Response::new(&mut headers);
+        assert!(response.parse(&out).unwrap().is_complete());
+        assert_eq!(response.code, Some(200));
+
+        let header = response
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("content-type"))?;
+        Some(String::from_utf8(header.value.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn plaintext_has_its_tfb_content_type() {
+        let expected = testing::tfb_content_type(PLAINTEXT.name).map(str::to_string);
+
+        assert_eq!(content_type(write_plaintext), expected);
+        assert_eq!(content_type(write_plaintext_closing), expected);
+    }
+}
@@ -0,0 +1,145 @@
+//! gRPC flavour of the database tests, enabled with the `grpc` feature.
+//!
+//! Serves the `Benchmark` service from `proto/benchmark.proto` on
+//! `AXUM_TECHEMPOWER_GRPC_PORT` (default 50051), using the same pool and
+//! queries as the HTTP handlers.
+
+use deadpool_postgres::PoolError;
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::{
+    common::params::clamp,
+    database_pg_pool::{
+        fetch_sorted_fortunes, fetch_world_by_id, fetch_worlds,
+        prepare_fetch_world_by_id_statement, update_worlds, DatabaseClient,
+    },
+    server::unspecified_addr,
+    utils::{get_environment_variable_or, random_id, random_ids, request_rng},
+};
+
+mod proto {
+    tonic::include_proto!("benchmark");
+}
+
+use proto::benchmark_server::{Benchmark, BenchmarkServer};
+
+pub fn spawn(pool: deadpool_postgres::Pool) {
+    let port: u16 = get_environment_variable_or("AXUM_TECHEMPOWER_GRPC_PORT", 50051);
+    let addr = unspecified_addr(port);
+
+    println!("Started gRPC server at {port}");
+
+    tokio::spawn(async move {
+        Server::builder()
+            .tcp_nodelay(true)
+            .add_service(BenchmarkServer::new(BenchmarkService { pool }))
+            .serve(addr)
+            .await
+            .unwrap();
+    });
+}
+
+struct BenchmarkService {
+    pool: deadpool_postgres::Pool,
+}
+
+impl BenchmarkService {
+    async fn client(&self) -> Result<DatabaseClient, Status> {
+        DatabaseClient::checkout(&self.pool)
+            .await
+            .map_err(|err| match err {
+                PoolError::Timeout(_) => Status::unavailable(err.to_string()),
+                err => internal(err),
+            })
+    }
+}
+
+fn internal(err: impl ToString) -> Status {
+    Status::internal(err.to_string())
+}
+
+impl From<crate::World> for proto::World {
+    fn from(world: crate::World) -> Self {
+        Self {
+            id: world.id.get(),
+            random_number: world.randomnumber,
+        }
+    }
+}
+
+fn worlds(worlds: Vec<crate::World>) -> proto::Worlds {
+    proto::Worlds {
+        worlds: worlds.into_iter().map(Into::into).collect(),
+    }
+}
+
+#[tonic::async_trait]
+impl Benchmark for BenchmarkService {
+    async fn get_world(
+        &self,
+        _: Request<proto::GetWorldRequest>,
+    ) -> Result<Response<proto::World>, Status> {
+        let client = self.client().await?;
+        let id = random_id(&mut request_rng());
+
+        let select = prepare_fetch_world_by_id_statement(&client)
+            .await
+            .map_err(internal)?;
+        let world = fetch_world_by_id(&client, id, &select)
+            .await
+            .map_err(internal)?;
+        client.finish().await.map_err(internal)?;
+
+        Ok(Response::new(world.into()))
+    }
+
+    async fn get_worlds(
+        &self,
+        request: Request<proto::GetWorldsRequest>,
+    ) -> Result<Response<proto::Worlds>, Status> {
+        let client = self.client().await?;
+        let ids = random_ids(&mut request_rng(), clamp(request.get_ref().count));
+
+        let results = fetch_worlds(&client, ids).await.map_err(internal)?;
+        client.finish().await.map_err(internal)?;
+
+        Ok(Response::new(worlds(results)))
+    }
+
+    async fn get_fortunes(
+        &self,
+        _: Request<proto::GetFortunesRequest>,
+    ) -> Result<Response<proto::Fortunes>, Status> {
+        let client = self.client().await?;
+
+        let fortunes = fetch_sorted_fortunes(&client).await.map_err(internal)?;
+        client.finish().await.map_err(internal)?;
+
+        Ok(Response::new(proto::Fortunes {
+            fortunes: fortunes
+                .into_iter()
+                .map(|fortune| proto::Fortune {
+                    id: fortune.id,
+                    message: fortune.message,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn update_worlds(
+        &self,
+        request: Request<proto::UpdateWorldsRequest>,
+    ) -> Result<Response<proto::Worlds>, Status> {
+        let client = self.client().await?;
+        let mut rng = request_rng();
+        let ids = random_ids(&mut rng, clamp(request.get_ref().count));
+
+        let mut results = fetch_worlds(&client, ids).await.map_err(internal)?;
+        update_worlds(&client, &mut results, &mut rng)
+            .await
+            .map_err(internal)?;
+        client.finish().await.map_err(internal)?;
+
+        Ok(Response::new(worlds(results)))
+    }
+}
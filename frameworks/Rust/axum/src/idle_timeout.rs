@@ -0,0 +1,182 @@
+//! Closes connections that stop making progress.
+//!
+//! hyper's header read timeout only starts once the first byte of a request
+//! has arrived, so a client that opens connections and never sends anything,
+//! or stops reading its responses, would hold them forever. Every accepted
+//! connection is wrapped in an `IdleStream`, which fails pending reads and
+//! writes with `TimedOut` once the socket has seen no traffic in either
+//! direction for `AXUM_TECHEMPOWER_IDLE_TIMEOUT_SECS` (default 60, `0`
+//! disables it, reloaded on SIGHUP).
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hyper::server::accept::Accept;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep_until, Instant, Sleep},
+};
+
+use crate::{reload::Reloaded, utils::get_environment_variable_or};
+
+const IDLE_TIMEOUT_SECS: &str = "AXUM_TECHEMPOWER_IDLE_TIMEOUT_SECS";
+
+fn idle_timeout_secs() -> &'static AtomicU64 {
+    static SECS: OnceLock<AtomicU64> = OnceLock::new();
+
+    SECS.get_or_init(|| {
+        AtomicU64::new(get_environment_variable_or(IDLE_TIMEOUT_SECS, 60))
+    })
+}
+
+pub fn idle_timeout() -> Option<Duration> {
+    let secs = idle_timeout_secs().load(Ordering::Relaxed);
+
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Picks up a changed `AXUM_TECHEMPOWER_IDLE_TIMEOUT_SECS`; connections that
+/// are already open keep the timeout they were accepted with.
+pub fn reload(settings: &Reloaded) {
+    if let Some(secs) = settings.get_or(IDLE_TIMEOUT_SECS, 60) {
+        idle_timeout_secs().store(secs, Ordering::Relaxed);
+    }
+}
+
+/// Connection that times out after `timeout` without reads or writes.
+///
+/// Activity only records a timestamp; the timer is re-armed lazily when it
+/// fires, so busy connections don't touch the timer wheel on every read.
+pub struct IdleStream<S> {
+    stream: S,
+    timer: Option<IdleTimer>,
+}
+
+struct IdleTimer {
+    timeout: Duration,
+    last_active: Instant,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl<S> IdleStream<S> {
+    pub fn new(stream: S, timeout: Option<Duration>) -> Self {
+        let timer = timeout.map(|timeout| {
+            let now = Instant::now();
+            IdleTimer {
+                timeout,
+                last_active: now,
+                sleep: Box::pin(sleep_until(now + timeout)),
+            }
+        });
+
+        Self { stream, timer }
+    }
+
+    fn poll_io<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        let Some(timer) = &mut self.timer else {
+            return poll;
+        };
+
+        if poll.is_ready() {
+            timer.last_active = Instant::now();
+            return poll;
+        }
+
+        loop {
+            if timer.sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+
+            let deadline = timer.last_active + timer.timeout;
+            if deadline <= Instant::now() {
+                return Poll::Ready(Err(io::ErrorKind::TimedOut.into()));
+            }
+            timer.sleep.as_mut().reset(deadline);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.stream).poll_read(cx, buf);
+        self.poll_io(cx, poll)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_write(cx, buf);
+        self.poll_io(cx, poll)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_write_vectored(cx, bufs);
+        self.poll_io(cx, poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Wraps every connection accepted from `I` in an `IdleStream`.
+pub struct IdleIncoming<I>(I);
+
+impl<I> IdleIncoming<I> {
+    pub fn new(incoming: I) -> Self {
+        Self(incoming)
+    }
+}
+
+impl<I: Accept + Unpin> Accept for IdleIncoming<I> {
+    type Conn = IdleStream<I::Conn>;
+    type Error = I::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        Pin::new(&mut self.0)
+            .poll_accept(cx)
+            .map_ok(|stream| IdleStream::new(stream, idle_timeout()))
+    }
+}
@@ -0,0 +1,144 @@
+//! Per-endpoint service time histograms, enabled with the `latency-histograms`
+//! feature.
+//!
+//! Every request is timed from the moment the router sees it until its
+//! response head is ready, which leaves out the network and hyper's own
+//! queueing. Comparing these quantiles with the ones wrk reports tells time
+//! spent in the handlers apart from time spent waiting to be served.
+//!
+//! `GET /admin/latency` returns the quantiles of every endpoint seen so far,
+//! and `?reset=true` clears the histograms afterwards, e.g. between runs.
+//!
+//! Each worker thread records into its own histograms, so recording only ever
+//! takes an uncontended lock; the admin route merges them.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    extract::Query,
+    http::Request,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use hdrhistogram::Histogram;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    common::{admin::Admin, endpoints::ENDPOINTS},
+    utils::JsonFast,
+};
+
+/// Slowest service time tracked, longer ones are recorded as this.
+const MAX_MICROS: u64 = 60_000_000;
+
+/// One histogram per entry of `ENDPOINTS`, plus one for every other path,
+/// created on the first request for that endpoint.
+type Histograms = Vec<Option<Histogram<u64>>>;
+
+static RECORDERS: Mutex<Vec<Arc<Mutex<Histograms>>>> = Mutex::new(Vec::new());
+
+thread_local! {
+    static RECORDER: Arc<Mutex<Histograms>> = {
+        let recorder = Arc::new(Mutex::new(vec![None; ENDPOINTS.len() + 1]));
+        RECORDERS.lock().unwrap().push(recorder.clone());
+        recorder
+    };
+}
+
+/// Records the service time of every request to `router`.
+pub fn layer(router: Router) -> Router {
+    router.layer(middleware::from_fn(record))
+}
+
+pub fn routes<S>() -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new().route("/admin/latency", get(dump))
+}
+
+async fn record<B>(request: Request<B>, next: Next<B>) -> Response {
+    let endpoint = ENDPOINTS
+        .iter()
+        .position(|endpoint| endpoint.path == request.uri().path() && endpoint.enabled())
+        .unwrap_or(ENDPOINTS.len());
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let micros = (start.elapsed().as_micros() as u64).clamp(1, MAX_MICROS);
+
+    RECORDER.with(|recorder| {
+        let mut histograms = recorder.lock().unwrap();
+        let histogram = histograms[endpoint].get_or_insert_with(new_histogram);
+        let _ = histogram.record(micros);
+    });
+
+    response
+}
+
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, MAX_MICROS, 2).unwrap()
+}
+
+#[derive(Debug, Deserialize)]
+struct DumpParams {
+    #[serde(default)]
+    reset: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Quantiles {
+    count: u64,
+    p50_us: u64,
+    p90_us: u64,
+    p99_us: u64,
+    p999_us: u64,
+    max_us: u64,
+}
+
+async fn dump(_: Admin, Query(params): Query<DumpParams>) -> impl IntoResponse {
+    let mut merged: Vec<Option<Histogram<u64>>> = vec![None; ENDPOINTS.len() + 1];
+
+    for recorder in RECORDERS.lock().unwrap().iter() {
+        let mut histograms = recorder.lock().unwrap();
+
+        for (total, histogram) in merged.iter_mut().zip(histograms.iter_mut()) {
+            let Some(histogram) = histogram else {
+                continue;
+            };
+
+            let _ = total.get_or_insert_with(new_histogram).add(&*histogram);
+            if params.reset {
+                histogram.reset();
+            }
+        }
+    }
+
+    let names = ENDPOINTS
+        .iter()
+        .map(|endpoint| endpoint.name)
+        .chain(["other"]);
+    let quantiles: BTreeMap<_, _> = names
+        .zip(merged)
+        .filter_map(|(name, histogram)| Some((name, histogram?)))
+        .map(|(name, histogram)| {
+            let quantiles = Quantiles {
+                count: histogram.len(),
+                p50_us: histogram.value_at_quantile(0.50),
+                p90_us: histogram.value_at_quantile(0.90),
+                p99_us: histogram.value_at_quantile(0.99),
+                p999_us: histogram.value_at_quantile(0.999),
+                max_us: histogram.max(),
+            };
+            (name, quantiles)
+        })
+        .collect();
+
+    JsonFast::new(quantiles)
+}
@@ -1,19 +1,57 @@
 use axum::{
-    http::{header, HeaderValue, StatusCode},
+    http::{header, HeaderValue},
     response::IntoResponse,
     routing::get,
-    Json, Router,
+    Router,
 };
 use dotenv::dotenv;
 use tower_http::set_header::SetResponseHeaderLayer;
 
+mod circuit_breaker;
+mod clock;
+mod common;
+#[cfg(feature = "compression")]
+mod compression;
+mod deadline;
+mod diagnostics;
+mod drain;
+#[cfg(feature = "unsafe-fast-http")]
+mod fast_http;
+mod idle_timeout;
+#[cfg(feature = "latency-histograms")]
+mod latency;
+mod max_requests;
+#[cfg(feature = "runtime-metrics")]
+mod metrics;
+mod micro_cache;
 mod models_common;
+#[cfg(feature = "pprof")]
+mod profiling;
+mod rate_limit;
+mod raw;
+mod reload;
+mod request_id;
 mod server;
+mod telemetry;
+mod totals;
+mod utils;
+mod ws;
 
-use self::models_common::Message;
+use self::{
+    common::{
+        capabilities,
+        endpoints::{Routes, JSON, PLAINTEXT, WS},
+        headers::TEXT_PLAIN,
+    },
+    models_common::Message,
+    utils::JsonFast,
+};
 
-pub async fn plaintext() -> &'static str {
-    "Hello, World!"
+pub async fn plaintext() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, TEXT_PLAIN.clone())],
+        "Hello, World!",
+    )
 }
 
 pub async fn json() -> impl IntoResponse {
@@ -21,26 +59,91 @@ pub async fn json() -> impl IntoResponse {
         message: "Hello, World!",
     };
 
-    (StatusCode::OK, Json(message))
+    JsonFast::new(message)
 }
 
-#[tokio::main]
-async fn main() {
-    dotenv().ok();
+fn routes() -> Routes<()> {
+    Routes::default()
+        .serve(&PLAINTEXT, get(plaintext))
+        .serve(&JSON, get(json))
+        .serve(&WS, get(ws::upgrade))
+}
 
+fn app() -> Router {
     let server_header_value = HeaderValue::from_static("Axum");
 
-    let app = Router::new()
-        .route("/plaintext", get(plaintext))
-        .route("/json", get(json))
+    routes()
+        .into_router()
+        .merge(common::info::routes("none", None))
         .layer(SetResponseHeaderLayer::if_not_present(
             header::SERVER,
             server_header_value,
-        ));
+        ))
+}
+
+#[tokio::main]
+async fn main() {
+    dotenv().ok();
+
+    if capabilities::requested() {
+        capabilities::print("none", &routes());
+    }
+
+    telemetry::init();
+
+    let app = app();
+
+    #[cfg(feature = "pprof")]
+    let app = app.merge(profiling::routes());
+
+    #[cfg(feature = "latency-histograms")]
+    let app = app.merge(latency::routes());
+
+    server::warm_up(&app).await;
+    let app = server::apply_limits(app);
+
+    server::spawn_raw_listener();
+
+    #[cfg(feature = "unsafe-fast-http")]
+    fast_http::serve(8000, app).await.unwrap();
+
+    #[cfg(not(feature = "unsafe-fast-http"))]
+    {
+        let serving = drain::Serving::start();
+        server::builder()
+            .http1_pipeline_flush(true)
+            .serve(max_requests::make_service(app))
+            .with_graceful_shutdown(drain::requested())
+            .await
+            .unwrap();
+        serving.finished().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{golden, testing};
+
+    #[tokio::test]
+    async fn serializes_json_byte_for_byte() {
+        assert_eq!(
+            testing::body_text(json().await).await,
+            testing::JSON_SNAPSHOT
+        );
+    }
+
+    #[tokio::test]
+    async fn every_route_has_its_tfb_content_type() {
+        let routes = routes();
+        let served = routes.served();
+
+        testing::assert_content_types(routes.into_router(), &served).await;
+    }
 
-    server::builder()
-        .http1_pipeline_flush(true)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    #[tokio::test]
+    async fn answers_as_recorded() {
+        golden::assert_endpoint("axum/plaintext", app(), "/plaintext").await;
+        golden::assert_endpoint("axum/json", app(), "/json").await;
+    }
 }
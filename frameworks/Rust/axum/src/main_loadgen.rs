@@ -0,0 +1,973 @@
+//! Load generator for the workloads wrk can't drive.
+//!
+//! ```text
+//! axum-loadgen ws [--url URL] [--connections N] [--duration SECS] [--size BYTES]
+//! axum-loadgen bench --a CONFIG --b CONFIG [--path PATH] [--connections N]
+//!     [--duration SECS]
+//! axum-loadgen scenario FILE
+//! axum-loadgen parity [CONFIG]
+//! ```
+//!
+//! `ws` opens `N` WebSocket connections to the `/ws` echo endpoint, each sending
+//! one `BYTES` long text message at a time and waiting for its echo, and reports
+//! the echoed messages per second.
+//!
+//! `bench` boots two server configurations in this process and compares them.
+//! `pool` serves `/db` and `/queries` from a deadpool shared by a multi-threaded
+//! runtime, as `axum-pg-pool` does, with `AXUM_TECHEMPOWER_MAX_POOL_SIZE`
+//! connections; `per-core` serves them from one connection per single-threaded
+//! worker, as `axum-pg` does. Both read `AXUM_TECHEMPOWER_DATABASE_URL` and the
+//! other server settings from the environment. Each is booted on a free local
+//! port and driven over `N` keep-alive HTTP/1.1 connections sending `GET PATH`
+//! (default `/db`) back to back for `SECS`, one after the other, so they don't
+//! compete for the CPU, and the results are printed side by side. The first
+//! stays up, idle, while the second runs.
+//!
+//! `scenario` drives a running server with the mixed workload a TOML file
+//! describes, as wrk would with a Lua script. Every request picks its path by
+//! weight, and the stages run one after the other, so a concurrency ramp is a
+//! series of stages; latencies are reported per stage and path:
+//!
+//! ```toml
+//! addr = "127.0.0.1:8000" # the default
+//!
+//! [[requests]]
+//! path = "/json"
+//! weight = 70
+//!
+//! [[requests]]
+//! path = "/queries?queries=20"
+//! weight = 20
+//!
+//! [[requests]]
+//! path = "/updates?queries=20"
+//! weight = 10
+//!
+//! [[stages]]
+//! connections = 16
+//! duration_secs = 10
+//!
+//! [[stages]]
+//! connections = 256
+//! duration_secs = 30
+//! ```
+//!
+//! `parity` asks every binary built next to it which endpoints it serves,
+//! through `--capabilities`, and prints them as a matrix. `tested` marks the
+//! endpoints the binary's test in `CONFIG` (default `benchmark_config.json`)
+//! declares, `yes` the other endpoints it serves. It fails if a binary no
+//! longer serves an endpoint its test declares, so refactors can't silently
+//! drop one. TLS and transactional updates aren't listed: no binary has them.
+
+// `bench` boots its servers from the server binaries' modules, of which it
+// uses only a few items
+#![allow(dead_code)]
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    net::{Ipv4Addr, SocketAddr, TcpListener},
+    path::Path,
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    http::StatusCode, response::IntoResponse, routing::get as route_get, Router,
+};
+use futures_util::{future, SinkExt, StreamExt};
+use hyper::{client::conn, header, Body, Request};
+use rand::{
+    distributions::WeightedIndex, prelude::Distribution, rngs::SmallRng, SeedableRng,
+};
+use serde::Deserialize;
+use tokio::{net::TcpStream, process::Command};
+use tokio_tungstenite::{connect_async, tungstenite};
+
+#[cfg(not(feature = "html-writer"))]
+mod blocking;
+mod cache;
+mod circuit_breaker;
+mod clock;
+mod common;
+#[cfg(feature = "compression")]
+mod compression;
+mod database_pg;
+mod database_pg_pool;
+mod deadline;
+mod diagnostics;
+mod drain;
+mod idle_timeout;
+#[cfg(feature = "latency-histograms")]
+mod latency;
+mod max_requests;
+#[cfg(feature = "runtime-metrics")]
+mod metrics;
+mod micro_cache;
+mod models_common;
+mod models_pg;
+mod models_pg_pool;
+mod pool_tuning;
+#[cfg(feature = "pprof")]
+mod profiling;
+mod rate_limit;
+mod raw;
+mod reload;
+mod request_id;
+mod server;
+mod telemetry;
+mod totals;
+mod utils;
+
+pub use self::models_pg_pool::{Fortune, World};
+use self::{
+    common::params::Queries,
+    database_pg::{DatabaseConnection, PgConnection},
+    database_pg_pool::{
+        create_pool, fetch_world_by_id, fetch_worlds,
+        prepare_fetch_world_by_id_statement, query_error, warm_up_pool, DatabaseClient,
+    },
+    server::Server,
+    utils::{
+        exit_on_error, get_environment_variable, get_optional_environment_variable,
+        random_id, random_ids, JsonFast, Rng, WORLD_JSON_CAPACITY,
+    },
+};
+
+struct Options {
+    url: String,
+    connections: usize,
+    duration: Duration,
+    size: usize,
+}
+
+struct BenchOptions {
+    a: &'static Configuration,
+    b: &'static Configuration,
+    path: String,
+    connections: usize,
+    duration: Duration,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Scenario {
+    #[serde(default = "default_addr")]
+    addr: String,
+    requests: Vec<WeightedRequest>,
+    stages: Vec<Stage>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WeightedRequest {
+    path: String,
+    weight: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Stage {
+    connections: usize,
+    duration_secs: u64,
+}
+
+fn default_addr() -> String {
+    DEFAULT_ADDR.to_string()
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: axum-loadgen ws [--url URL] [--connections N] [--duration SECS] \
+         [--size BYTES]\n       \
+         axum-loadgen bench --a CONFIG --b CONFIG [--path PATH] \
+         [--connections N] [--duration SECS]\n       \
+         axum-loadgen scenario FILE\n       \
+         axum-loadgen parity [CONFIG]\n\n\
+         bench configurations: {}",
+        CONFIGURATIONS
+            .map(|configuration| configuration.name)
+            .join(", ")
+    );
+    process::exit(2)
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Options {
+    let mut options = Options {
+        url: "ws://127.0.0.1:8000/ws".to_string(),
+        connections: 64,
+        duration: Duration::from_secs(10),
+        size: 32,
+    };
+
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        let number = || value.parse::<u64>().unwrap_or_else(|_| usage());
+
+        match flag.as_str() {
+            "--url" => options.url = value.clone(),
+            "--connections" => options.connections = number() as usize,
+            "--duration" => options.duration = Duration::from_secs(number()),
+            "--size" => options.size = number() as usize,
+            _ => usage(),
+        }
+    }
+
+    options
+}
+
+fn parse_bench_options(mut args: impl Iterator<Item = String>) -> BenchOptions {
+    let (mut a, mut b) = (None, None);
+    let mut options = BenchOptions {
+        a: &CONFIGURATIONS[0],
+        b: &CONFIGURATIONS[0],
+        path: "/db".to_string(),
+        connections: 64,
+        duration: Duration::from_secs(10),
+    };
+
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        let number = || value.parse::<u64>().unwrap_or_else(|_| usage());
+        let configuration = || {
+            CONFIGURATIONS
+                .iter()
+                .find(|configuration| configuration.name == value)
+                .unwrap_or_else(|| usage())
+        };
+
+        match flag.as_str() {
+            "--a" => a = Some(configuration()),
+            "--b" => b = Some(configuration()),
+            "--path" => options.path = value.clone(),
+            "--connections" => options.connections = number() as usize,
+            "--duration" => options.duration = Duration::from_secs(number()),
+            _ => usage(),
+        }
+    }
+
+    match (a, b) {
+        (Some(a), Some(b)) => BenchOptions { a, b, ..options },
+        _ => usage(),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = env::args().skip(1);
+
+    match args.next().as_deref() {
+        Some("ws") => ws(parse_options(args)).await,
+        Some("bench") => bench(parse_bench_options(args)).await,
+        Some("scenario") => match (args.next(), args.next()) {
+            (Some(file), None) => scenario(&file).await,
+            _ => usage(),
+        },
+        Some("parity") => match (args.next(), args.next()) {
+            (config, None) => {
+                parity(config.as_deref().unwrap_or("benchmark_config.json")).await
+            }
+            _ => usage(),
+        },
+        _ => usage(),
+    }
+}
+
+async fn ws(options: Options) {
+    let messages = Arc::new(AtomicU64::new(0));
+    let payload = "x".repeat(options.size);
+    let start = Instant::now();
+    let deadline = start + options.duration;
+
+    let connections: Vec<_> = (0..options.connections)
+        .map(|_| {
+            tokio::spawn(ws_connection(
+                options.url.clone(),
+                payload.clone(),
+                deadline,
+                messages.clone(),
+            ))
+        })
+        .collect();
+
+    let mut failed = 0;
+    for connection in connections {
+        let result = match connection.await {
+            Ok(result) => result,
+            Err(err) => Err(err.to_string()),
+        };
+
+        if let Err(err) = result {
+            eprintln!("connection failed: {err}");
+            failed += 1;
+        }
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let messages = messages.load(Ordering::Relaxed);
+
+    println!(
+        "{messages} messages in {elapsed:.2}s over {} connections ({failed} failed): \
+         {:.0} messages/sec",
+        options.connections,
+        messages as f64 / elapsed
+    );
+}
+
+async fn ws_connection(
+    url: String,
+    payload: String,
+    deadline: Instant,
+    messages: Arc<AtomicU64>,
+) -> Result<(), String> {
+    let (mut socket, _) = connect_async(url.as_str())
+        .await
+        .map_err(|err| err.to_string())?;
+
+    while Instant::now() < deadline {
+        socket
+            .send(tungstenite::Message::Text(payload.clone()))
+            .await
+            .map_err(|err| err.to_string())?;
+
+        // skip control frames until the echo arrives
+        loop {
+            match socket.next().await {
+                Some(Ok(
+                    tungstenite::Message::Text(_) | tungstenite::Message::Binary(_),
+                )) => break,
+                Some(Ok(tungstenite::Message::Close(_))) | None => {
+                    return Err("closed by server".to_string())
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Err(err.to_string()),
+            }
+        }
+
+        messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let _ = socket.close(None).await;
+    Ok(())
+}
+
+const DEFAULT_ADDR: &str = "127.0.0.1:8000";
+
+/// Outcome of driving one configuration, or one path of a mix.
+struct BenchResult {
+    requests: u64,
+    errors: u64,
+    elapsed: Duration,
+    /// Sorted latencies of the successful requests, in microseconds.
+    latencies: Vec<u32>,
+}
+
+impl BenchResult {
+    fn quantile(&self, q: f64) -> u32 {
+        if self.latencies.is_empty() {
+            return 0;
+        }
+        let index = ((self.latencies.len() - 1) as f64 * q).round() as usize;
+        self.latencies[index]
+    }
+
+    fn rps(&self) -> f64 {
+        self.requests as f64 / self.elapsed.as_secs_f64()
+    }
+
+    fn print_header(label: &str) {
+        println!(
+            "{label:<24} {:>10} {:>12} {:>10} {:>10} {:>10} {:>8}",
+            "requests", "rps", "p50_us", "p99_us", "max_us", "errors"
+        );
+    }
+
+    fn print_row(&self, label: &str) {
+        println!(
+            "{label:<24} {:>10} {:>12.0} {:>10} {:>10} {:>10} {:>8}",
+            self.requests,
+            self.rps(),
+            self.quantile(0.50),
+            self.quantile(0.99),
+            self.latencies.last().copied().unwrap_or(0),
+            self.errors
+        );
+    }
+}
+
+/// Paths to request on `addr`, each picked with its weight.
+struct Mix {
+    addr: String,
+    paths: Vec<String>,
+    weights: WeightedIndex<u32>,
+}
+
+impl Mix {
+    fn single(addr: &str, path: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+            paths: vec![path.to_string()],
+            weights: WeightedIndex::new([1]).unwrap(),
+        }
+    }
+}
+
+async fn bench(options: BenchOptions) {
+    let mut results = Vec::new();
+
+    for (name, configuration) in [("a", options.a), ("b", options.b)] {
+        println!("{name}: {}", configuration.name);
+        match bench_configuration(configuration, &options).await {
+            Ok(result) => results.push((name, result)),
+            Err(err) => {
+                eprintln!("{name}: {err}");
+                process::exit(1);
+            }
+        }
+    }
+
+    println!();
+    BenchResult::print_header("config");
+    for (name, result) in &results {
+        result.print_row(name);
+    }
+
+    let (a, b) = (&results[0].1, &results[1].1);
+    println!(
+        "\nb/a: rps {:.2}x, p50 {:.2}x, p99 {:.2}x",
+        b.rps() / a.rps(),
+        f64::from(b.quantile(0.50)) / f64::from(a.quantile(0.50).max(1)),
+        f64::from(b.quantile(0.99)) / f64::from(a.quantile(0.99).max(1)),
+    );
+}
+
+/// Boots `configuration` on a free port, and drives it.
+async fn bench_configuration(
+    configuration: &Configuration,
+    options: &BenchOptions,
+) -> Result<BenchResult, String> {
+    let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|listener| listener.local_addr())
+        .map_err(|err| format!("could not find a free port: {err}"))?
+        .port();
+    (configuration.boot)(port);
+
+    let mix = Arc::new(Mix::single(&format!("127.0.0.1:{port}"), &options.path));
+    wait_until_ready(&mix.addr, &options.path).await?;
+    let mut results = drive(mix.clone(), options.connections, options.duration).await;
+    Ok(results.remove(0))
+}
+
+/// A server `bench` can boot in this process.
+struct Configuration {
+    name: &'static str,
+    /// Starts serving on `port` on threads of its own, and returns.
+    boot: fn(u16),
+}
+
+const CONFIGURATIONS: [Configuration; 2] = [
+    Configuration {
+        name: "pool",
+        boot: boot_pool,
+    },
+    Configuration {
+        name: "per-core",
+        boot: boot_per_core,
+    },
+];
+
+fn boot_pool(port: u16) {
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async move {
+            let database_url: String =
+                get_environment_variable("AXUM_TECHEMPOWER_DATABASE_URL");
+            let max_pool_size: u32 =
+                get_environment_variable("AXUM_TECHEMPOWER_MAX_POOL_SIZE");
+            let pool = create_pool(
+                database_url,
+                max_pool_size,
+                get_optional_environment_variable(
+                    "AXUM_TECHEMPOWER_STATEMENT_TIMEOUT_MS",
+                ),
+                get_optional_environment_variable("AXUM_TECHEMPOWER_POOL_WAIT_MS"),
+            )
+            .await;
+            exit_on_error(
+                warm_up_pool(&pool, max_pool_size as usize).await,
+                "could not warm up postgres pool",
+            );
+
+            let router = Router::new()
+                .route("/db", route_get(pool_db))
+                .route("/queries", route_get(pool_queries))
+                .with_state(pool);
+            let router = server::apply_limits(router);
+
+            server::builder_on(port)
+                .serve(max_requests::make_service(router))
+                .await
+                .unwrap();
+        });
+    });
+}
+
+fn boot_per_core(port: u16) {
+    std::thread::spawn(move || {
+        Server::builder()
+            .bind(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+            .serve(|| async {
+                let database_url: String =
+                    get_environment_variable("AXUM_TECHEMPOWER_DATABASE_URL");
+                let connection = exit_on_error(
+                    PgConnection::connect(
+                        database_url,
+                        get_optional_environment_variable(
+                            "AXUM_TECHEMPOWER_STATEMENT_TIMEOUT_MS",
+                        ),
+                    )
+                    .await,
+                    "could not set up postgres connection",
+                );
+
+                Router::new()
+                    .route("/db", route_get(per_core_db))
+                    .route("/queries", route_get(per_core_queries))
+                    .with_state(connection)
+            });
+    });
+}
+
+async fn pool_db(
+    client: DatabaseClient,
+    mut rng: Rng,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let select = prepare_fetch_world_by_id_statement(&client)
+        .await
+        .map_err(query_error)?;
+    let world = fetch_world_by_id(&client, random_id(&mut rng), &select)
+        .await
+        .map_err(query_error)?;
+    client.finish().await.map_err(query_error)?;
+
+    Ok(JsonFast::new(world))
+}
+
+async fn pool_queries(
+    client: DatabaseClient,
+    mut rng: Rng,
+    Queries(q): Queries,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let results = fetch_worlds(&client, random_ids(&mut rng, q))
+        .await
+        .map_err(query_error)?;
+    client.finish().await.map_err(query_error)?;
+
+    let capacity = results.len() * WORLD_JSON_CAPACITY;
+    Ok(JsonFast::with_capacity(results, capacity))
+}
+
+fn database_error(err: database_pg::PgError) -> (StatusCode, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+}
+
+async fn per_core_db(
+    DatabaseConnection(conn): DatabaseConnection,
+    mut rng: Rng,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let world = conn.get_world(&mut rng).await.map_err(database_error)?;
+
+    Ok(JsonFast::new(world))
+}
+
+async fn per_core_queries(
+    DatabaseConnection(conn): DatabaseConnection,
+    mut rng: Rng,
+    Queries(q): Queries,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let results = conn.get_worlds(&mut rng, q).await.map_err(database_error)?;
+
+    let capacity = results.len() * WORLD_JSON_CAPACITY;
+    Ok(JsonFast::with_capacity(results, capacity))
+}
+
+/// Waits up to 30s for the server to answer `path` successfully.
+async fn wait_until_ready(addr: &str, path: &str) -> Result<(), String> {
+    let deadline = Instant::now() + Duration::from_secs(30);
+
+    while Instant::now() < deadline {
+        if let Ok(mut sender) = connect(addr).await {
+            if let Ok(response) = sender.send_request(get(addr, path)).await {
+                if response.status().is_success() {
+                    return Ok(());
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    Err(format!("server didn't answer {path} within 30s"))
+}
+
+async fn connect(addr: &str) -> Result<conn::SendRequest<Body>, String> {
+    let stream = TcpStream::connect(addr)
+        .await
+        .map_err(|err| err.to_string())?;
+    let _ = stream.set_nodelay(true);
+
+    let (sender, connection) = conn::handshake(stream)
+        .await
+        .map_err(|err| err.to_string())?;
+    tokio::spawn(connection);
+    Ok(sender)
+}
+
+fn get(addr: &str, path: &str) -> Request<Body> {
+    Request::get(path)
+        .header("host", addr)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Benchmark endpoints in report order, named as `--capabilities` names them.
+const PARITY_ENDPOINTS: &[&str] = &[
+    "plaintext",
+    "json",
+    "ws",
+    "db",
+    "queries",
+    "fortunes",
+    "updates",
+    "cached-queries",
+    "events",
+];
+
+/// `benchmark_config.json` URL keys and the endpoints they test.
+const CONFIG_URLS: &[(&str, &str)] = &[
+    ("plaintext_url", "plaintext"),
+    ("json_url", "json"),
+    ("db_url", "db"),
+    ("query_url", "queries"),
+    ("fortune_url", "fortunes"),
+    ("update_url", "updates"),
+    ("cached_query_url", "cached-queries"),
+];
+
+#[derive(Deserialize)]
+struct BenchmarkConfig {
+    tests: Vec<BTreeMap<String, BTreeMap<String, serde_json::Value>>>,
+}
+
+#[derive(Deserialize)]
+struct Capabilities {
+    endpoints: Vec<String>,
+}
+
+/// The endpoints `binary` serves, from its `--capabilities`.
+async fn capabilities(binary: &Path) -> Result<Vec<String>, String> {
+    let output = Command::new(binary)
+        .arg("--capabilities")
+        .env_remove("AXUM_TECHEMPOWER_ENDPOINTS")
+        .output()
+        .await
+        .map_err(|err| err.to_string())?;
+    if !output.status.success() {
+        return Err(format!("exited with {}", output.status));
+    }
+
+    serde_json::from_slice::<Capabilities>(&output.stdout)
+        .map(|capabilities| capabilities.endpoints)
+        .map_err(|err| err.to_string())
+}
+
+/// The endpoints a `benchmark_config.json` test declares.
+fn declared_endpoints(test: &BTreeMap<String, serde_json::Value>) -> Vec<&'static str> {
+    CONFIG_URLS
+        .iter()
+        .filter(|(key, _)| test.contains_key(*key))
+        .map(|&(_, endpoint)| endpoint)
+        .collect()
+}
+
+/// The report's cells for a binary, in `PARITY_ENDPOINTS` order.
+fn parity_row(served: &[String], declared: &[&str]) -> Vec<&'static str> {
+    PARITY_ENDPOINTS
+        .iter()
+        .map(|endpoint| {
+            let served = served.iter().any(|name| name == endpoint);
+            match (served, declared.contains(endpoint)) {
+                (true, true) => "tested",
+                (true, false) => "yes",
+                (false, true) => "MISSING",
+                (false, false) => "-",
+            }
+        })
+        .collect()
+}
+
+async fn parity(config: &str) {
+    let config = fs::read_to_string(config)
+        .map_err(|err| err.to_string())
+        .and_then(|text| {
+            serde_json::from_str::<BenchmarkConfig>(&text).map_err(|err| err.to_string())
+        })
+        .unwrap_or_else(|err| {
+            eprintln!("could not read {config}: {err}");
+            process::exit(2);
+        });
+    let dir = env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+        .unwrap_or_default();
+
+    print!("{:<16}", "binary");
+    for endpoint in PARITY_ENDPOINTS {
+        print!(" {endpoint:>14}");
+    }
+    println!();
+
+    let mut failed = false;
+    for (variant, test) in config.tests.iter().flatten() {
+        let binary = match variant.as_str() {
+            "default" => "axum".to_string(),
+            variant => format!("axum-{variant}"),
+        };
+        let declared = declared_endpoints(test);
+
+        let served = match capabilities(&dir.join(&binary)).await {
+            Ok(served) => served,
+            Err(err) => {
+                println!("{binary:<16} could not list capabilities: {err}");
+                failed = true;
+                continue;
+            }
+        };
+
+        print!("{binary:<16}");
+        for cell in parity_row(&served, &declared) {
+            failed |= cell == "MISSING";
+            print!(" {cell:>14}");
+        }
+        println!();
+    }
+
+    if failed {
+        eprintln!("\nsome binaries don't serve the tests benchmark_config.json declares for them");
+        process::exit(1);
+    }
+}
+
+async fn scenario(file: &str) {
+    let scenario = fs::read_to_string(file)
+        .map_err(|err| err.to_string())
+        .and_then(|text| {
+            toml::from_str::<Scenario>(&text).map_err(|err| err.to_string())
+        })
+        .unwrap_or_else(|err| {
+            eprintln!("could not read scenario {file}: {err}");
+            process::exit(2);
+        });
+
+    let weights = scenario.requests.iter().map(|request| request.weight);
+    let weights = WeightedIndex::new(weights).unwrap_or_else(|err| {
+        eprintln!("invalid request weights in {file}: {err}");
+        process::exit(2);
+    });
+    let mix = Arc::new(Mix {
+        addr: scenario.addr,
+        paths: scenario.requests.into_iter().map(|r| r.path).collect(),
+        weights,
+    });
+
+    for (number, stage) in scenario.stages.iter().enumerate() {
+        let duration = Duration::from_secs(stage.duration_secs);
+        println!(
+            "\nstage {}: {} connections for {duration:?}",
+            number + 1,
+            stage.connections
+        );
+
+        let results = drive(mix.clone(), stage.connections, duration).await;
+
+        BenchResult::print_header("path");
+        for (path, result) in mix.paths.iter().zip(&results) {
+            result.print_row(path);
+        }
+
+        let mut total = BenchResult {
+            requests: results.iter().map(|result| result.requests).sum(),
+            errors: results.iter().map(|result| result.errors).sum(),
+            elapsed: results[0].elapsed,
+            latencies: results
+                .iter()
+                .flat_map(|r| r.latencies.iter().copied())
+                .collect(),
+        };
+        total.latencies.sort_unstable();
+        total.print_row("total");
+    }
+}
+
+/// Drives `mix` over `connections` connections for `duration`, returning the
+/// results per path of the mix.
+async fn drive(
+    mix: Arc<Mix>,
+    connections: usize,
+    duration: Duration,
+) -> Vec<BenchResult> {
+    let start = Instant::now();
+    let deadline = start + duration;
+
+    let connections: Vec<_> = (0..connections)
+        .map(|_| tokio::spawn(drive_connection(mix.clone(), deadline)))
+        .collect();
+
+    let mut results: Vec<_> = mix
+        .paths
+        .iter()
+        .map(|_| BenchResult {
+            requests: 0,
+            errors: 0,
+            elapsed: Duration::ZERO,
+            latencies: Vec::new(),
+        })
+        .collect();
+    for connection in connections {
+        let per_path = connection.await.unwrap_or_default();
+        for (result, (latencies, errors)) in results.iter_mut().zip(per_path) {
+            result.requests += latencies.len() as u64 + errors;
+            result.errors += errors;
+            result.latencies.extend(latencies);
+        }
+    }
+
+    let elapsed = start.elapsed();
+    for result in &mut results {
+        result.elapsed = elapsed;
+        result.latencies.sort_unstable();
+    }
+    results
+}
+
+/// Sends requests back to back until `deadline`, reconnecting after
+/// connection errors. Returns the latencies and errors per path of `mix`.
+async fn drive_connection(mix: Arc<Mix>, deadline: Instant) -> Vec<(Vec<u32>, u64)> {
+    let mut results = vec![(Vec::new(), 0); mix.paths.len()];
+    let mut rng = SmallRng::from_entropy();
+    let mut sender: Option<conn::SendRequest<Body>> = None;
+
+    while Instant::now() < deadline {
+        let path = mix.weights.sample(&mut rng);
+        let (latencies, errors) = &mut results[path];
+
+        // a connection the server closed after its last response, e.g. a
+        // draining one, is replaced without counting an error
+        if let Some(current) = sender.as_mut() {
+            if future::poll_fn(|cx| current.poll_ready(cx)).await.is_err() {
+                sender = None;
+            }
+        }
+
+        let current = match sender.as_mut() {
+            Some(current) => current,
+            None => match connect(&mix.addr).await {
+                Ok(connected) => sender.insert(connected),
+                Err(_) => {
+                    *errors += 1;
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    continue;
+                }
+            },
+        };
+
+        let start = Instant::now();
+        let mut closing = false;
+        // `None` if the connection failed, which is then replaced
+        let success =
+            match current.send_request(get(&mix.addr, &mix.paths[path])).await {
+                Ok(response) => {
+                    let success = response.status().is_success();
+                    // e.g. a draining server
+                    closing = response.headers().get(header::CONNECTION).is_some_and(
+                        |value| value.as_bytes().eq_ignore_ascii_case(b"close"),
+                    );
+                    let body = hyper::body::to_bytes(response.into_body()).await;
+                    body.ok().map(|_| success)
+                }
+                Err(_) => None,
+            };
+
+        match success {
+            Some(true) => {
+                latencies.push(start.elapsed().as_micros().min(u32::MAX as u128) as u32)
+            }
+            Some(false) => *errors += 1,
+            None => {
+                *errors += 1;
+                sender = None;
+            }
+        }
+        if closing {
+            sender = None;
+        }
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"{
+        "tests": [{
+            "default": {"plaintext_url": "/plaintext", "json_url": "/json", "port": 8000},
+            "pg": {"db_url": "/db", "query_url": "/queries?queries=", "fortune_url": "/fortunes"}
+        }]
+    }"#;
+
+    /// `--capabilities` of a binary serving every endpoint its test declares,
+    /// and one more.
+    const COMPLETE: &str = r#"{"backend":"none","endpoints":["plaintext","json","ws"]}"#;
+
+    /// `--capabilities` of a binary that serves some of the endpoints its test
+    /// declares, and lost another.
+    const REGRESSED: &str =
+        r#"{"backend":"postgres","endpoints":["db","fortunes","updates"]}"#;
+
+    fn row(capabilities: &str, variant: &str) -> Vec<&'static str> {
+        let config: BenchmarkConfig = serde_json::from_str(CONFIG).unwrap();
+        let served = serde_json::from_str::<Capabilities>(capabilities)
+            .unwrap()
+            .endpoints;
+        let test = config
+            .tests
+            .iter()
+            .flatten()
+            .find(|(name, _)| *name == variant)
+            .map(|(_, test)| test)
+            .unwrap();
+
+        parity_row(&served, &declared_endpoints(test))
+    }
+
+    #[test]
+    fn marks_declared_and_extra_endpoints() {
+        assert_eq!(
+            row(COMPLETE, "default"),
+            ["tested", "tested", "yes", "-", "-", "-", "-", "-", "-"]
+        );
+    }
+
+    #[test]
+    fn marks_only_the_declared_endpoints_a_binary_lost_as_missing() {
+        assert_eq!(
+            row(REGRESSED, "pg"),
+            ["-", "-", "-", "tested", "MISSING", "tested", "yes", "-", "-"]
+        );
+    }
+}
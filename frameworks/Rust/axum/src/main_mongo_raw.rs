@@ -1,122 +1,172 @@
 use std::time::Duration;
 
 use axum::{
-    extract::Query,
-    http::{header, HeaderValue, StatusCode},
+    http::{header, HeaderValue},
     response::IntoResponse,
     routing::get,
-    Json, Router,
+    Router,
 };
+#[cfg(feature = "raw-json")]
+use bytes::BytesMut;
 use dotenv::dotenv;
-use mongodb::{
-    options::{ClientOptions, Compressor},
-    Client,
-};
-use rand::{rngs::SmallRng, thread_rng, Rng, SeedableRng};
+use mongodb::options::{ClientOptions, Compressor};
 use tower_http::set_header::SetResponseHeaderLayer;
 
+mod circuit_breaker;
+mod clock;
+mod common;
+#[cfg(feature = "compression")]
+mod compression;
 mod database_mongo_raw;
+mod deadline;
+mod diagnostics;
+mod drain;
+mod idle_timeout;
+#[cfg(feature = "latency-histograms")]
+mod latency;
+mod max_requests;
+#[cfg(feature = "runtime-metrics")]
+mod metrics;
+mod micro_cache;
 mod models_common;
 mod models_mongo;
+#[cfg(feature = "pprof")]
+mod profiling;
+mod rate_limit;
+mod raw;
+mod reload;
+mod request_id;
 mod server;
+mod telemetry;
+mod totals;
 mod utils;
+mod write_batch;
 
+#[cfg(not(feature = "raw-json"))]
+use self::database_mongo_raw::find_world_by_id;
 use self::{
-    database_mongo_raw::{
-        find_world_by_id, find_worlds, update_worlds, DatabaseConnection,
+    common::{
+        capabilities,
+        endpoints::{Routes, DB, QUERIES, UPDATES},
+        params::Queries,
+    },
+    database_mongo_raw::{find_worlds, DatabaseConnection},
+    models_mongo::{update_worlds_batched, Databases, World},
+    server::Server,
+    utils::{
+        get_environment_variable, random_id, random_ids, random_number, JsonFast, Rng,
+        WORLD_JSON_CAPACITY,
     },
-    models_mongo::World,
-    utils::{get_environment_variable, parse_params, Params},
+};
+#[cfg(feature = "raw-json")]
+use self::{
+    database_mongo_raw::{write_world_json, write_worlds_json},
+    utils::RawJson,
 };
 
-async fn db(DatabaseConnection(db): DatabaseConnection) -> impl IntoResponse {
-    let mut rng = SmallRng::from_rng(&mut thread_rng()).unwrap();
+#[cfg(not(feature = "raw-json"))]
+async fn db(
+    DatabaseConnection(db): DatabaseConnection,
+    mut rng: Rng,
+) -> impl IntoResponse {
+    let world = find_world_by_id(&db, random_id(&mut rng))
+        .await
+        .expect("world could not be found");
 
-    let random_id = (rng.gen::<u32>() % 10_000 + 1) as i32;
+    JsonFast::new(world)
+}
 
-    let world = find_world_by_id(db, random_id)
+#[cfg(feature = "raw-json")]
+async fn db(
+    DatabaseConnection(db): DatabaseConnection,
+    mut rng: Rng,
+) -> impl IntoResponse {
+    let mut body = BytesMut::with_capacity(WORLD_JSON_CAPACITY);
+    write_world_json(&db, random_id(&mut rng), &mut body)
         .await
         .expect("world could not be found");
 
-    (StatusCode::OK, Json(world))
+    RawJson(body.freeze())
 }
 
+#[cfg(not(feature = "raw-json"))]
 async fn queries(
     DatabaseConnection(db): DatabaseConnection,
-    Query(params): Query<Params>,
+    mut rng: Rng,
+    Queries(q): Queries,
 ) -> impl IntoResponse {
-    let q = parse_params(params);
-
-    let mut rng = SmallRng::from_rng(&mut thread_rng()).unwrap();
-    let mut ids: Vec<i32> = Vec::with_capacity(q as usize);
-
-    for _ in 0..q {
-        let random_id = (rng.gen::<u32>() % 10_000 + 1) as i32;
+    let ids = random_ids(&mut rng, q);
 
-        ids.push(random_id);
-    }
-
-    let worlds = find_worlds(db, ids).await;
+    let worlds = find_worlds(&db, ids).await;
     let results = worlds.expect("worlds could not be retrieved");
 
-    (StatusCode::OK, Json(results))
+    let capacity = results.len() * WORLD_JSON_CAPACITY;
+
+    JsonFast::with_capacity(results, capacity)
 }
 
-async fn updates(
+#[cfg(feature = "raw-json")]
+async fn queries(
     DatabaseConnection(db): DatabaseConnection,
-    Query(params): Query<Params>,
+    mut rng: Rng,
+    Queries(q): Queries,
 ) -> impl IntoResponse {
-    let q = parse_params(params);
+    let ids = random_ids(&mut rng, q);
 
-    let mut rng = SmallRng::from_rng(&mut thread_rng()).unwrap();
-    let mut ids: Vec<i32> = Vec::with_capacity(q as usize);
+    let mut body = BytesMut::with_capacity(q * WORLD_JSON_CAPACITY + 2);
+    write_worlds_json(&db, ids, &mut body)
+        .await
+        .expect("worlds could not be retrieved");
 
-    for _ in 0..q {
-        let random_id = (rng.gen::<u32>() % 10_000 + 1) as i32;
+    RawJson(body.freeze())
+}
 
-        ids.push(random_id);
-    }
+async fn updates(
+    DatabaseConnection(db): DatabaseConnection,
+    mut rng: Rng,
+    Queries(q): Queries,
+) -> impl IntoResponse {
+    let ids = random_ids(&mut rng, q);
 
-    let worlds = find_worlds(db.clone(), ids)
+    let worlds = find_worlds(&db, ids)
         .await
         .expect("worlds could not be retrieved");
-    let mut updated_worlds: Vec<World> = Vec::with_capacity(q as usize);
+    let mut updated_worlds: Vec<World> = Vec::with_capacity(q);
 
     for mut world in worlds {
-        let random_number = (rng.gen::<u32>() % 10_000 + 1) as i32;
-
-        world.random_number = random_number;
+        world.random_number = random_number(&mut rng);
         updated_worlds.push(world);
     }
 
-    update_worlds(db.clone(), updated_worlds.clone())
+    update_worlds_batched(&db, updated_worlds.clone())
         .await
         .expect("could not update worlds");
 
-    (StatusCode::OK, Json(updated_worlds.clone()))
+    let capacity = updated_worlds.len() * WORLD_JSON_CAPACITY;
+
+    JsonFast::with_capacity(updated_worlds, capacity)
+}
+
+fn routes() -> Routes<Databases> {
+    Routes::default()
+        .serve(&DB, get(db))
+        .serve(&QUERIES, get(queries))
+        .serve(&UPDATES, get(updates))
 }
 
 fn main() {
     dotenv().ok();
 
-    let rt = tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()
-        .unwrap();
-
-    for _ in 1..num_cpus::get() {
-        std::thread::spawn(move || {
-            let rt = tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap();
-            rt.block_on(serve());
-        });
+    if capabilities::requested() {
+        capabilities::print("mongodb-raw", &routes());
     }
-    rt.block_on(serve());
+
+    telemetry::init();
+
+    Server::builder().serve(app);
 }
 
-async fn serve() {
+async fn app() -> Router {
     let database_url: String = get_environment_variable("AXUM_TECHEMPOWER_MONGODB_URL");
     let max_pool_size: u32 = get_environment_variable("AXUM_TECHEMPOWER_MAX_POOL_SIZE");
     let min_pool_size: u32 = get_environment_variable("AXUM_TECHEMPOWER_MIN_POOL_SIZE");
@@ -127,6 +177,7 @@ async fn serve() {
     client_options.max_pool_size = Some(max_pool_size);
     client_options.min_pool_size = Some(min_pool_size);
     client_options.connect_timeout = Some(Duration::from_millis(200));
+    models_mongo::configure_for_sharding(&mut client_options);
 
     // the server will select the algorithm it supports from the list provided by the driver
     client_options.compressors = Some(vec![
@@ -139,22 +190,35 @@ async fn serve() {
         },
     ]);
 
-    let client = Client::with_options(client_options).unwrap();
-    let database = client.database("hello_world");
+    let databases = Databases::connect(client_options).unwrap();
     let server_header_value = HeaderValue::from_static("Axum");
 
-    let app = Router::new()
-        .route("/db", get(db))
-        .route("/queries", get(queries))
-        .route("/updates", get(updates))
-        .with_state(database)
+    routes()
+        .into_router()
+        .with_state(databases)
+        .merge(common::info::routes("mongodb-raw", Some(max_pool_size)))
         .layer(SetResponseHeaderLayer::if_not_present(
             header::SERVER,
             server_header_value,
-        ));
+        ))
+}
 
-    server::builder()
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testing;
+
+    #[tokio::test]
+    async fn every_route_has_its_tfb_content_type() {
+        let options = ClientOptions::parse("mongodb://127.0.0.1").await.unwrap();
+        let databases = Databases::connect(options).unwrap();
+        let routes = routes();
+        let served = routes.served();
+
+        testing::assert_content_types(
+            routes.into_router().with_state(databases),
+            &served,
+        )
+        .await;
+    }
 }
@@ -1,116 +1,200 @@
+use std::sync::Arc;
+
 use axum::{
-    extract::Query,
-    http::{header, HeaderValue, StatusCode},
+    http::{header, HeaderValue},
     response::IntoResponse,
     routing::get,
-    Json, Router,
+    Router,
 };
 use dotenv::dotenv;
 use tower_http::set_header::SetResponseHeaderLayer;
+#[cfg(not(feature = "html-writer"))]
 use yarte::Template;
 
+#[cfg(not(feature = "html-writer"))]
+mod blocking;
+mod circuit_breaker;
+mod clock;
+mod common;
+#[cfg(feature = "compression")]
+mod compression;
 mod database_pg;
+mod deadline;
+mod diagnostics;
+mod drain;
+mod idle_timeout;
+#[cfg(feature = "latency-histograms")]
+mod latency;
+mod max_requests;
+#[cfg(feature = "runtime-metrics")]
+mod metrics;
+mod micro_cache;
 mod models_common;
 mod models_pg;
+#[cfg(feature = "pprof")]
+mod profiling;
+mod rate_limit;
+mod raw;
+mod reload;
+mod request_id;
 mod server;
+mod telemetry;
+mod totals;
 mod utils;
 
+#[cfg(feature = "html-writer")]
+use self::common::html::render_fortunes;
 use self::{
+    common::{
+        capabilities,
+        endpoints::{Routes, DB, FORTUNES, QUERIES, UPDATES},
+        params::Queries,
+    },
     database_pg::{DatabaseConnection, PgConnection},
     models_pg::Fortune,
-    utils::{get_environment_variable, parse_params, Params, Utf8Html},
+    server::Server,
+    utils::{
+        exit_on_error, get_environment_variable, get_optional_environment_variable,
+        JsonFast, Rng, Utf8Html, WORLD_JSON_CAPACITY,
+    },
 };
 
+#[cfg(not(feature = "html-writer"))]
 #[derive(Template)]
 #[template(path = "fortunes.html.hbs")]
 pub struct FortunesTemplate<'a> {
-    pub fortunes: &'a Vec<Fortune>,
+    pub fortunes: &'a [Fortune],
 }
 
-async fn db(DatabaseConnection(conn): DatabaseConnection) -> impl IntoResponse {
-    let world = conn.get_world().await.expect("error loading world");
+async fn db(
+    DatabaseConnection(conn): DatabaseConnection,
+    mut rng: Rng,
+) -> impl IntoResponse {
+    let world = conn.get_world(&mut rng).await.expect("error loading world");
 
-    (StatusCode::OK, Json(world))
+    JsonFast::new(world)
 }
 
 async fn queries(
     DatabaseConnection(conn): DatabaseConnection,
-    Query(params): Query<Params>,
+    mut rng: Rng,
+    Queries(q): Queries,
 ) -> impl IntoResponse {
-    let q = parse_params(params);
-
     let results = conn
-        .get_worlds(q as usize)
+        .get_worlds(&mut rng, q)
         .await
         .expect("error loading worlds");
 
-    (StatusCode::OK, Json(results))
+    let capacity = results.len() * WORLD_JSON_CAPACITY;
+
+    JsonFast::with_capacity(results, capacity)
 }
 
 async fn fortunes(DatabaseConnection(conn): DatabaseConnection) -> impl IntoResponse {
     let fortunes: Vec<Fortune> =
         conn.tell_fortune().await.expect("error loading fortunes");
 
-    Utf8Html(
-        FortunesTemplate {
-            fortunes: &fortunes,
-        }
-        .call()
-        .expect("error rendering template"),
-    )
+    #[cfg(feature = "html-writer")]
+    let body =
+        render_fortunes(fortunes.iter().map(|f| (f.id, f.message.as_str()))).await;
+
+    #[cfg(not(feature = "html-writer"))]
+    let body = blocking::render(fortunes, |fortunes| {
+        FortunesTemplate { fortunes }
+            .call()
+            .expect("error rendering template")
+    })
+    .await;
+
+    Utf8Html(body)
 }
 
 async fn updates(
     DatabaseConnection(conn): DatabaseConnection,
-    Query(params): Query<Params>,
+    mut rng: Rng,
+    Queries(q): Queries,
 ) -> impl IntoResponse {
-    let q = parse_params(params);
+    let results = conn
+        .update(&mut rng, q as u16)
+        .await
+        .expect("error updating worlds");
+
+    let capacity = results.len() * WORLD_JSON_CAPACITY;
 
-    let results = conn.update(q as u16).await.expect("error updating worlds");
+    JsonFast::with_capacity(results, capacity)
+}
 
-    (StatusCode::OK, Json(results))
+fn routes() -> Routes<Arc<PgConnection>> {
+    Routes::default()
+        .serve(&FORTUNES, get(fortunes))
+        .serve(&DB, get(db))
+        .serve(&QUERIES, get(queries))
+        .serve(&UPDATES, get(updates))
 }
 
 fn main() {
     dotenv().ok();
 
-    let rt = tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()
-        .unwrap();
-
-    for _ in 1..num_cpus::get() {
-        std::thread::spawn(move || {
-            let rt = tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap();
-            rt.block_on(serve());
-        });
+    if capabilities::requested() {
+        capabilities::print("postgres", &routes());
     }
-    rt.block_on(serve());
+
+    telemetry::init();
+
+    Server::builder().serve(app);
 }
 
-async fn serve() {
+async fn app() -> Router {
     let database_url: String = get_environment_variable("AXUM_TECHEMPOWER_DATABASE_URL");
+    let statement_timeout_ms: Option<u64> =
+        get_optional_environment_variable("AXUM_TECHEMPOWER_STATEMENT_TIMEOUT_MS");
 
     // setup connection pool
-    let pg_connection = PgConnection::connect(database_url).await;
+    let pg_connection = exit_on_error(
+        PgConnection::connect(database_url, statement_timeout_ms).await,
+        "could not set up postgres connection",
+    );
     let server_header_value = HeaderValue::from_static("Axum");
 
-    let router = Router::new()
-        .route("/fortunes", get(fortunes))
-        .route("/db", get(db))
-        .route("/queries", get(queries))
-        .route("/updates", get(updates))
+    routes()
+        .into_router()
         .with_state(pg_connection)
+        // every worker holds a single connection
+        .merge(common::info::routes("postgres", Some(1)))
         .layer(SetResponseHeaderLayer::if_not_present(
             header::SERVER,
             server_header_value,
-        ));
+        ))
+}
 
-    server::builder()
-        .serve(router.into_make_service())
-        .await
+#[cfg(test)]
+mod tests {
+    #[cfg(not(feature = "html-writer"))]
+    use super::*;
+    use crate::{common::testing, models_pg::World};
+
+    #[cfg(not(feature = "html-writer"))]
+    #[test]
+    fn renders_fortunes_byte_for_byte() {
+        let fortunes: Vec<Fortune> = testing::FORTUNES
+            .iter()
+            .map(|&(id, message)| Fortune {
+                id,
+                message: message.to_string(),
+            })
+            .collect();
+
+        let page = FortunesTemplate {
+            fortunes: &fortunes,
+        }
+        .call()
         .unwrap();
+        assert_eq!(page, testing::TEMPLATE_PAGE);
+    }
+
+    #[tokio::test]
+    async fn serializes_worlds_byte_for_byte() {
+        testing::assert_world_snapshots(|id, randomnumber| World { id, randomnumber })
+            .await;
+    }
 }
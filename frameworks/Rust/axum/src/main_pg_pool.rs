@@ -1,165 +1,590 @@
+use std::{pin::pin, sync::Arc, time::Instant};
+
 use axum::{
-    extract::Query,
+    extract::{FromRef, State},
     http::{header, HeaderValue, StatusCode},
-    response::IntoResponse,
-    routing::get,
-    Json, Router,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
 };
 use dotenv::dotenv;
-use futures_util::{stream::FuturesUnordered, TryStreamExt};
-use rand::{rngs::SmallRng, thread_rng, Rng, SeedableRng};
+use futures_util::StreamExt;
+use serde::Serialize;
 use tower_http::set_header::SetResponseHeaderLayer;
+#[cfg(not(feature = "html-writer"))]
 use yarte::Template;
 
+#[cfg(not(feature = "html-writer"))]
+mod blocking;
+mod cache;
+mod circuit_breaker;
+mod clock;
+mod common;
+#[cfg(feature = "compression")]
+mod compression;
 mod database_pg_pool;
+mod deadline;
+mod diagnostics;
+mod drain;
+mod events;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod idle_timeout;
+#[cfg(feature = "latency-histograms")]
+mod latency;
+mod max_requests;
+#[cfg(feature = "runtime-metrics")]
+mod metrics;
+mod micro_cache;
 mod models_common;
 mod models_pg_pool;
+mod pool_tuning;
+#[cfg(feature = "pprof")]
+mod profiling;
+mod rate_limit;
+mod raw;
+mod reload;
+mod request_id;
 mod server;
+mod telemetry;
+mod totals;
 mod utils;
 
+#[cfg(feature = "html-writer")]
+use self::common::html::render_fortunes;
 use self::{
+    cache::{LruWorldCache, WorldCache},
+    common::{
+        admin::Admin,
+        capabilities,
+        endpoints::{Routes, CACHED_QUERIES, DB, EVENTS, FORTUNES, QUERIES, UPDATES},
+        migrate,
+        params::{self, Count, Queries},
+        schema,
+    },
     database_pg_pool::{
-        create_pool, fetch_all_fortunes, fetch_world_by_id,
-        prepare_fetch_all_fortunes_statement, prepare_fetch_world_by_id_statement,
-        prepare_update_world_by_id_statement, update_world, DatabaseClient, PgError,
+        check_pooling_mode, checkout_error, create_pool, fetch_sorted_fortunes,
+        fetch_world_by_id, fetch_worlds, load_world_cache,
+        prepare_fetch_world_by_id_statement, query_error, stream_worlds, update_worlds,
+        warm_up_pool, DatabaseClient,
     },
+    models_common::WorldId,
     models_pg_pool::{Fortune, World},
-    utils::{get_environment_variable, parse_params, random_number, Params, Utf8Html},
+    utils::{
+        exit_on_error, get_environment_variable, get_optional_environment_variable,
+        random_id, random_ids, JsonArrayWriter, JsonFast, Rng, Utf8Html,
+        WORLD_JSON_CAPACITY,
+    },
 };
 
+#[derive(Clone)]
+struct AppState {
+    pool: deadpool_postgres::Pool,
+    cache: Arc<WorldCache<World>>,
+    lru: Option<Arc<LruWorldCache<World>>>,
+}
+
+impl FromRef<AppState> for deadpool_postgres::Pool {
+    fn from_ref(state: &AppState) -> Self {
+        state.pool.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<WorldCache<World>> {
+    fn from_ref(state: &AppState) -> Self {
+        state.cache.clone()
+    }
+}
+
+impl FromRef<AppState> for Option<Arc<LruWorldCache<World>>> {
+    fn from_ref(state: &AppState) -> Self {
+        state.lru.clone()
+    }
+}
+
+#[cfg(not(feature = "html-writer"))]
 #[derive(Template)]
 #[template(path = "fortunes.html.hbs")]
 pub struct FortunesTemplate<'a> {
-    pub fortunes: &'a Vec<Fortune>,
+    pub fortunes: &'a [Fortune],
 }
 
-async fn db(DatabaseClient(client): DatabaseClient) -> impl IntoResponse {
-    let mut rng = SmallRng::from_rng(&mut thread_rng()).unwrap();
-
-    let random_id = (rng.gen::<u32>() % 10_000 + 1) as i32;
-
-    let select = prepare_fetch_world_by_id_statement(&client).await;
-    let world = fetch_world_by_id(&client, random_id, &select)
+async fn db(
+    client: DatabaseClient,
+    mut rng: Rng,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let select = prepare_fetch_world_by_id_statement(&client)
+        .await
+        .map_err(query_error)?;
+    let world = fetch_world_by_id(&client, random_id(&mut rng), &select)
         .await
-        .expect("could not fetch world");
+        .map_err(query_error)?;
+    client.finish().await.map_err(query_error)?;
 
-    (StatusCode::OK, Json(world))
+    Ok(JsonFast::new(world))
 }
 
 async fn queries(
-    DatabaseClient(client): DatabaseClient,
-    Query(params): Query<Params>,
-) -> impl IntoResponse {
-    let q = parse_params(params);
+    client: DatabaseClient,
+    mut rng: Rng,
+    Queries(q): Queries,
+) -> Result<Response, (StatusCode, String)> {
+    let ids = random_ids(&mut rng, q);
+    if params::stream_above().is_some_and(|above| q > above) {
+        return Ok(stream_queries(client, ids));
+    }
 
-    let mut rng = SmallRng::from_rng(&mut thread_rng()).unwrap();
+    let results = fetch_worlds(&client, ids).await.map_err(query_error)?;
+    client.finish().await.map_err(query_error)?;
 
-    let select = prepare_fetch_world_by_id_statement(&client).await;
+    let capacity = results.len() * WORLD_JSON_CAPACITY;
 
-    let future_worlds = FuturesUnordered::new();
+    Ok(JsonFast::with_capacity(results, capacity).into_response())
+}
 
-    for _ in 0..q {
-        let w_id = (rng.gen::<u32>() % 10_000 + 1) as i32;
+/// Writes out the worlds of `ids` as they arrive, so neither the memory a
+/// request holds nor its time to first byte grows with the count. A failed
+/// query aborts the response.
+fn stream_queries(client: DatabaseClient, ids: Vec<WorldId>) -> Response {
+    let (mut writer, response) = JsonArrayWriter::response();
+
+    tokio::spawn(async move {
+        let Ok(select) = prepare_fetch_world_by_id_statement(&client).await else {
+            writer.abort();
+            return;
+        };
+        {
+            let mut worlds = pin!(stream_worlds(&client, &select, ids));
+            while let Some(batch) = worlds.next().await {
+                let written = match batch.into_iter().collect::<Result<Vec<_>, _>>() {
+                    Ok(batch) => writer.write(&batch).await,
+                    Err(err) => Err(axum::Error::new(err)),
+                };
+                if written.is_err() {
+                    writer.abort();
+                    return;
+                }
+            }
+        }
+        if client.finish().await.is_err() {
+            writer.abort();
+            return;
+        }
 
-        future_worlds.push(fetch_world_by_id(&client, w_id, &select));
-    }
+        writer.finish().await;
+    });
+
+    response
+}
 
-    let worlds: Result<Vec<World>, PgError> = future_worlds.try_collect().await;
-    let results = worlds.expect("worlds could not be retrieved");
+async fn fortunes(
+    client: DatabaseClient,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let fortunes = fetch_sorted_fortunes(&client).await.map_err(query_error)?;
+    client.finish().await.map_err(query_error)?;
 
-    (StatusCode::OK, Json(results))
+    Ok(render_page(fortunes).await)
 }
 
-async fn fortunes(DatabaseClient(client): DatabaseClient) -> impl IntoResponse {
-    let select = prepare_fetch_all_fortunes_statement(&client).await;
+/// Renders the fortunes page without holding up the worker for long, however
+/// large the table: the writer yields now and then, and large yarte renders
+/// run on the blocking threads.
+async fn render_page(fortunes: Vec<Fortune>) -> impl IntoResponse {
+    #[cfg(feature = "html-writer")]
+    let body =
+        render_fortunes(fortunes.iter().map(|f| (f.id, f.message.as_str()))).await;
+
+    #[cfg(not(feature = "html-writer"))]
+    let body = blocking::render(fortunes, |fortunes| {
+        FortunesTemplate { fortunes }
+            .call()
+            .expect("error rendering template")
+    })
+    .await;
+
+    Utf8Html(body)
+}
 
-    let mut fortunes = fetch_all_fortunes(client, &select)
+async fn updates(
+    State(lru): State<Option<Arc<LruWorldCache<World>>>>,
+    client: DatabaseClient,
+    mut rng: Rng,
+    Queries(q): Queries,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let mut results = fetch_worlds(&client, random_ids(&mut rng, q))
         .await
-        .expect("could not fetch fortunes");
+        .map_err(query_error)?;
 
-    fortunes.push(Fortune {
-        id: 0,
-        message: "Additional fortune added at request time.".to_string(),
-    });
+    update_worlds(&client, &mut results, &mut rng)
+        .await
+        .map_err(query_error)?;
+    client.finish().await.map_err(query_error)?;
 
-    fortunes.sort_by(|a, b| a.message.cmp(&b.message));
+    if let Some(lru) = &lru {
+        lru.insert_many(results.iter().map(|world| (world.id, world.clone())));
+    }
 
-    Utf8Html(
-        FortunesTemplate {
-            fortunes: &fortunes,
-        }
-        .call()
-        .expect("error rendering template"),
-    )
+    let capacity = results.len() * WORLD_JSON_CAPACITY;
+
+    Ok(JsonFast::with_capacity(results, capacity))
 }
 
-async fn updates(
-    DatabaseClient(client): DatabaseClient,
-    Query(params): Query<Params>,
-) -> impl IntoResponse {
-    let q = parse_params(params);
+async fn cached_queries(
+    State(state): State<AppState>,
+    mut rng: Rng,
+    Count(count): Count,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let ids = random_ids(&mut rng, count);
+
+    let results = match &state.lru {
+        Some(lru) => {
+            let (mut found, missing) = lru.get_many(ids);
+            if !missing.is_empty() {
+                let client = DatabaseClient::checkout(&state.pool)
+                    .await
+                    .map_err(checkout_error)?;
+                let fetched =
+                    fetch_worlds(&client, missing).await.map_err(query_error)?;
+                client.finish().await.map_err(query_error)?;
+
+                lru.insert_many(fetched.iter().map(|world| (world.id, world.clone())));
+                found.extend(fetched);
+            }
+            found
+        }
+        None => state.cache.get_many(ids),
+    };
+
+    let capacity = results.len() * WORLD_JSON_CAPACITY;
 
-    let mut rng = SmallRng::from_entropy();
+    Ok(JsonFast::with_capacity(results, capacity))
+}
 
-    let select = prepare_fetch_world_by_id_statement(&client).await;
+#[derive(Serialize)]
+struct CacheFlush {
+    cleared: usize,
+    loaded: usize,
+    elapsed_ms: u128,
+}
 
-    let future_worlds = FuturesUnordered::new();
+async fn flush_cache(
+    _: Admin,
+    State(state): State<AppState>,
+    client: DatabaseClient,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let start = Instant::now();
 
-    for _ in 0..q {
-        let query_id = random_number(&mut rng);
+    let (mut cleared, loaded) = load_world_cache(&client, &state.cache)
+        .await
+        .map_err(query_error)?;
+    client.finish().await.map_err(query_error)?;
 
-        future_worlds.push(fetch_world_by_id(&client, query_id, &select));
+    if let Some(lru) = &state.lru {
+        cleared += lru.clear();
     }
 
-    let worlds: Result<Vec<World>, PgError> = future_worlds.try_collect().await;
-    let results = worlds.expect("worlds could not be retrieved");
+    Ok(JsonFast::new(CacheFlush {
+        cleared,
+        loaded,
+        elapsed_ms: start.elapsed().as_millis(),
+    }))
+}
 
-    let update = prepare_update_world_by_id_statement(&client).await;
+async fn cache_stats(_: Admin, State(state): State<AppState>) -> impl IntoResponse {
+    let stats = match &state.lru {
+        Some(lru) => lru.stats(),
+        None => state.cache.stats(),
+    };
 
-    let future_world_updates = FuturesUnordered::new();
+    JsonFast::new(stats)
+}
 
-    for w in &results {
-        let random_id = random_number(&mut rng);
-        let w_id = w.id;
+/// The endpoints served from the database.
+fn db_routes() -> Routes<AppState> {
+    Routes::default()
+        .serve(&FORTUNES, get(fortunes))
+        .serve(&DB, get(db))
+        .serve(&QUERIES, get(queries))
+        .serve(&UPDATES, get(updates))
+}
 
-        future_world_updates.push(update_world(&client, &update, random_id, w_id));
-    }
+/// The endpoints served from the world caches.
+fn cache_routes() -> Routes<AppState> {
+    Routes::default()
+        .serve(&CACHED_QUERIES, get(cached_queries))
+        .serve(&EVENTS, get(events::events::<World>))
+}
 
-    let world_updates: Result<Vec<u64>, PgError> =
-        future_world_updates.try_collect().await;
-    world_updates.expect("updates could not be executed");
+/// Every benchmark endpoint of the binary.
+fn routes() -> Routes<AppState> {
+    db_routes().merge(cache_routes())
+}
+
+/// Operator endpoints, outside the benchmark endpoints.
+fn admin_routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/cache/flush", post(flush_cache))
+        .route("/admin/cache/stats", get(cache_stats))
+}
 
-    (StatusCode::OK, Json(results))
+fn app(state: AppState, max_pool_size: u32) -> Router {
+    let server_header_value = HeaderValue::from_static("Axum");
+
+    routes()
+        .into_router()
+        .merge(admin_routes())
+        .with_state(state)
+        .merge(common::info::routes("postgres-pool", Some(max_pool_size)))
+        .layer(SetResponseHeaderLayer::if_not_present(
+            header::SERVER,
+            server_header_value,
+        ))
 }
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
 
+    if capabilities::requested() {
+        capabilities::print("postgres-pool", &routes());
+    }
+
+    telemetry::init();
+
     serve().await;
 }
 
 async fn serve() {
     let database_url: String = get_environment_variable("AXUM_TECHEMPOWER_DATABASE_URL");
     let max_pool_size: u32 = get_environment_variable("AXUM_TECHEMPOWER_MAX_POOL_SIZE");
+    let statement_timeout_ms: Option<u64> =
+        get_optional_environment_variable("AXUM_TECHEMPOWER_STATEMENT_TIMEOUT_MS");
+    let pool_wait_ms: Option<u64> =
+        get_optional_environment_variable("AXUM_TECHEMPOWER_POOL_WAIT_MS");
 
     // setup Client pool
-    let pool = create_pool(database_url, max_pool_size).await;
-    let server_header_value = HeaderValue::from_static("Axum");
+    let pool = create_pool(
+        database_url,
+        max_pool_size,
+        statement_timeout_ms,
+        pool_wait_ms,
+    )
+    .await;
+    if migrate::requested() {
+        let client = exit_on_error(pool.get().await, "could not connect to postgres");
+        exit_on_error(migrate::run(&client).await, "could not migrate the schema");
+    }
+    exit_on_error(
+        warm_up_pool(&pool, max_pool_size as usize).await,
+        "could not warm up postgres pool",
+    );
+    exit_on_error(
+        check_pooling_mode(&pool).await,
+        "could not use postgres pool",
+    );
+
+    let cache = Arc::new(WorldCache::default());
+    let client = exit_on_error(pool.get().await, "could not connect to postgres");
+    exit_on_error(
+        schema::check_world_index(&client).await,
+        "could not check world indexes",
+    );
+    exit_on_error(
+        load_world_cache(&client, &cache).await,
+        "could not load world cache",
+    );
+    drop(client);
+
+    let lru =
+        cache::lru_capacity().map(|capacity| Arc::new(LruWorldCache::new(capacity)));
+
+    pool_tuning::spawn(pool.clone());
+
+    #[cfg(feature = "grpc")]
+    grpc::spawn(pool.clone());
+
+    #[cfg(feature = "runtime-metrics")]
+    if let Some(lru) = &lru {
+        let lru = lru.clone();
+        metrics::register_cache(move || lru.counters());
+    }
 
-    let router = Router::new()
-        .route("/fortunes", get(fortunes))
-        .route("/db", get(db))
-        .route("/queries", get(queries))
-        .route("/updates", get(updates))
-        .with_state(pool)
-        .layer(SetResponseHeaderLayer::if_not_present(
-            header::SERVER,
-            server_header_value,
-        ));
+    #[cfg(feature = "runtime-metrics")]
+    {
+        let pool = pool.clone();
+        metrics::register_pool(move || {
+            let status = pool.status();
+            (
+                status.size.saturating_sub(status.available),
+                status.available,
+            )
+        });
+    }
+
+    let router = app(AppState { pool, cache, lru }, max_pool_size);
+
+    #[cfg(feature = "pprof")]
+    let router = router.merge(profiling::routes());
+
+    #[cfg(feature = "latency-histograms")]
+    let router = router.merge(latency::routes());
+
+    server::warm_up(&router).await;
+    let router = server::apply_limits(router);
+
+    server::spawn_raw_listener();
 
+    let serving = drain::Serving::start();
     server::builder()
-        .serve(router.into_make_service())
+        .serve(max_requests::make_service(router))
+        .with_graceful_shutdown(drain::requested())
         .await
         .unwrap();
+    serving.finished().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::common::{golden, testing};
+
+    #[cfg(not(feature = "html-writer"))]
+    #[test]
+    fn renders_fortunes_byte_for_byte() {
+        let fortunes: Vec<Fortune> = testing::FORTUNES
+            .iter()
+            .map(|&(id, message)| Fortune {
+                id,
+                message: message.to_string(),
+            })
+            .collect();
+
+        let page = FortunesTemplate {
+            fortunes: &fortunes,
+        }
+        .call()
+        .unwrap();
+        assert_eq!(page, testing::TEMPLATE_PAGE);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn plaintext_stays_responsive_while_a_large_table_renders() {
+        let fortunes: Vec<Fortune> = (0..100_000)
+            .map(|id| Fortune {
+                id,
+                message: format!("fortune <{id}> & \"friends\""),
+            })
+            .collect();
+        let plaintext: Router =
+            Router::new().route("/plaintext", get(|| async { "Hello, World!" }));
+
+        let started = Instant::now();
+        let render = tokio::spawn(async move {
+            let _ = render_page(fortunes).await.into_response();
+            started.elapsed()
+        });
+        // queued behind the render on the only worker thread
+        let request = tokio::spawn(async move {
+            let request = axum::http::Request::get("/plaintext")
+                .body(axum::body::Body::empty())
+                .unwrap();
+            let response = plaintext.oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            started.elapsed()
+        });
+
+        let (rendered, answered) = (render.await.unwrap(), request.await.unwrap());
+        assert!(
+            answered * 10 < rendered,
+            "plaintext took {answered:?} while the table rendered in {rendered:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn serializes_worlds_byte_for_byte() {
+        testing::assert_world_snapshots(|id, randomnumber| World { id, randomnumber })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn every_route_has_its_tfb_content_type() {
+        let pool = create_pool(
+            "postgres://127.0.0.1/hello_world".to_string(),
+            1,
+            None,
+            None,
+        )
+        .await;
+        let state = AppState {
+            pool,
+            cache: Arc::default(),
+            lru: None,
+        };
+        let routes = routes();
+        let served = routes.served();
+
+        testing::assert_content_types(routes.into_router().with_state(state), &served)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn answers_as_recorded() {
+        let pool = create_pool(
+            "postgres://127.0.0.1/hello_world".to_string(),
+            1,
+            None,
+            None,
+        )
+        .await;
+        let cache = WorldCache::default();
+        cache.replace(
+            testing::seeded_store()
+                .into_iter()
+                .map(|(id, randomnumber)| (id, World { id, randomnumber })),
+        );
+        let app = app(
+            AppState {
+                pool,
+                cache: Arc::new(cache),
+                lru: None,
+            },
+            1,
+        );
+
+        golden::assert_endpoint(
+            "pg-pool/cached-queries",
+            app.clone(),
+            "/cached-queries?count=5",
+        )
+        .await;
+        golden::assert_endpoint(
+            "pg-pool/cached-queries-count-0",
+            app,
+            "/cached-queries?count=0",
+        )
+        .await;
+
+        // the database endpoints, from the handlers' serializers on
+        let worlds: Vec<World> = testing::seeded_worlds(3)
+            .into_iter()
+            .map(|(id, randomnumber)| World { id, randomnumber })
+            .collect();
+        golden::assert_response("pg-pool/db", JsonFast::new(&worlds[0])).await;
+        golden::assert_response("pg-pool/queries", JsonFast::new(&worlds)).await;
+
+        let fortunes: Vec<Fortune> = testing::FORTUNES
+            .iter()
+            .map(|&(id, message)| Fortune {
+                id,
+                message: message.to_string(),
+            })
+            .collect();
+        #[cfg(not(feature = "html-writer"))]
+        let name = "pg-pool/fortunes";
+        #[cfg(feature = "html-writer")]
+        let name = "pg-pool/fortunes-writer";
+        golden::assert_response(name, render_page(fortunes).await).await;
+    }
 }
@@ -1,44 +1,81 @@
 use axum::{
-    http::{header, HeaderValue, StatusCode},
+    http::{header, HeaderValue},
     response::IntoResponse,
     routing::get,
-    Json, Router,
+    Router,
 };
 use dotenv::dotenv;
-use rand::{rngs::SmallRng, thread_rng, Rng, SeedableRng};
 use sqlx::PgPool;
 use tower_http::set_header::SetResponseHeaderLayer;
+#[cfg(not(feature = "html-writer"))]
 use yarte::Template;
 
+#[cfg(not(feature = "html-writer"))]
+mod blocking;
+mod circuit_breaker;
+mod clock;
+mod common;
+#[cfg(feature = "compression")]
+mod compression;
 mod database_sqlx;
+mod deadline;
+mod diagnostics;
+mod drain;
+mod idle_timeout;
+#[cfg(feature = "latency-histograms")]
+mod latency;
+mod max_requests;
+#[cfg(feature = "runtime-metrics")]
+mod metrics;
+mod micro_cache;
 mod models_common;
 mod models_sqlx;
+#[cfg(feature = "pprof")]
+mod profiling;
+mod rate_limit;
+mod raw;
+mod reload;
+mod request_id;
 mod server;
+mod telemetry;
+mod totals;
 mod utils;
 
+#[cfg(feature = "html-writer")]
+use self::common::html::render_fortunes;
 use self::{
-    database_sqlx::{create_pool, fetch_fortunes, fetch_world, DatabaseConnection},
+    common::{
+        capabilities,
+        endpoints::{Routes, DB, FORTUNES},
+        schema,
+        sort::sort_by_message,
+    },
+    database_sqlx::{
+        create_pool, fetch_fortunes, fetch_world, warm_up_pool, DatabaseConnection,
+    },
     models_sqlx::{Fortune, World},
-    utils::get_environment_variable,
-    utils::Utf8Html,
+    utils::{
+        exit_on_error, get_environment_variable, get_optional_environment_variable,
+        random_id, JsonFast, Rng, Utf8Html,
+    },
 };
 
+#[cfg(not(feature = "html-writer"))]
 #[derive(Template)]
 #[template(path = "fortunes.html.hbs")]
 pub struct FortunesTemplate<'a> {
-    pub fortunes: &'a Vec<Fortune>,
+    pub fortunes: &'a [Fortune],
 }
 
-async fn db(DatabaseConnection(conn): DatabaseConnection) -> impl IntoResponse {
-    let mut rng = SmallRng::from_rng(&mut thread_rng()).unwrap();
-
-    let random_id = (rng.gen::<u32>() % 10_000 + 1) as i32;
-
-    let world = fetch_world(conn, random_id)
+async fn db(
+    DatabaseConnection(conn): DatabaseConnection,
+    mut rng: Rng,
+) -> impl IntoResponse {
+    let world = fetch_world(conn, random_id(&mut rng))
         .await
         .expect("could not fetch world");
 
-    (StatusCode::OK, Json(world))
+    JsonFast::new(world)
 }
 
 async fn fortunes(DatabaseConnection(conn): DatabaseConnection) -> impl IntoResponse {
@@ -51,45 +88,145 @@ async fn fortunes(DatabaseConnection(conn): DatabaseConnection) -> impl IntoResp
         message: "Additional fortune added at request time.".to_string(),
     });
 
-    fortunes.sort_by(|a, b| a.message.cmp(&b.message));
+    sort_by_message(&mut fortunes, |fortune| &fortune.message);
 
-    Utf8Html(
-        FortunesTemplate {
-            fortunes: &fortunes,
-        }
-        .call()
-        .expect("error rendering template"),
-    )
+    #[cfg(feature = "html-writer")]
+    let body =
+        render_fortunes(fortunes.iter().map(|f| (f.id, f.message.as_str()))).await;
+
+    #[cfg(not(feature = "html-writer"))]
+    let body = blocking::render(fortunes, |fortunes| {
+        FortunesTemplate { fortunes }
+            .call()
+            .expect("error rendering template")
+    })
+    .await;
+
+    Utf8Html(body)
 }
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
 
+    if capabilities::requested() {
+        capabilities::print("postgres-sqlx", &routes());
+    }
+
+    telemetry::init();
+
     let database_url: String = get_environment_variable("AXUM_TECHEMPOWER_DATABASE_URL");
     let max_pool_size: u32 = get_environment_variable("AXUM_TECHEMPOWER_MAX_POOL_SIZE");
     let min_pool_size: u32 = get_environment_variable("AXUM_TECHEMPOWER_MIN_POOL_SIZE");
+    let statement_timeout_ms: Option<u64> =
+        get_optional_environment_variable("AXUM_TECHEMPOWER_STATEMENT_TIMEOUT_MS");
 
     // setup connection pool
-    let pool = create_pool(database_url, max_pool_size, min_pool_size).await;
+    let pool = exit_on_error(
+        create_pool(
+            database_url,
+            max_pool_size,
+            min_pool_size,
+            statement_timeout_ms,
+        )
+        .await,
+        "could not connect to postgres",
+    );
+    exit_on_error(
+        warm_up_pool(&pool, max_pool_size).await,
+        "could not warm up postgres pool",
+    );
+    exit_on_error(
+        schema::check_world_index_sqlx(&pool).await,
+        "could not check world indexes",
+    );
+
+    #[cfg(feature = "runtime-metrics")]
+    {
+        let pool = pool.clone();
+        metrics::register_pool(move || {
+            let idle = pool.num_idle();
+            ((pool.size() as usize).saturating_sub(idle), idle)
+        });
+    }
+
+    let app = router(pool)
+        .await
+        .merge(common::info::routes("postgres-sqlx", Some(max_pool_size)));
+
+    #[cfg(feature = "pprof")]
+    let app = app.merge(profiling::routes());
 
-    let app = router(pool).await;
+    #[cfg(feature = "latency-histograms")]
+    let app = app.merge(latency::routes());
 
+    server::warm_up(&app).await;
+    let app = server::apply_limits(app);
+
+    server::spawn_raw_listener();
+
+    let serving = drain::Serving::start();
     server::builder()
-        .serve(app.into_make_service())
+        .serve(max_requests::make_service(app))
+        .with_graceful_shutdown(drain::requested())
         .await
         .unwrap();
+    serving.finished().await;
+}
+
+fn routes() -> Routes<PgPool> {
+    Routes::default()
+        .serve(&FORTUNES, get(fortunes))
+        .serve(&DB, get(db))
 }
 
 async fn router(pool: PgPool) -> Router {
     let server_header_value = HeaderValue::from_static("Axum");
 
-    Router::new()
-        .route("/fortunes", get(fortunes))
-        .route("/db", get(db))
-        .with_state(pool)
-        .layer(SetResponseHeaderLayer::if_not_present(
-            header::SERVER,
-            server_header_value,
-        ))
+    routes().into_router().with_state(pool).layer(
+        SetResponseHeaderLayer::if_not_present(header::SERVER, server_header_value),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::testing;
+
+    #[cfg(not(feature = "html-writer"))]
+    #[test]
+    fn renders_fortunes_byte_for_byte() {
+        let fortunes: Vec<Fortune> = testing::FORTUNES
+            .iter()
+            .map(|&(id, message)| Fortune {
+                id,
+                message: message.to_string(),
+            })
+            .collect();
+
+        let page = FortunesTemplate {
+            fortunes: &fortunes,
+        }
+        .call()
+        .unwrap();
+        assert_eq!(page, testing::TEMPLATE_PAGE);
+    }
+
+    #[tokio::test]
+    async fn serializes_worlds_byte_for_byte() {
+        testing::assert_world_snapshots(|id, random_number| World { id, random_number })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn every_route_has_its_tfb_content_type() {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgres://127.0.0.1/hello_world")
+            .unwrap();
+        let routes = routes();
+        let served = routes.served();
+
+        testing::assert_content_types(routes.into_router().with_state(pool), &served)
+            .await;
+    }
 }
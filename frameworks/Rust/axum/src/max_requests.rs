@@ -0,0 +1,104 @@
+//! Closes keep-alive connections after `AXUM_TECHEMPOWER_MAX_REQUESTS_PER_CONNECTION`
+//! requests, to force connection churn when benchmarking the accept path.
+//!
+//! The response to the last request a connection may make carries
+//! `Connection: close`, after which hyper closes the connection. Requests are
+//! counted per connection, including those answered by the plaintext fast
+//! path. Unset, connections stay open for as long as their clients keep them.
+
+use std::{
+    convert::Infallible,
+    future::{ready, Ready},
+    sync::OnceLock,
+    task::{Context, Poll},
+};
+
+use axum::http::{header, HeaderValue, Request, Response};
+use futures_util::{future::MapOk, TryFutureExt};
+use tower::Service;
+
+use crate::utils::get_optional_environment_variable;
+
+/// Requests a connection may make, if limited.
+pub fn max_requests() -> Option<u64> {
+    static MAX_REQUESTS: OnceLock<Option<u64>> = OnceLock::new();
+
+    *MAX_REQUESTS.get_or_init(|| {
+        get_optional_environment_variable::<u64>(
+            "AXUM_TECHEMPOWER_MAX_REQUESTS_PER_CONNECTION",
+        )
+        .map(|max| max.max(1))
+    })
+}
+
+/// Makes a `CloseAfter` around `service` for every connection; pass it to
+/// hyper's `serve` in place of `into_make_service`.
+#[derive(Clone)]
+pub struct MakeService<S>(S);
+
+pub fn make_service<S>(service: S) -> MakeService<S> {
+    MakeService(service)
+}
+
+impl<S: Clone, T> Service<&T> for MakeService<S> {
+    type Response = CloseAfter<S>;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Infallible>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: &T) -> Self::Future {
+        ready(Ok(CloseAfter::new(self.0.clone(), 0)))
+    }
+}
+
+/// Service of one connection, which asks the client to close it once it has
+/// made `max_requests` requests.
+#[derive(Clone)]
+pub struct CloseAfter<S> {
+    inner: S,
+    served: u64,
+}
+
+impl<S> CloseAfter<S> {
+    /// Wraps the service of a connection that has already made `served`
+    /// requests.
+    pub fn new(inner: S, served: u64) -> Self {
+        Self { inner, served }
+    }
+}
+
+fn keep_open<B>(response: Response<B>) -> Response<B> {
+    response
+}
+
+fn close<B>(mut response: Response<B>) -> Response<B> {
+    response
+        .headers_mut()
+        .insert(header::CONNECTION, HeaderValue::from_static("close"));
+    response
+}
+
+impl<S, B, R> Service<Request<B>> for CloseAfter<S>
+where
+    S: Service<Request<B>, Response = Response<R>>,
+{
+    type Response = Response<R>;
+    type Error = S::Error;
+    type Future = MapOk<S::Future, fn(Response<R>) -> Response<R>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        self.served += 1;
+        let last = max_requests().is_some_and(|max| self.served >= max);
+
+        let respond: fn(Response<R>) -> Response<R> =
+            if last { close } else { keep_open };
+        self.inner.call(request).map_ok(respond)
+    }
+}
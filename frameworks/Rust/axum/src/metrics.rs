@@ -0,0 +1,373 @@
+//! Runtime and connection gauges, logged periodically when the
+//! `runtime-metrics` feature is enabled.
+//!
+//! The Tokio gauges (busy ratio, queue depths) are only available when built
+//! with `RUSTFLAGS="--cfg tokio_unstable"`; otherwise only connection counts
+//! are reported. Comparing the two tells runtime saturation (busy workers,
+//! growing queues) apart from database saturation (idle workers, many open
+//! connections waiting on queries). Requests shed because no pooled database
+//! connection became free in time are counted since startup.
+//!
+//! A second, process-wide line reports the requests served since the previous
+//! one: rate, p50/p99 latency up to the response head, 5xx count and, for
+//! binaries with a connection pool, its in-use and idle connections. With an
+//! LRU world cache, its hits and misses over the interval are added too.
+
+use std::{
+    cell::Cell,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        OnceLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    http::Request,
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use hyper::server::{
+    accept::Accept,
+    conn::{AddrIncoming, AddrStream},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::utils::get_environment_variable_or;
+
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static POOL_SHEDS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static REPORTER_SPAWNED: Cell<bool> = Cell::new(false);
+}
+
+/// Spawns the reporter on the current runtime, once per thread.
+///
+/// The interval is read from `AXUM_TECHEMPOWER_METRICS_INTERVAL_SECS`.
+pub fn spawn_reporter() {
+    if REPORTER_SPAWNED.with(|spawned| spawned.replace(true)) {
+        return;
+    }
+
+    let interval: u64 =
+        get_environment_variable_or("AXUM_TECHEMPOWER_METRICS_INTERVAL_SECS", 10);
+    let interval = Duration::from_secs(interval);
+
+    tokio::spawn(report(interval));
+
+    static STATS_SPAWNED: AtomicBool = AtomicBool::new(false);
+    if !STATS_SPAWNED.swap(true, Ordering::Relaxed) {
+        tokio::spawn(report_stats(interval));
+    }
+}
+
+async fn report(period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    interval.tick().await;
+
+    #[cfg(tokio_unstable)]
+    let mut runtime = RuntimeGauges::new();
+
+    loop {
+        interval.tick().await;
+
+        let connections = CONNECTIONS.load(Ordering::Relaxed);
+        let sheds = POOL_SHEDS.load(Ordering::Relaxed);
+
+        #[cfg(tokio_unstable)]
+        println!(
+            "metrics: connections={connections} pool_sheds={sheds} {}",
+            runtime.sample(period)
+        );
+
+        #[cfg(not(tokio_unstable))]
+        println!("metrics: connections={connections} pool_sheds={sheds}");
+    }
+}
+
+/// Latency histogram buckets: four per power of two microseconds.
+const BUCKETS: usize = 252;
+
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static LATENCY: [AtomicU64; BUCKETS] = [ZERO; BUCKETS];
+
+/// In-use and idle connections of the binary's database pool.
+type PoolGauge = Box<dyn Fn() -> (usize, usize) + Send + Sync>;
+
+static POOL: OnceLock<PoolGauge> = OnceLock::new();
+
+/// Adds the pool's in-use and idle connections to the stats line.
+#[allow(dead_code)]
+pub fn register_pool(gauge: impl Fn() -> (usize, usize) + Send + Sync + 'static) {
+    let _ = POOL.set(Box::new(gauge));
+}
+
+/// Hits and misses of the binary's world cache since startup.
+type CacheCounters = Box<dyn Fn() -> (u64, u64) + Send + Sync>;
+
+static CACHE: OnceLock<CacheCounters> = OnceLock::new();
+
+/// Adds the cache's hits and misses to the stats line.
+#[allow(dead_code)]
+pub fn register_cache(counters: impl Fn() -> (u64, u64) + Send + Sync + 'static) {
+    let _ = CACHE.set(Box::new(counters));
+}
+
+/// Records the requests served by `router` for the stats line.
+pub fn layer(router: Router) -> Router {
+    router.layer(middleware::from_fn(record))
+}
+
+async fn record<B>(request: Request<B>, next: Next<B>) -> Response {
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let micros = start.elapsed().as_micros().min(u64::MAX as u128) as u64;
+
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+    LATENCY[bucket(micros)].fetch_add(1, Ordering::Relaxed);
+    if response.status().is_server_error() {
+        ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    response
+}
+
+fn bucket(micros: u64) -> usize {
+    if micros < 4 {
+        return micros as usize;
+    }
+
+    let msb = 63 - micros.leading_zeros() as usize;
+    4 * (msb - 1) + ((micros >> (msb - 2)) & 3) as usize
+}
+
+/// Lower bound of `bucket`, in microseconds.
+fn bucket_floor(bucket: usize) -> u64 {
+    if bucket < 4 {
+        return bucket as u64;
+    }
+
+    (4 + (bucket % 4) as u64) << (bucket / 4 - 1)
+}
+
+async fn report_stats(period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    interval.tick().await;
+
+    let mut requests = 0;
+    let mut errors = 0;
+    let mut latency = [0; BUCKETS];
+    let mut cache = (0, 0);
+    let mut last = Instant::now();
+
+    loop {
+        interval.tick().await;
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(last).as_secs_f64();
+        last = now;
+
+        let total = REQUESTS.load(Ordering::Relaxed);
+        let total_errors = ERRORS.load(Ordering::Relaxed);
+        let served = total - requests;
+        let failed = total_errors - errors;
+        requests = total;
+        errors = total_errors;
+
+        let mut histogram = [0; BUCKETS];
+        for (bucket, count) in histogram.iter_mut().enumerate() {
+            let total = LATENCY[bucket].load(Ordering::Relaxed);
+            *count = total - latency[bucket];
+            latency[bucket] = total;
+        }
+
+        let mut line = format!(
+            "stats: rps={:.0} p50={} p99={} errors={failed}",
+            served as f64 / elapsed,
+            percentile(&histogram, served, 0.50),
+            percentile(&histogram, served, 0.99),
+        );
+        if let Some(pool) = POOL.get() {
+            let (in_use, idle) = pool();
+            line.push_str(&format!(" pool_in_use={in_use} pool_idle={idle}"));
+        }
+        if let Some(counters) = CACHE.get() {
+            let (hits, misses) = counters();
+            line.push_str(&format!(
+                " cache_hits={} cache_misses={}",
+                hits - cache.0,
+                misses - cache.1
+            ));
+            cache = (hits, misses);
+        }
+
+        println!("{line}");
+    }
+}
+
+fn percentile(histogram: &[u64], count: u64, quantile: f64) -> String {
+    if count == 0 {
+        return "-".to_string();
+    }
+
+    let rank = ((count as f64 * quantile).ceil() as u64).max(1);
+    let mut seen = 0;
+    let bucket = histogram
+        .iter()
+        .position(|&n| {
+            seen += n;
+            seen >= rank
+        })
+        .unwrap_or(BUCKETS - 1);
+
+    format!("{:.2}ms", bucket_floor(bucket) as f64 / 1000.0)
+}
+
+#[cfg(tokio_unstable)]
+struct RuntimeGauges {
+    metrics: tokio::runtime::RuntimeMetrics,
+    busy: Vec<Duration>,
+}
+
+#[cfg(tokio_unstable)]
+impl RuntimeGauges {
+    fn new() -> Self {
+        let metrics = tokio::runtime::Handle::current().metrics();
+        let busy = (0..metrics.num_workers())
+            .map(|worker| metrics.worker_total_busy_duration(worker))
+            .collect();
+
+        Self { metrics, busy }
+    }
+
+    fn sample(&mut self, period: Duration) -> String {
+        let workers = self.metrics.num_workers();
+        let mut busy = Duration::ZERO;
+        let mut local_queue = 0;
+
+        for worker in 0..workers {
+            let total = self.metrics.worker_total_busy_duration(worker);
+            busy += total.saturating_sub(self.busy[worker]);
+            self.busy[worker] = total;
+            local_queue += self.metrics.worker_local_queue_depth(worker);
+        }
+
+        let busy_ratio = busy.as_secs_f64() / (period.as_secs_f64() * workers as f64);
+
+        format!(
+            "workers={workers} busy={:.1}% tasks={} injection_queue={} \
+             local_queue={local_queue} blocking_queue={}",
+            busy_ratio * 100.0,
+            self.metrics.active_tasks_count(),
+            self.metrics.injection_queue_depth(),
+            self.metrics.blocking_queue_depth(),
+        )
+    }
+}
+
+/// Counts a request rejected because the database pool had no free connection.
+#[allow(dead_code)]
+pub fn record_pool_shed() {
+    POOL_SHEDS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts live connections for the metrics line.
+pub struct ConnectionGuard(());
+
+impl ConnectionGuard {
+    pub fn new() -> Self {
+        CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// `AddrIncoming` whose connections are counted while they are open.
+pub struct CountedIncoming(AddrIncoming);
+
+impl CountedIncoming {
+    pub fn new(incoming: AddrIncoming) -> Self {
+        Self(incoming)
+    }
+}
+
+impl Accept for CountedIncoming {
+    type Conn = CountedStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        Pin::new(&mut self.0)
+            .poll_accept(cx)
+            .map_ok(|stream| CountedStream {
+                stream,
+                _guard: ConnectionGuard::new(),
+            })
+    }
+}
+
+pub struct CountedStream {
+    stream: AddrStream,
+    _guard: ConnectionGuard,
+}
+
+impl AsyncRead for CountedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CountedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,189 @@
+//! Micro-cache in front of `/fortunes` and `/db`, enabled by setting
+//! `AXUM_TECHEMPOWER_MICRO_CACHE_TTL_MS`, e.g. to `100`. Not TFB compliant:
+//! it is meant for deployments that use this crate as a template for a real
+//! service, where serving a response that is a fraction of a second old is
+//! fine.
+//!
+//! Responses are cached by path and query for the given time. Concurrent
+//! misses for the same key are collapsed: one request runs, and the others
+//! wait for and share its response. Only 200 responses stay cached; any
+//! other is handed to the requests that waited for it but not kept. At most
+//! `MAX_ENTRIES` keys are cached, beyond which requests for new keys skip
+//! the cache.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{self, Body, Bytes},
+    http::{HeaderMap, Method, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use tokio::sync::OnceCell;
+
+use crate::{
+    clock,
+    common::endpoints::{DB, FORTUNES},
+    utils::get_optional_environment_variable,
+};
+
+const MAX_ENTRIES: usize = 1024;
+
+struct Cached {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Cached {
+    fn response(&self) -> Response {
+        let mut response = Response::new(body::boxed(Body::from(self.body.clone())));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
+/// A response that is being produced or has been, shared by every request
+/// for its key in the meantime.
+type Slot = Arc<OnceCell<Arc<Cached>>>;
+
+struct MicroCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, Slot)>>,
+}
+
+impl MicroCache {
+    /// The slot for `key`: the cached one if it is still fresh, a new one
+    /// otherwise. `None` if the cache is full.
+    fn slot(&self, key: &str) -> Option<Slot> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = clock::now();
+
+        if let Some((created, slot)) = entries.get(key) {
+            if now.duration_since(*created) < self.ttl {
+                return Some(slot.clone());
+            }
+        }
+
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(key) {
+            entries.retain(|_, (created, _)| now.duration_since(*created) < self.ttl);
+            if entries.len() >= MAX_ENTRIES {
+                return None;
+            }
+        }
+
+        let slot = Slot::default();
+        entries.insert(key.to_string(), (now, slot.clone()));
+        Some(slot)
+    }
+
+    /// Drops `slot` from the cache, unless it has been replaced already.
+    fn forget(&self, key: &str, slot: &Slot) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries
+            .get(key)
+            .is_some_and(|(_, cached)| Arc::ptr_eq(cached, slot))
+        {
+            entries.remove(key);
+        }
+    }
+}
+
+fn cache() -> Option<&'static MicroCache> {
+    static CACHE: OnceLock<Option<MicroCache>> = OnceLock::new();
+
+    CACHE
+        .get_or_init(|| {
+            let ttl_ms: u64 = get_optional_environment_variable(
+                "AXUM_TECHEMPOWER_MICRO_CACHE_TTL_MS",
+            )?;
+
+            Some(MicroCache {
+                ttl: Duration::from_millis(ttl_ms),
+                entries: Mutex::new(HashMap::new()),
+            })
+        })
+        .as_ref()
+}
+
+/// Caches the responses `router` gives to `/fortunes` and `/db`, if enabled.
+pub fn layer(router: Router) -> Router {
+    if cache().is_none() {
+        return router;
+    }
+
+    router.layer(middleware::from_fn(serve))
+}
+
+async fn serve(request: Request<Body>, next: Next<Body>) -> Response {
+    let path = request.uri().path();
+    let cacheable =
+        request.method() == Method::GET && (path == FORTUNES.path || path == DB.path);
+    let Some(cache) = cache().filter(|_| cacheable) else {
+        return next.run(request).await;
+    };
+
+    let key = request
+        .uri()
+        .path_and_query()
+        .map_or(path, |path_and_query| path_and_query.as_str())
+        .to_string();
+    let Some(slot) = cache.slot(&key) else {
+        return next.run(request).await;
+    };
+
+    let cached = slot
+        .get_or_try_init(|| async move {
+            let (parts, body) = next.run(request).await.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+
+            Ok::<_, axum::Error>(Arc::new(Cached {
+                status: parts.status,
+                headers: parts.headers,
+                body,
+            }))
+        })
+        .await;
+
+    match cached {
+        Ok(cached) => {
+            if cached.status != StatusCode::OK {
+                cache.forget(&key, &slot);
+            }
+            cached.response()
+        }
+        Err(_) => {
+            cache.forget(&key, &slot);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_expire_after_the_ttl() {
+        let (_held, clock) = clock::mock::hold();
+        let cache = MicroCache {
+            ttl: Duration::from_millis(100),
+            entries: Mutex::new(HashMap::new()),
+        };
+
+        let slot = cache.slot("/db").unwrap();
+        clock.advance(Duration::from_millis(99));
+        assert!(Arc::ptr_eq(&slot, &cache.slot("/db").unwrap()));
+
+        clock.advance(Duration::from_millis(1));
+        let fresh = cache.slot("/db").unwrap();
+        assert!(!Arc::ptr_eq(&slot, &fresh));
+        assert!(Arc::ptr_eq(&fresh, &cache.slot("/db").unwrap()));
+    }
+}
@@ -1,6 +1,112 @@
-use serde::Serialize;
+use std::{error::Error, fmt};
+
+use bytes::BytesMut;
+use mongodb::bson::Bson;
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef},
+    Decode, Encode, Postgres,
+};
+use tokio_postgres::types::{to_sql_checked, FromSql, ToSql, Type};
 
 #[derive(Serialize)]
 pub struct Message {
     pub message: &'static str,
 }
+
+/// Number of rows in the `world` table.
+#[allow(dead_code)]
+pub const WORLD_COUNT: i32 = 10_000;
+
+/// Primary key of a `World`, guaranteed to be within `1..=WORLD_COUNT`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "i32", into = "i32")]
+pub struct WorldId(i32);
+
+#[allow(dead_code)]
+impl WorldId {
+    pub fn get(self) -> i32 {
+        self.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidWorldId(pub i32);
+
+impl fmt::Display for InvalidWorldId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "world id {} is outside of 1..={WORLD_COUNT}", self.0)
+    }
+}
+
+impl Error for InvalidWorldId {}
+
+impl TryFrom<i32> for WorldId {
+    type Error = InvalidWorldId;
+
+    fn try_from(id: i32) -> Result<Self, Self::Error> {
+        if (1..=WORLD_COUNT).contains(&id) {
+            Ok(Self(id))
+        } else {
+            Err(InvalidWorldId(id))
+        }
+    }
+}
+
+impl From<WorldId> for i32 {
+    fn from(id: WorldId) -> Self {
+        id.0
+    }
+}
+
+impl From<WorldId> for Bson {
+    fn from(id: WorldId) -> Self {
+        Bson::Int32(id.0)
+    }
+}
+
+impl ToSql for WorldId {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<tokio_postgres::types::IsNull, Box<dyn Error + Sync + Send>> {
+        self.0.to_sql(ty, out)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <i32 as ToSql>::accepts(ty)
+    }
+
+    to_sql_checked!();
+}
+
+impl<'a> FromSql<'a> for WorldId {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Ok(Self::try_from(i32::from_sql(ty, raw)?)?)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <i32 as FromSql>::accepts(ty)
+    }
+}
+
+impl sqlx::Type<Postgres> for WorldId {
+    fn type_info() -> PgTypeInfo {
+        <i32 as sqlx::Type<Postgres>>::type_info()
+    }
+}
+
+impl Encode<'_, Postgres> for WorldId {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+        <i32 as Encode<Postgres>>::encode_by_ref(&self.0, buf)
+    }
+}
+
+impl Decode<'_, Postgres> for WorldId {
+    fn decode(value: PgValueRef<'_>) -> Result<Self, BoxDynError> {
+        Ok(Self::try_from(<i32 as Decode<Postgres>>::decode(value)?)?)
+    }
+}
@@ -1,7 +1,33 @@
-use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
 
+use futures_util::{stream::FuturesUnordered, TryFutureExt, TryStreamExt};
+use mongodb::{
+    bson::{doc, from_document, to_document, Document, RawBsonRef},
+    options::{ClientOptions, FindOneOptions, FindOptions, Hint},
+    Client, Database,
+};
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+
+use crate::{
+    common::tables,
+    deadline,
+    models_common::WorldId,
+    utils::get_optional_environment_variable,
+    write_batch::{self, BatchError, WriteBatcher, Writers},
+};
+
+/// `fortune` document as stored in MongoDB.
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct Fortune {
+    #[serde(rename = "_id")]
     pub id: i32,
     pub message: String,
 }
@@ -12,9 +38,690 @@ pub struct FortuneInfo {
     pub message: String,
 }
 
+/// `world` document as stored in MongoDB.
+///
+/// Both fields accept any of the integer encodings `bson_integer` does.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct WorldDocument {
+    #[serde(rename = "_id", deserialize_with = "world_id")]
+    pub id: WorldId,
+    #[serde(rename = "randomNumber", deserialize_with = "integer")]
+    pub random_number: i32,
+}
+
+/// `World` as rendered in JSON responses.
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct World {
-    pub id: i32,
+    pub id: WorldId,
     #[serde(rename = "randomNumber")]
     pub random_number: i32,
 }
+
+impl From<WorldDocument> for World {
+    fn from(doc: WorldDocument) -> Self {
+        Self {
+            id: doc.id,
+            random_number: doc.random_number,
+        }
+    }
+}
+
+/// Counts the server reported for an `update` command.
+///
+/// `modified` can trail `matched` without anything being wrong: a world drawn
+/// twice, or given the random number it already had, matches but isn't
+/// modified.
+#[derive(Clone, Debug, Deserialize)]
+pub struct UpdateOutcome {
+    #[serde(rename = "n")]
+    pub matched: u64,
+    #[serde(rename = "nModified")]
+    pub modified: u64,
+    #[serde(rename = "writeErrors", default)]
+    pub write_errors: Vec<Document>,
+}
+
+/// Filter matching a single `world` document by primary key, and by the
+/// shard key too if `world` is sharded on `id`.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct WorldFilter {
+    #[serde(rename = "_id")]
+    id: WorldId,
+    #[serde(rename = "id", skip_serializing_if = "Option::is_none")]
+    shard_id: Option<WorldId>,
+}
+
+impl WorldFilter {
+    pub fn new(id: WorldId) -> Self {
+        Self {
+            id,
+            shard_id: (shard_key() == Some(ShardKey::Id)).then_some(id),
+        }
+    }
+}
+
+/// Field the `world` collection is sharded on, when benchmarking against
+/// mongos rather than a single mongod, from
+/// `AXUM_TECHEMPOWER_MONGODB_SHARD_KEY`: `_id`, or `id`, which the TFB setup
+/// scripts seed with the same value. Unset means `world` isn't sharded.
+///
+/// Every lookup and update matches one document by its full shard key, so
+/// mongos routes it to a single shard whether the key is hashed
+/// (`{_id: "hashed"}`) or ranged (`{_id: 1}`). The difference is in how load
+/// spreads: hashed keys distribute the ids over all shards right away, while
+/// ranged ones keep neighbouring ids together and leave everything on one
+/// shard until the balancer has split the chunks, so pre-split ranged
+/// collections before measuring.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShardKey {
+    /// Sharded on `_id`, which every filter already includes.
+    Primary,
+    /// Sharded on `id`, which filters then include alongside `_id`.
+    Id,
+}
+
+pub fn shard_key() -> Option<ShardKey> {
+    static SHARD_KEY: OnceLock<Option<ShardKey>> = OnceLock::new();
+
+    *SHARD_KEY.get_or_init(|| {
+        let key: String =
+            get_optional_environment_variable("AXUM_TECHEMPOWER_MONGODB_SHARD_KEY")?;
+
+        match key.as_str() {
+            "_id" => Some(ShardKey::Primary),
+            "id" => Some(ShardKey::Id),
+            _ => panic!(
+                "AXUM_TECHEMPOWER_MONGODB_SHARD_KEY must be _id or id, got {key:?}"
+            ),
+        }
+    })
+}
+
+/// Adjusts `options` for a sharded `world`: turns on retryable writes, which
+/// mongos supports, unless the URL already decides. They cover the driver's
+/// own write helpers; see `update_worlds` for the batched `update` command.
+pub fn configure_for_sharding(options: &mut ClientOptions) {
+    if shard_key().is_some() && options.retry_writes.is_none() {
+        options.retry_writes = Some(true);
+    }
+}
+
+/// The `hello_world` database, through a client for reads and one for writes.
+///
+/// With `AXUM_TECHEMPOWER_MONGODB_WRITE_POOL_SIZE` set, updates go through a
+/// second client with a pool of that size, so a burst of `/updates` can't
+/// take the connections `/db`, `/queries` and `/fortunes` need; both pools
+/// then count towards the server's connection limit. Unset, both are the
+/// same client.
+///
+/// With `AXUM_TECHEMPOWER_UPDATE_WRITERS` set, there is also a client with a
+/// single connection for each writer task, see `write_batch`. Those are
+/// created once and shared by every worker of the process.
+#[derive(Clone)]
+pub struct Databases {
+    read: Database,
+    write: Database,
+    writers: Option<Arc<[Database]>>,
+}
+
+impl Databases {
+    /// Connects with `options`, which size the read pool.
+    pub fn connect(options: ClientOptions) -> mongodb::error::Result<Self> {
+        let write_pool_size: Option<u32> = get_optional_environment_variable(
+            "AXUM_TECHEMPOWER_MONGODB_WRITE_POOL_SIZE",
+        );
+
+        let write_options = write_pool_size.map(|size| {
+            let mut options = options.clone();
+            options.max_pool_size = Some(size);
+            options.min_pool_size = options.min_pool_size.map(|min| min.min(size));
+            options
+        });
+
+        let writers = write_batch::writers()
+            .map(|writers| writer_databases(&options, writers))
+            .transpose()?;
+
+        let read = Client::with_options(options)?.database("hello_world");
+        let write = match write_options {
+            Some(options) => Client::with_options(options)?.database("hello_world"),
+            None => read.clone(),
+        };
+
+        Ok(Self {
+            read,
+            write,
+            writers,
+        })
+    }
+
+    pub fn read(&self) -> Database {
+        self.read.clone()
+    }
+
+    pub fn write(&self) -> Database {
+        self.write.clone()
+    }
+
+    /// One database per writer task, if they are on.
+    pub fn writers(&self) -> Option<&[Database]> {
+        self.writers.as_deref()
+    }
+}
+
+fn writer_databases(
+    options: &ClientOptions,
+    writers: usize,
+) -> mongodb::error::Result<Arc<[Database]>> {
+    static WRITERS: Mutex<Option<Arc<[Database]>>> = Mutex::new(None);
+
+    let mut shared = WRITERS.lock().unwrap();
+    if let Some(databases) = &*shared {
+        return Ok(databases.clone());
+    }
+
+    let mut options = options.clone();
+    options.max_pool_size = Some(1);
+    options.min_pool_size = Some(1);
+
+    let databases = (0..writers)
+        .map(|_| Ok(Client::with_options(options.clone())?.database("hello_world")))
+        .collect::<mongodb::error::Result<Arc<[Database]>>>()?;
+    *shared = Some(databases.clone());
+    Ok(databases)
+}
+
+/// Options for the `world` lookups, to keep the planner on the primary key
+/// index on clusters where profiling shows collection scans.
+///
+/// `AXUM_TECHEMPOWER_MONGODB_WORLD_HINT` names the field of the index to hint,
+/// e.g. `_id` for `{_id: 1}`, and `AXUM_TECHEMPOWER_MONGODB_MAX_TIME_MS` bounds
+/// the server-side time of each lookup, as does the time left until the
+/// request's deadline. `None` if none of them is set.
+pub fn world_find_options() -> Option<FindOneOptions> {
+    static OPTIONS: OnceLock<Option<FindOneOptions>> = OnceLock::new();
+
+    let configured = OPTIONS.get_or_init(|| {
+        let hint: Option<String> =
+            get_optional_environment_variable("AXUM_TECHEMPOWER_MONGODB_WORLD_HINT");
+        let max_time_ms: Option<u64> =
+            get_optional_environment_variable("AXUM_TECHEMPOWER_MONGODB_MAX_TIME_MS");
+
+        if hint.is_none() && max_time_ms.is_none() {
+            return None;
+        }
+
+        let options = FindOneOptions::builder()
+            .hint(hint.map(|field| Hint::Keys(doc! { field: 1 })))
+            .max_time(max_time_ms.map(Duration::from_millis))
+            .build();
+        Some(options)
+    });
+
+    let Some(budget) = deadline_budget() else {
+        return configured.clone();
+    };
+
+    let mut options = configured.clone().unwrap_or_default();
+    options.max_time = Some(options.max_time.map_or(budget, |max| max.min(budget)));
+    Some(options)
+}
+
+/// Options for the `fortune` query, bounding it by the request's deadline.
+#[allow(dead_code)]
+pub fn fortune_find_options() -> Option<FindOptions> {
+    let budget = deadline_budget()?;
+
+    Some(FindOptions::builder().max_time(budget).build())
+}
+
+/// Time left for the current request, at least 1ms since a `max_time_ms` of
+/// 0 means no limit.
+fn deadline_budget() -> Option<Duration> {
+    Some(deadline::remaining()?.max(Duration::from_millis(1)))
+}
+
+/// `update` command writing new random numbers to `world` documents.
+#[derive(Debug, Serialize)]
+pub struct UpdateWorlds {
+    update: &'static str,
+    updates: Vec<WorldUpdate>,
+    ordered: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct WorldUpdate {
+    q: WorldFilter,
+    u: SetRandomNumber,
+}
+
+#[derive(Debug, Serialize)]
+struct SetRandomNumber {
+    #[serde(rename = "$set")]
+    set: RandomNumber,
+}
+
+#[derive(Debug, Serialize)]
+struct RandomNumber {
+    #[serde(rename = "randomNumber")]
+    random_number: i32,
+}
+
+impl FromIterator<World> for UpdateWorlds {
+    fn from_iter<I: IntoIterator<Item = World>>(worlds: I) -> Self {
+        let updates = worlds
+            .into_iter()
+            .map(|world| WorldUpdate {
+                q: WorldFilter::new(world.id),
+                u: SetRandomNumber {
+                    set: RandomNumber {
+                        random_number: world.random_number,
+                    },
+                },
+            })
+            .collect();
+
+        Self {
+            update: tables::world(),
+            updates,
+            ordered: false,
+        }
+    }
+}
+
+/// Runs `lookup` for all `ids` concurrently, for the `find_worlds` of both
+/// Mongo backends.
+///
+/// The lookups are polled by the returned future rather than spawned, so when
+/// the request is dropped (e.g. the client disconnected) every query still in
+/// flight is dropped with it, and the first failing lookup cancels the rest.
+/// The driver closes connections dropped mid-operation instead of returning
+/// them to the pool, so an abandoned request never holds a connection.
+pub async fn find_all<T, E, F, Fut>(ids: Vec<WorldId>, lookup: F) -> Result<Vec<T>, E>
+where
+    F: FnMut(WorldId) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    ids.into_iter()
+        .map(lookup)
+        .collect::<FuturesUnordered<_>>()
+        .try_collect()
+        .await
+}
+
+/// Writes the random numbers of `worlds` back in one `update` command, for
+/// both Mongo backends.
+///
+/// Statements that matched no document or failed are logged; the counts are
+/// returned either way. The driver never retries `run_command`, so on a
+/// sharded cluster a failed command is retried once here, which is safe since
+/// it only sets absolute values.
+pub async fn update_worlds(
+    db: Database,
+    worlds: Vec<World>,
+) -> mongodb::error::Result<UpdateOutcome> {
+    let expected = worlds.len() as u64;
+    let command = to_document(&worlds.into_iter().collect::<UpdateWorlds>())?;
+
+    let retry = shard_key().is_some().then(|| command.clone());
+    crate::totals::db_write();
+    let reply = match (db.run_command(command, None).await, retry) {
+        (Err(err), Some(command)) => {
+            eprintln!("update failed, retrying once: {err}");
+            crate::totals::db_write();
+            db.run_command(command, None).await?
+        }
+        (reply, _) => reply?,
+    };
+    let outcome: UpdateOutcome = from_document(reply)?;
+
+    if outcome.matched != expected || !outcome.write_errors.is_empty() {
+        eprintln!(
+            "update matched {} of {expected} worlds ({} write errors)",
+            outcome.matched,
+            outcome.write_errors.len()
+        );
+    }
+
+    Ok(outcome)
+}
+
+thread_local! {
+    static UPDATE_BATCHER: Option<WriteBatcher<World>> =
+        write_batch::window().map(WriteBatcher::new);
+}
+
+static UPDATE_WRITERS: OnceLock<Option<Writers<World>>> = OnceLock::new();
+
+/// `update_worlds`, through the writer tasks if
+/// `AXUM_TECHEMPOWER_UPDATE_WRITERS` is set, or coalesced with the updates of
+/// concurrent requests on this worker if
+/// `AXUM_TECHEMPOWER_UPDATE_BATCH_WINDOW_US` is, see `write_batch`.
+pub async fn update_worlds_batched(
+    db: &Databases,
+    worlds: Vec<World>,
+) -> Result<(), BatchError> {
+    let writers = UPDATE_WRITERS.get_or_init(|| {
+        let databases = db.writers()?.to_vec();
+        Some(Writers::spawn(databases.into_iter().map(|db| {
+            move |worlds| update_worlds(db.clone(), worlds).map_ok(drop)
+        })))
+    });
+    if let Some(writers) = writers {
+        return writers.write(worlds).await;
+    }
+
+    let db = db.write();
+    let Some(batcher) = UPDATE_BATCHER.with(Clone::clone) else {
+        return update_worlds(db, worlds)
+            .await
+            .map(drop)
+            .map_err(|err| err.to_string().into());
+    };
+
+    batcher
+        .write(worlds, move |worlds| {
+            update_worlds(db.clone(), worlds).map_ok(drop)
+        })
+        .await
+}
+
+/// Integer stored as BSON int32, int64 or an integral double. The TFB setup
+/// scripts insert the worlds from JavaScript, where every number is a double,
+/// while the JSON responses must carry integers.
+pub fn bson_integer(value: RawBsonRef<'_>) -> Option<i32> {
+    match value {
+        RawBsonRef::Int32(n) => Some(n),
+        RawBsonRef::Int64(n) => n.try_into().ok(),
+        RawBsonRef::Double(n) => double_to_i32(n),
+        _ => None,
+    }
+}
+
+fn double_to_i32(n: f64) -> Option<i32> {
+    let in_range = n >= f64::from(i32::MIN) && n <= f64::from(i32::MAX);
+
+    (in_range && n.fract() == 0.0).then_some(n as i32)
+}
+
+fn integer<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i32, D::Error> {
+    struct IntegerVisitor;
+
+    impl Visitor<'_> for IntegerVisitor {
+        type Value = i32;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("an int32, int64 or integral double")
+        }
+
+        fn visit_i32<E: de::Error>(self, n: i32) -> Result<i32, E> {
+            Ok(n)
+        }
+
+        fn visit_i64<E: de::Error>(self, n: i64) -> Result<i32, E> {
+            bson_integer(RawBsonRef::Int64(n))
+                .ok_or_else(|| E::invalid_value(de::Unexpected::Signed(n), &self))
+        }
+
+        fn visit_f64<E: de::Error>(self, n: f64) -> Result<i32, E> {
+            bson_integer(RawBsonRef::Double(n))
+                .ok_or_else(|| E::invalid_value(de::Unexpected::Float(n), &self))
+        }
+    }
+
+    deserializer.deserialize_any(IntegerVisitor)
+}
+
+fn world_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<WorldId, D::Error> {
+    WorldId::try_from(integer(deserializer)?).map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures_util::FutureExt;
+    use mongodb::bson::{self, from_document, Bson, RawDocumentBuf};
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::models_common::WORLD_COUNT;
+
+    /// Decodes `document` as read from `world`, which must give either a
+    /// valid world or a deserialization error.
+    fn decode(document: Document) -> Result<World, bson::de::Error> {
+        let decoded = from_document::<WorldDocument>(document.clone()).map(World::from);
+
+        match &decoded {
+            Ok(world) => assert!(
+                (1..=WORLD_COUNT).contains(&world.id.get()),
+                "{document} decoded to {world:?}"
+            ),
+            Err(err) => assert!(
+                matches!(err, bson::de::Error::DeserializationError { .. }),
+                "{document} failed with {err:?}"
+            ),
+        }
+        decoded
+    }
+
+    #[test]
+    fn documents_with_integral_fields_of_any_type_decode() {
+        let documents = [
+            doc! { "_id": 42, "randomNumber": 4174 },
+            doc! { "_id": 42_i64, "randomNumber": 4174_i64 },
+            doc! { "_id": 42.0, "randomNumber": 4174.0 },
+            doc! { "_id": 42, "randomNumber": 4174_i64 },
+            doc! { "_id": 42.0, "id": 42.0, "randomNumber": 4174 },
+            doc! { "randomNumber": 4174, "extra": "field", "_id": 42 },
+        ];
+
+        for document in documents {
+            let world = decode(document).unwrap();
+            assert_eq!((world.id.get(), world.random_number), (42, 4174));
+        }
+    }
+
+    #[test]
+    fn documents_with_bad_fields_fail_to_decode() {
+        let documents = [
+            doc! { "_id": 42.5, "randomNumber": 4174 },
+            doc! { "_id": 42, "randomNumber": 4174.5 },
+            doc! { "_id": 42, "randomNumber": i64::from(i32::MAX) + 1 },
+            doc! { "_id": i64::MAX, "randomNumber": 4174 },
+            doc! { "_id": 0, "randomNumber": 4174 },
+            doc! { "_id": WORLD_COUNT + 1, "randomNumber": 4174 },
+            doc! { "_id": 42 },
+            doc! { "randomNumber": 4174 },
+            doc! {},
+            doc! { "_id": "42", "randomNumber": 4174 },
+            doc! { "_id": 42, "randomNumber": Bson::Null },
+        ];
+
+        for document in documents {
+            assert!(decode(document.clone()).is_err(), "{document} decoded");
+        }
+    }
+
+    /// A field value of one of the stored types, or of another one, with the
+    /// integer it must decode to, if any.
+    fn random_value(rng: &mut SmallRng) -> (Bson, Option<i64>) {
+        let n: i64 = match rng.gen_range(0..3) {
+            0 => rng.gen_range(-2..=WORLD_COUNT as i64 + 2),
+            1 => rng.gen_range(i64::from(i32::MIN) - 2..=i64::from(i32::MIN) + 2),
+            _ => rng.gen(),
+        };
+
+        match rng.gen_range(0..6) {
+            0 => (Bson::Int32(n as i32), Some(i64::from(n as i32))),
+            1 => (Bson::Int64(n), Some(n)),
+            2 => (Bson::Double(n as f64), Some(n as f64 as i64)),
+            3 => (Bson::Double(n as f64 + 0.5), None),
+            4 => (Bson::String(n.to_string()), None),
+            _ => (Bson::Boolean(true), None),
+        }
+    }
+
+    #[test]
+    fn random_documents_decode_or_fail_cleanly() {
+        let mut rng = SmallRng::seed_from_u64(0);
+
+        for _ in 0..10_000 {
+            let mut document = Document::new();
+            let mut fields = [None, None];
+
+            for (field, key) in fields.iter_mut().zip(["_id", "randomNumber"]) {
+                if rng.gen_ratio(1, 10) {
+                    continue;
+                }
+                let (value, n) = random_value(&mut rng);
+                document.insert(key, value);
+                *field = n.and_then(|n| i32::try_from(n).ok());
+            }
+            if rng.gen() {
+                document.insert("id", rng.gen::<i32>());
+            }
+
+            let [id, random_number] = fields;
+            let id = id.filter(|id| (1..=WORLD_COUNT).contains(id));
+            let expected = id.zip(random_number);
+
+            let decoded = decode(document.clone())
+                .ok()
+                .map(|world| (world.id.get(), world.random_number));
+            assert_eq!(decoded, expected, "decoding {document}");
+        }
+    }
+
+    /// Stored `randomNumber`s and what they must decode to.
+    fn stored_numbers() -> Vec<(Bson, Option<i32>)> {
+        vec![
+            (Bson::Int32(4174), Some(4174)),
+            (Bson::Int32(i32::MIN), Some(i32::MIN)),
+            (Bson::Int64(4174), Some(4174)),
+            (Bson::Int64(i64::from(i32::MAX)), Some(i32::MAX)),
+            (Bson::Int64(i64::from(i32::MAX) + 1), None),
+            (Bson::Int64(i64::MIN), None),
+            (Bson::Double(4174.0), Some(4174)),
+            (Bson::Double(-0.0), Some(0)),
+            (Bson::Double(f64::from(i32::MIN)), Some(i32::MIN)),
+            (Bson::Double(4174.5), None),
+            (Bson::Double(f64::from(i32::MAX) + 1.0), None),
+            (Bson::Double(f64::NAN), None),
+            (Bson::Double(f64::INFINITY), None),
+            (Bson::String("4174".to_string()), None),
+        ]
+    }
+
+    #[test]
+    fn serde_path_accepts_integral_numbers_of_every_stored_type() {
+        for (stored, expected) in stored_numbers() {
+            let decoded = from_document::<WorldDocument>(
+                doc! { "_id": 1, "randomNumber": stored.clone() },
+            );
+
+            assert_eq!(
+                decoded.ok().map(|world| world.random_number),
+                expected,
+                "decoding {stored:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn raw_path_accepts_integral_numbers_of_every_stored_type() {
+        for (stored, expected) in stored_numbers() {
+            let raw =
+                RawDocumentBuf::from_document(&doc! { "randomNumber": stored.clone() })
+                    .unwrap();
+            let value = raw.get("randomNumber").unwrap().unwrap();
+
+            assert_eq!(bson_integer(value), expected, "decoding {stored:?}");
+        }
+    }
+
+    #[test]
+    fn json_always_carries_an_integer() {
+        let world: World =
+            from_document::<WorldDocument>(doc! { "_id": 1.0, "randomNumber": 4174.0 })
+                .unwrap()
+                .into();
+
+        assert_eq!(
+            serde_json::to_string(&world).unwrap(),
+            r#"{"id":1,"randomNumber":4174}"#
+        );
+    }
+
+    /// Counts the lookups that were started and those that were dropped.
+    #[derive(Default)]
+    struct Lookups {
+        started: AtomicUsize,
+        dropped: AtomicUsize,
+    }
+
+    struct Guard<'a>(&'a Lookups);
+
+    impl Drop for Guard<'_> {
+        fn drop(&mut self) {
+            self.0.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl Lookups {
+        /// A lookup that never finishes, or fails right away for `failing`.
+        async fn lookup(
+            &self,
+            id: WorldId,
+            failing: Option<i32>,
+        ) -> Result<World, WorldId> {
+            let _guard = Guard(self);
+            self.started.fetch_add(1, Ordering::Relaxed);
+
+            if Some(id.get()) == failing {
+                return Err(id);
+            }
+            std::future::pending().await
+        }
+    }
+
+    fn ids(ids: impl IntoIterator<Item = i32>) -> Vec<WorldId> {
+        ids.into_iter()
+            .map(|id| WorldId::try_from(id).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn dropping_the_fan_out_drops_every_lookup() {
+        let lookups = Lookups::default();
+
+        let fan_out = find_all(ids(1..=20), |id| lookups.lookup(id, None));
+        assert!(fan_out.now_or_never().is_none());
+
+        assert_eq!(lookups.started.load(Ordering::Relaxed), 20);
+        assert_eq!(lookups.dropped.load(Ordering::Relaxed), 20);
+    }
+
+    #[test]
+    fn the_first_failure_cancels_the_other_lookups() {
+        let lookups = Lookups::default();
+
+        let fan_out = find_all(ids(1..=20), |id| lookups.lookup(id, Some(7)));
+        let failed = fan_out.now_or_never().expect("fan-out still pending");
+
+        assert_eq!(failed.map(drop), Err(WorldId::try_from(7).unwrap()));
+        assert_eq!(
+            lookups.dropped.load(Ordering::Relaxed),
+            lookups.started.load(Ordering::Relaxed)
+        );
+    }
+
+    #[tokio::test]
+    async fn serializes_worlds_byte_for_byte() {
+        crate::common::testing::assert_world_snapshots(|id, random_number| World {
+            id,
+            random_number,
+        })
+        .await;
+    }
+}
@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::models_common::WorldId;
+
 #[allow(non_snake_case)]
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct Fortune {
@@ -10,7 +12,7 @@ pub struct Fortune {
 #[allow(non_snake_case)]
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct World {
-    pub id: i32,
+    pub id: WorldId,
     #[serde(rename = "randomNumber")]
     pub randomnumber: i32,
 }
@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use tokio_pg_mapper_derive::PostgresMapper;
 
+use crate::models_common::WorldId;
+
 #[allow(non_snake_case)]
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, PostgresMapper)]
 #[pg_mapper(table = "Fortune")]
@@ -13,7 +15,7 @@ pub struct Fortune {
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, PostgresMapper)]
 #[pg_mapper(table = "World")]
 pub struct World {
-    pub id: i32,
+    pub id: WorldId,
     #[serde(rename = "randomNumber")]
     pub randomnumber: i32,
 }
@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
+use crate::models_common::WorldId;
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, FromRow)]
 pub struct Fortune {
     pub id: i32,
@@ -9,7 +11,7 @@ pub struct Fortune {
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, FromRow)]
 pub struct World {
-    pub id: i32,
+    pub id: WorldId,
     #[sqlx(rename = "randomnumber")]
     #[serde(rename = "randomNumber")]
     pub random_number: i32,
@@ -0,0 +1,182 @@
+//! Experimental pool size tuning for `axum-pg-pool`, enabled by setting
+//! `AXUM_TECHEMPOWER_POOL_TUNING_MAX`, for finding a good pool size for the
+//! hardware at hand without a grid search over `AXUM_TECHEMPOWER_MAX_POOL_SIZE`.
+//!
+//! Every `AXUM_TECHEMPOWER_POOL_TUNING_INTERVAL_MS` (default 5000) the
+//! controller looks at the checkouts of the interval: how long they waited
+//! for a free connection, and how long a request took from asking for one to
+//! giving it back. If waiting took at least a tenth of that, the pool grows
+//! by a quarter; if it took less than a hundredth, the pool shrinks by a
+//! quarter, so it doesn't keep connections that only add contention on the
+//! database. A change after which latency rose by more than a tenth is taken
+//! back, and the size it came from becomes the new bound in that direction.
+//! The size stays between `AXUM_TECHEMPOWER_POOL_TUNING_MIN` (default 1) and
+//! the maximum, starting from `AXUM_TECHEMPOWER_MAX_POOL_SIZE`. Every change
+//! is logged; once the load is steady the last one logged is the size to use.
+//!
+//! Latency moves with the load too, so the controller assumes the load stays
+//! the same while it works, as it does under a benchmark's steady phase.
+//! `axum-mongo` can't take part: the driver's pool size is fixed when the
+//! client is created.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use crate::utils::{get_environment_variable_or, get_optional_environment_variable};
+
+/// Intervals with fewer checkouts than this say too little to act on.
+const MIN_CHECKOUTS: u64 = 100;
+
+/// A latency this much above the one before a change takes the change back.
+const WORSE: f64 = 1.1;
+
+static CHECKOUTS: AtomicU64 = AtomicU64::new(0);
+static WAIT_NANOS: AtomicU64 = AtomicU64::new(0);
+static HELD_NANOS: AtomicU64 = AtomicU64::new(0);
+
+struct Settings {
+    min: usize,
+    max: usize,
+    interval: Duration,
+}
+
+fn settings() -> Option<&'static Settings> {
+    static SETTINGS: OnceLock<Option<Settings>> = OnceLock::new();
+
+    SETTINGS
+        .get_or_init(|| {
+            let max: usize =
+                get_optional_environment_variable("AXUM_TECHEMPOWER_POOL_TUNING_MAX")?;
+            let min: usize =
+                get_environment_variable_or("AXUM_TECHEMPOWER_POOL_TUNING_MIN", 1);
+            let interval_ms: u64 = get_environment_variable_or(
+                "AXUM_TECHEMPOWER_POOL_TUNING_INTERVAL_MS",
+                5000,
+            );
+
+            Some(Settings {
+                min: min.clamp(1, max.max(1)),
+                max: max.max(1),
+                interval: Duration::from_millis(interval_ms.max(1)),
+            })
+        })
+        .as_ref()
+}
+
+/// Whether checkouts should be timed for the controller.
+pub fn enabled() -> bool {
+    settings().is_some()
+}
+
+/// Counts a checkout that waited `wait` for its connection and then held it
+/// for `held`.
+pub fn record(wait: Duration, held: Duration) {
+    CHECKOUTS.fetch_add(1, Ordering::Relaxed);
+    WAIT_NANOS.fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+    HELD_NANOS.fetch_add(held.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// A size change, and the latency seen before it.
+struct Change {
+    from: usize,
+    latency: f64,
+}
+
+struct Controller {
+    floor: usize,
+    ceiling: usize,
+    last: Option<Change>,
+}
+
+impl Controller {
+    /// The size to switch to after an interval at `size` in which checkouts
+    /// waited `wait` and took `latency` on average, if any.
+    fn decide(&mut self, size: usize, wait: f64, latency: f64) -> Option<usize> {
+        if let Some(change) = self.last.take() {
+            if latency > change.latency * WORSE {
+                println!(
+                    "pool tuning: {size} -> {} connections, latency rose from {:.2}ms \
+                     to {:.2}ms",
+                    change.from,
+                    change.latency * 1e3,
+                    latency * 1e3
+                );
+                if change.from < size {
+                    self.ceiling = change.from;
+                } else {
+                    self.floor = change.from;
+                }
+                return Some(change.from);
+            }
+        }
+
+        let step = (size / 4).max(1);
+        let to = if wait * 10.0 >= latency && size < self.ceiling {
+            (size + step).min(self.ceiling)
+        } else if wait * 100.0 < latency && size > self.floor {
+            size.saturating_sub(step).max(self.floor)
+        } else {
+            return None;
+        };
+
+        println!(
+            "pool tuning: {size} -> {to} connections, checkouts waited {:.2}ms of \
+             {:.2}ms",
+            wait * 1e3,
+            latency * 1e3
+        );
+        self.last = Some(Change {
+            from: size,
+            latency,
+        });
+        Some(to)
+    }
+}
+
+/// Starts the controller on `pool`, if enabled.
+pub fn spawn(pool: deadpool_postgres::Pool) {
+    let Some(settings) = settings() else {
+        return;
+    };
+
+    let mut size = pool.status().max_size.clamp(settings.min, settings.max);
+    pool.resize(size);
+    println!(
+        "pool tuning: starting at {size} connections, between {} and {}",
+        settings.min, settings.max
+    );
+
+    let mut controller = Controller {
+        floor: settings.min,
+        ceiling: settings.max,
+        last: None,
+    };
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(settings.interval);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let checkouts = CHECKOUTS.swap(0, Ordering::Relaxed);
+            let wait = WAIT_NANOS.swap(0, Ordering::Relaxed);
+            let held = HELD_NANOS.swap(0, Ordering::Relaxed);
+            if checkouts < MIN_CHECKOUTS {
+                continue;
+            }
+
+            let wait = wait as f64 / checkouts as f64 / 1e9;
+            let latency = wait + held as f64 / checkouts as f64 / 1e9;
+            if let Some(to) = controller.decide(size, wait, latency) {
+                size = to;
+                pool.resize(size);
+            }
+        }
+    });
+}
@@ -0,0 +1,81 @@
+//! CPU profiling endpoint, enabled with the `pprof` feature.
+//!
+//! `GET /debug/pprof/profile?seconds=N` samples the whole process for `N`
+//! seconds and returns a flamegraph SVG, or a protobuf profile readable by
+//! `go tool pprof` when `format=proto` is passed.
+
+use std::time::Duration;
+
+use axum::{
+    extract::Query,
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use pprof::{protos::Message, ProfilerGuardBuilder};
+use serde::Deserialize;
+
+const MAX_SECONDS: u64 = 300;
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileParams {
+    seconds: Option<u64>,
+    format: Option<String>,
+}
+
+pub fn routes<S>() -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new().route("/debug/pprof/profile", get(profile))
+}
+
+async fn profile(Query(params): Query<ProfileParams>) -> Response {
+    let seconds = params.seconds.unwrap_or(10).clamp(1, MAX_SECONDS);
+    let proto = params.format.as_deref() == Some("proto");
+
+    // the sampling runs on a blocking thread, keeping the workers free to serve
+    // the load being profiled
+    let result = tokio::task::spawn_blocking(move || {
+        let guard = ProfilerGuardBuilder::default()
+            .frequency(99)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()?;
+
+        std::thread::sleep(Duration::from_secs(seconds));
+
+        let report = guard.report().build()?;
+        let mut body = Vec::new();
+
+        if proto {
+            report
+                .pprof()?
+                .encode(&mut body)
+                .map_err(|err| pprof::Error::IoError(err.into()))?;
+        } else {
+            report.flamegraph(&mut body)?;
+        }
+
+        Ok::<_, pprof::Error>(body)
+    })
+    .await;
+
+    let content_type = if proto {
+        "application/octet-stream"
+    } else {
+        "image/svg+xml"
+    };
+
+    match result {
+        Ok(Ok(body)) => (
+            [(header::CONTENT_TYPE, HeaderValue::from_static(content_type))],
+            body,
+        )
+            .into_response(),
+        Ok(Err(err)) => {
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
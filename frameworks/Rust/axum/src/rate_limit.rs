@@ -0,0 +1,150 @@
+//! Optional global token-bucket rate limiter.
+//!
+//! Enabled by setting `AXUM_TECHEMPOWER_RATE_LIMIT_RPS`. The bucket holds up to
+//! `AXUM_TECHEMPOWER_RATE_LIMIT_BURST` tokens (defaults to the rate) and is
+//! shared by every worker, so the limit applies to the whole process. Requests
+//! arriving with an empty bucket are answered with `429 Too Many Requests`.
+//! Both settings are re-read on SIGHUP, see `reload`.
+
+use std::{
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+
+use axum::{
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+
+use crate::{clock, reload::Reloaded, utils::get_optional_environment_variable};
+
+const RATE_LIMIT_RPS: &str = "AXUM_TECHEMPOWER_RATE_LIMIT_RPS";
+const RATE_LIMIT_BURST: &str = "AXUM_TECHEMPOWER_RATE_LIMIT_BURST";
+
+struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            state: Mutex::new(BucketState {
+                capacity,
+                rate,
+                tokens: capacity,
+                last: clock::now(),
+            }),
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        let now = clock::now();
+        state.tokens = (state.tokens
+            + now.duration_since(state.last).as_secs_f64() * state.rate)
+            .min(state.capacity);
+        state.last = now;
+
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn bucket() -> Option<&'static TokenBucket> {
+    static BUCKET: OnceLock<Option<TokenBucket>> = OnceLock::new();
+
+    BUCKET
+        .get_or_init(|| {
+            let rate: f64 = get_optional_environment_variable(RATE_LIMIT_RPS)?;
+            let burst: f64 =
+                get_optional_environment_variable(RATE_LIMIT_BURST).unwrap_or(rate);
+
+            Some(TokenBucket::new(rate, burst.max(1.0)))
+        })
+        .as_ref()
+}
+
+/// Picks up a changed rate and burst. The limiter can't be switched on or off
+/// without a restart, so an unset rate keeps the current one.
+pub fn reload(settings: &Reloaded) {
+    let Some(bucket) = bucket() else {
+        return;
+    };
+    let mut state = bucket.state.lock().unwrap();
+
+    if let Some(Some(rate)) = settings.get(RATE_LIMIT_RPS) {
+        state.rate = rate;
+    }
+    match settings.get::<f64>(RATE_LIMIT_BURST) {
+        Some(Some(burst)) => state.capacity = burst.max(1.0),
+        Some(None) => {}
+        None => state.capacity = state.rate.max(1.0),
+    }
+    state.tokens = state.tokens.min(state.capacity);
+}
+
+/// Wraps `router` in the rate limiter, if one is configured.
+pub fn apply(router: Router) -> Router {
+    if bucket().is_none() {
+        return router;
+    }
+
+    router.layer(middleware::from_fn(limit))
+}
+
+async fn limit<B>(request: Request<B>, next: Next<B>) -> Response {
+    match bucket() {
+        Some(bucket) if !bucket.try_acquire() => {
+            (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, "1")]).into_response()
+        }
+        _ => next.run(request).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn bucket_refills_at_the_rate() {
+        let (_held, clock) = clock::mock::hold();
+        let bucket = TokenBucket::new(4.0, 1.0);
+
+        assert!(bucket.try_acquire());
+        assert!(!bucket.try_acquire());
+
+        clock.advance(Duration::from_millis(125));
+        assert!(!bucket.try_acquire());
+
+        clock.advance(Duration::from_millis(125));
+        assert!(bucket.try_acquire());
+        assert!(!bucket.try_acquire());
+    }
+
+    #[test]
+    fn bucket_holds_at_most_its_capacity() {
+        let (_held, clock) = clock::mock::hold();
+        let bucket = TokenBucket::new(4.0, 2.0);
+
+        clock.advance(Duration::from_secs(10));
+        assert!(bucket.try_acquire());
+        assert!(bucket.try_acquire());
+        assert!(!bucket.try_acquire());
+    }
+}
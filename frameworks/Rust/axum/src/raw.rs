@@ -0,0 +1,77 @@
+use std::convert::Infallible;
+
+use axum::{
+    body::Body,
+    http::{header, HeaderValue, Method, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    common::{
+        endpoints::{JSON, PLAINTEXT},
+        headers::TEXT_PLAIN,
+    },
+    models_common::Message,
+    server::body_too_large,
+    utils::JsonFast,
+};
+
+/// Minimal service for the plaintext and json tests.
+///
+/// Bypasses axum's router and middleware entirely, so these two endpoints
+/// can be served on a dedicated listener next to the full router.
+pub async fn handle(req: Request<Body>) -> Result<Response, Infallible> {
+    let path = req.uri().path();
+    let known = (path == PLAINTEXT.path && PLAINTEXT.enabled())
+        || (path == JSON.path && JSON.enabled());
+
+    let mut res = if !known {
+        StatusCode::NOT_FOUND.into_response()
+    } else if req.method() != Method::GET && req.method() != Method::HEAD {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            [(header::ALLOW, "GET,HEAD")],
+        )
+            .into_response()
+    } else if body_too_large(req.headers()) {
+        StatusCode::PAYLOAD_TOO_LARGE.into_response()
+    } else if path == PLAINTEXT.path {
+        (
+            [(header::CONTENT_TYPE, TEXT_PLAIN.clone())],
+            "Hello, World!",
+        )
+            .into_response()
+    } else {
+        JsonFast::new(Message {
+            message: "Hello, World!",
+        })
+        .into_response()
+    };
+
+    res.headers_mut()
+        .insert(header::SERVER, HeaderValue::from_static("Axum"));
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::service_fn;
+
+    use super::*;
+    use crate::common::testing;
+
+    #[tokio::test]
+    async fn answers_with_the_tfb_content_types() {
+        for endpoint in [&PLAINTEXT, &JSON] {
+            assert_eq!(
+                testing::content_type(service_fn(handle), endpoint.path).await,
+                (
+                    StatusCode::OK,
+                    testing::tfb_content_type(endpoint.name).map(str::to_string)
+                ),
+                "{}",
+                endpoint.path
+            );
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Re-reads the runtime-tunable settings when the process receives SIGHUP.
+//!
+//! On SIGHUP the `.env` file is read again, and its values take precedence over
+//! the process environment for:
+//!
+//! - `AXUM_TECHEMPOWER_MAX_REQUEST_BODY`
+//! - `AXUM_TECHEMPOWER_IDLE_TIMEOUT_SECS`, for connections accepted afterwards
+//! - `AXUM_TECHEMPOWER_RATE_LIMIT_RPS` and `_BURST`, if the rate limiter was
+//!   enabled at startup
+//!
+//! Everything else is only read at startup. A value that doesn't parse is
+//! reported and the setting keeps its current value.
+
+use std::{
+    collections::HashMap,
+    env,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{idle_timeout, rate_limit, server};
+
+/// Settings as of a reload: `.env` first, then the process environment.
+pub struct Reloaded {
+    file: HashMap<String, String>,
+}
+
+impl Reloaded {
+    // the suggested replacement writes into the process environment, which
+    // isn't safe to do while other threads may be reading it
+    #[allow(deprecated)]
+    fn read() -> Self {
+        let file = match dotenv::dotenv_iter() {
+            Ok(iter) => iter.filter_map(Result::ok).collect(),
+            Err(_) => HashMap::new(),
+        };
+
+        Self { file }
+    }
+
+    /// `None` if `key` is set but doesn't parse.
+    pub fn get_or<T: FromStr>(&self, key: &str, default: T) -> Option<T> {
+        self.get(key).unwrap_or(Some(default))
+    }
+
+    /// `None` if `key` isn't set, `Some(None)` if it is set but doesn't parse.
+    pub fn get<T: FromStr>(&self, key: &str) -> Option<Option<T>> {
+        let value = self.file.get(key).cloned().or_else(|| env::var(key).ok())?;
+
+        match value.parse() {
+            Ok(value) => Some(Some(value)),
+            Err(_) => {
+                eprintln!("reload: could not parse {key}, keeping the current value");
+                Some(None)
+            }
+        }
+    }
+}
+
+/// Reloads the settings on every SIGHUP. Only the first call installs the
+/// handler; later calls, e.g. from other workers, do nothing.
+pub fn spawn_on_sighup() {
+    static SPAWNED: AtomicBool = AtomicBool::new(false);
+
+    if SPAWNED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            eprintln!("reload: could not install SIGHUP handler: {err}");
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            let settings = Reloaded::read();
+
+            server::reload(&settings);
+            idle_timeout::reload(&settings);
+            rate_limit::reload(&settings);
+
+            println!("reload: settings re-read");
+        }
+    });
+}
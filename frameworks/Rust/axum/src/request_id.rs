@@ -0,0 +1,139 @@
+//! Request ids, for correlating a failed verifier request with the server
+//! side, enabled with `AXUM_TECHEMPOWER_REQUEST_IDS=true`.
+//!
+//! Every request gets the id from its `X-Request-Id` header, or a fresh one,
+//! which is echoed back in the same header. Error responses log a line with
+//! the id, and carry it in their JSON body as `request_id`; plain text error
+//! bodies are turned into `{"error": ..., "request_id": ...}`. Handlers that
+//! panic have the id printed next to the panic message.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Once, OnceLock,
+};
+
+use axum::{
+    body::{self, Body, HttpBody},
+    http::{header, HeaderName, HeaderValue, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use serde_json::{json, Value};
+
+use crate::{common::headers::APPLICATION_JSON, utils::get_environment_variable_or};
+
+static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest `X-Request-Id` taken over from a client.
+const MAX_ID_LEN: usize = 128;
+
+/// Error bodies larger than this are passed through untouched.
+const MAX_ERROR_BODY: u64 = 64 * 1024;
+
+tokio::task_local! {
+    static REQUEST_ID: HeaderValue;
+}
+
+/// Assigns request ids on `router`, if enabled.
+pub fn layer(router: Router) -> Router {
+    if !get_environment_variable_or("AXUM_TECHEMPOWER_REQUEST_IDS", false) {
+        return router;
+    }
+
+    install_panic_hook();
+    router.layer(middleware::from_fn(assign))
+}
+
+async fn assign(request: Request<Body>, next: Next<Body>) -> Response {
+    let id = request
+        .headers()
+        .get(&X_REQUEST_ID)
+        .filter(|id| id.len() <= MAX_ID_LEN && id.to_str().is_ok())
+        .cloned()
+        .unwrap_or_else(generate);
+
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(request)).await;
+
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        eprintln!(
+            "request {}: {method} {uri} -> {status}",
+            id.to_str().unwrap_or_default()
+        );
+        response = tag_error(response, &id).await;
+    }
+
+    response.headers_mut().insert(X_REQUEST_ID.clone(), id);
+    response
+}
+
+/// `<process prefix>-<counter>`, unique within and, most likely, across runs.
+fn generate() -> HeaderValue {
+    static PREFIX: OnceLock<u32> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let prefix = PREFIX.get_or_init(rand::random);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    HeaderValue::try_from(format!("{prefix:08x}-{n:x}")).unwrap()
+}
+
+/// Id of the request being handled on this task, if ids are enabled.
+pub fn current() -> Option<HeaderValue> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+async fn tag_error(response: Response, id: &HeaderValue) -> Response {
+    let (mut parts, body) = response.into_parts();
+
+    let small = body
+        .size_hint()
+        .upper()
+        .is_some_and(|len| len <= MAX_ERROR_BODY);
+    if !small {
+        return Response::from_parts(parts, body);
+    }
+
+    let Ok(bytes) = hyper::body::to_bytes(body).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    let id = Value::from(id.to_str().unwrap_or_default());
+    let tagged = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(Value::Object(mut object)) => {
+            object.insert("request_id".to_string(), id);
+            Value::Object(object)
+        }
+        _ => json!({
+            "error": String::from_utf8_lossy(&bytes),
+            "request_id": id,
+        }),
+    };
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, APPLICATION_JSON.clone());
+    let body = body::boxed(Body::from(serde_json::to_vec(&tagged).unwrap()));
+
+    Response::from_parts(parts, body)
+}
+
+/// Prints the current request id before the usual panic message.
+fn install_panic_hook() {
+    static INSTALLED: Once = Once::new();
+
+    INSTALLED.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if let Some(id) = current() {
+                eprintln!("request {} panicked:", id.to_str().unwrap_or_default());
+            }
+            previous(info);
+        }));
+    });
+}
@@ -1,27 +1,498 @@
 use std::{
+    future::Future,
     io,
-    net::{Ipv4Addr, SocketAddr},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
 };
 
-use hyper::server::conn::AddrIncoming;
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, HeaderMap, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use futures_util::future::join_all;
+
+use hyper::{server::conn::AddrIncoming, service::service_fn};
+use socket2::SockRef;
 use tokio::net::{TcpListener, TcpSocket};
+use tower::ServiceExt;
+
+use crate::{
+    common::{admin::admin_token, endpoints::ENDPOINTS, params},
+    drain,
+    idle_timeout::{idle_timeout, IdleIncoming},
+    max_requests, rate_limit, raw,
+    reload::{self, Reloaded},
+    utils::{
+        get_environment_variable_or, get_optional_environment_variable, init_worker_rng,
+    },
+};
+
+#[cfg(not(feature = "runtime-metrics"))]
+type Accepted = AddrIncoming;
+
+#[cfg(feature = "runtime-metrics")]
+type Accepted = crate::metrics::CountedIncoming;
+
+pub type Incoming = IdleIncoming<Accepted>;
+
+/// Time a client gets to send a complete request head once it started one,
+/// from `AXUM_TECHEMPOWER_HEADER_READ_TIMEOUT_SECS` (default 10).
+pub fn header_read_timeout() -> Duration {
+    let secs =
+        get_environment_variable_or("AXUM_TECHEMPOWER_HEADER_READ_TIMEOUT_SECS", 10);
+    Duration::from_secs(secs)
+}
+
+/// Most bytes buffered per connection while reading request heads, from
+/// `AXUM_TECHEMPOWER_MAX_HEADER_BYTES` (default 64 KiB, at least 8 KiB).
+pub fn max_header_bytes() -> usize {
+    get_environment_variable_or("AXUM_TECHEMPOWER_MAX_HEADER_BYTES", 64 * 1024).max(8192)
+}
+
+/// Address family the servers listen on, from `AXUM_TECHEMPOWER_BIND`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Bind {
+    /// `0.0.0.0`, the default.
+    V4,
+    /// `[::]`, IPv6 only, for IPv6-only lab networks.
+    V6,
+    /// `[::]`, also accepting IPv4 clients as v4-mapped addresses.
+    Dual,
+}
+
+fn bind() -> Bind {
+    static BIND: OnceLock<Bind> = OnceLock::new();
+
+    *BIND.get_or_init(|| {
+        let bind: String =
+            get_environment_variable_or("AXUM_TECHEMPOWER_BIND", "v4".into());
 
-pub fn builder() -> hyper::server::Builder<AddrIncoming> {
-    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 8000));
+        match bind.as_str() {
+            "v4" => Bind::V4,
+            "v6" => Bind::V6,
+            "dual" => Bind::Dual,
+            _ => panic!("AXUM_TECHEMPOWER_BIND must be v4, v6 or dual, got {bind:?}"),
+        }
+    })
+}
+
+/// Wildcard address on `port` for the configured address family.
+pub fn unspecified_addr(port: u16) -> SocketAddr {
+    match bind() {
+        Bind::V4 => SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+        Bind::V6 | Bind::Dual => SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+    }
+}
+
+#[allow(dead_code)]
+pub fn builder() -> hyper::server::Builder<Incoming> {
+    builder_on(8000)
+}
+
+pub fn builder_on(port: u16) -> hyper::server::Builder<Incoming> {
+    let addr = unspecified_addr(port);
     let listener = reuse_listener(addr).expect("couldn't bind to addr");
-    let incoming = AddrIncoming::from_listener(listener).unwrap();
+    announce(&listener);
+    builder_from(listener)
+}
+
+/// Prints the startup snapshot and the listening port, once per process.
+fn announce(listener: &TcpListener) {
+    let port = listener.local_addr().map_or(0, |addr| addr.port());
+    header_read_timeout();
+    max_header_bytes();
+    prime_settings();
+    crate::diagnostics::report(listener);
+    println!("Started axum server at {port}");
+}
+
+fn builder_from(listener: TcpListener) -> hyper::server::Builder<Incoming> {
+    let header_read_timeout = header_read_timeout();
+    let max_header_bytes = max_header_bytes();
 
-    println!("Started axum server at 8000");
+    let mut incoming = AddrIncoming::from_listener(listener).unwrap();
+    incoming.set_nodelay(true);
 
-    axum::Server::builder(incoming)
+    #[cfg(feature = "runtime-metrics")]
+    let incoming = {
+        crate::metrics::spawn_reporter();
+        crate::metrics::CountedIncoming::new(incoming)
+    };
+
+    axum::Server::builder(IdleIncoming::new(incoming))
         .http1_only(true)
-        .tcp_nodelay(true)
+        .http1_header_read_timeout(header_read_timeout)
+        .http1_max_buf_size(max_header_bytes)
+}
+
+/// Reads the settings that are otherwise only read on first use, so the
+/// startup snapshot shows them.
+pub fn prime_settings() {
+    max_request_body();
+    idle_timeout();
+    admin_token();
+    params::max();
+}
+
+/// Bootstraps a server running one single-threaded runtime per worker.
+///
+/// Every worker builds its own router, and with it its own database state, and
+/// accepts on its own `SO_REUSEPORT` socket. When binding port 0, the first
+/// worker picks a free port and the others bind to the same one.
+///
+/// `AXUM_TECHEMPOWER_PARTITIONS` sets workers aside for some endpoints, to
+/// study how workloads interfere with each other on one host. It is a `;`
+/// separated list of `name,name@port:workers` partitions, e.g.
+/// `plaintext,json@8001:4`: the first 4 workers serve only `/plaintext` and
+/// `/json`, on port 8001, while the remaining workers serve every other
+/// endpoint on the bound address.
+#[allow(dead_code)]
+pub struct Server;
+
+#[allow(dead_code)]
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            addr: unspecified_addr(8000),
+            workers: num_cpus::get(),
+        }
+    }
+}
+
+pub struct ServerBuilder {
+    addr: SocketAddr,
+    workers: usize,
+}
+
+/// Workers serving only `endpoints`, on their own port.
+struct Partition {
+    endpoints: Vec<&'static str>,
+    port: u16,
+    workers: usize,
+}
+
+/// Endpoint names a worker serves, the others answer 404.
+type Served = Arc<[&'static str]>;
+
+fn partitions() -> Vec<Partition> {
+    let Some(spec) =
+        get_optional_environment_variable::<String>("AXUM_TECHEMPOWER_PARTITIONS")
+    else {
+        return Vec::new();
+    };
+
+    spec.split(';')
+        .filter(|partition| !partition.trim().is_empty())
+        .map(|partition| {
+            parse_partition(partition.trim()).unwrap_or_else(|| {
+                panic!("could not parse AXUM_TECHEMPOWER_PARTITIONS entry {partition:?}")
+            })
+        })
+        .collect()
+}
+
+fn parse_partition(partition: &str) -> Option<Partition> {
+    let (names, rest) = partition.split_once('@')?;
+    let (port, workers) = rest.split_once(':')?;
+
+    let endpoints = names
+        .split(',')
+        .map(|name| {
+            ENDPOINTS
+                .iter()
+                .find(|endpoint| endpoint.name == name.trim())
+                .map(|endpoint| endpoint.name)
+        })
+        .collect::<Option<_>>()?;
+
+    Some(Partition {
+        endpoints,
+        port: port.parse().ok()?,
+        workers: workers.parse().ok().filter(|&workers| workers > 0)?,
+    })
+}
+
+async fn filter_endpoints<B>(
+    State(served): State<Served>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let excluded = ENDPOINTS.iter().any(|endpoint| {
+        endpoint.path == request.uri().path() && !served.contains(&endpoint.name)
+    });
+
+    if excluded {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    next.run(request).await
+}
+
+#[allow(dead_code)]
+impl ServerBuilder {
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Serves the router returned by `app` on every worker, blocking the calling
+    /// thread, which runs the first worker of the default partition.
+    pub fn serve<F, Fut>(self, app: F)
+    where
+        F: Fn() -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = Router>,
+    {
+        let partitions = partitions();
+        let partitioned: usize = partitions.iter().map(|p| p.workers).sum();
+        assert!(
+            partitioned < self.workers,
+            "AXUM_TECHEMPOWER_PARTITIONS assigns {partitioned} of {} workers, \
+             leaving none for the remaining endpoints",
+            self.workers
+        );
+
+        let rest: Served = ENDPOINTS
+            .iter()
+            .map(|endpoint| endpoint.name)
+            .filter(|name| !partitions.iter().any(|p| p.endpoints.contains(name)))
+            .collect();
+
+        init_worker_rng(0);
+
+        runtime().block_on(async move {
+            let router = app().await;
+            let listener = reuse_listener(self.addr).expect("couldn't bind to addr");
+            let addr = listener.local_addr().unwrap();
+            announce(&listener);
+
+            let assigned = partitions.iter().flat_map(|partition| {
+                let addr = SocketAddr::new(addr.ip(), partition.port);
+                let served: Served = partition.endpoints.clone().into();
+                std::iter::repeat((addr, served)).take(partition.workers)
+            });
+            let unassigned = std::iter::repeat((addr, rest.clone()));
+
+            for (worker, (addr, served)) in
+                (1..self.workers).zip(assigned.chain(unassigned))
+            {
+                let app = app.clone();
+                std::thread::spawn(move || {
+                    init_worker_rng(worker as u64);
+
+                    runtime().block_on(async move {
+                        let router = app().await;
+                        let listener =
+                            reuse_listener(addr).expect("couldn't bind to addr");
+                        serve_worker(listener, router, served).await;
+                    });
+                });
+            }
+
+            serve_worker(listener, router, rest).await;
+        });
+    }
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+async fn serve_worker(listener: TcpListener, router: Router, served: Served) {
+    let router = router.layer(middleware::from_fn_with_state(served, filter_endpoints));
+
+    #[cfg(feature = "pprof")]
+    let router = router.merge(crate::profiling::routes());
+
+    #[cfg(feature = "latency-histograms")]
+    let router = router.merge(crate::latency::routes());
+
+    warm_up(&router).await;
+    let router = apply_limits(router);
+
+    spawn_raw_listener();
+
+    let serving = drain::Serving::start();
+    builder_from(listener)
+        .serve(max_requests::make_service(router))
+        .with_graceful_shutdown(drain::requested())
+        .await
+        .unwrap();
+    serving.finished().await;
+}
+
+/// Sends `AXUM_TECHEMPOWER_WARMUP_REQUESTS` concurrent requests (default 0,
+/// disabled) to each endpoint's warm-up path before the server starts accepting
+/// connections, so connection pools and statement caches are filled before the
+/// first measured iteration. Endpoints a binary doesn't serve are skipped.
+pub async fn warm_up(router: &Router) {
+    let requests: usize =
+        get_environment_variable_or("AXUM_TECHEMPOWER_WARMUP_REQUESTS", 0);
+    if requests == 0 {
+        return;
+    }
+
+    let paths = ENDPOINTS
+        .iter()
+        .filter(|endpoint| endpoint.enabled())
+        .filter_map(|endpoint| endpoint.warm_up);
+
+    for path in paths {
+        let start = Instant::now();
+        let responses = join_all((0..requests).map(|_| {
+            let request = Request::get(path).body(Body::empty()).unwrap();
+            router.clone().oneshot(request)
+        }))
+        .await;
+
+        let statuses: Vec<StatusCode> = responses
+            .into_iter()
+            .map(|response| match response {
+                Ok(response) => response.status(),
+                Err(err) => match err {},
+            })
+            .collect();
+
+        // endpoints the binary doesn't serve
+        if statuses.iter().all(|status| {
+            *status == StatusCode::NOT_FOUND || *status == StatusCode::NOT_IMPLEMENTED
+        }) {
+            continue;
+        }
+
+        let failed = statuses
+            .iter()
+            .filter(|status| !status.is_success())
+            .count();
+        println!(
+            "warm-up: {path} {requests} requests in {}ms, {failed} failed",
+            start.elapsed().as_millis()
+        );
+    }
+}
+
+const MAX_REQUEST_BODY: &str = "AXUM_TECHEMPOWER_MAX_REQUEST_BODY";
+
+/// Largest request body accepted, from `AXUM_TECHEMPOWER_MAX_REQUEST_BODY`
+/// (default 1 KiB). None of the benchmark routes read a body.
+fn max_request_body() -> &'static AtomicU64 {
+    static MAX: OnceLock<AtomicU64> = OnceLock::new();
+
+    MAX.get_or_init(|| {
+        AtomicU64::new(get_environment_variable_or(MAX_REQUEST_BODY, 1024))
+    })
+}
+
+/// Picks up a changed `AXUM_TECHEMPOWER_MAX_REQUEST_BODY`.
+pub fn reload(settings: &Reloaded) {
+    if let Some(max) = settings.get_or(MAX_REQUEST_BODY, 1024) {
+        max_request_body().store(max, Ordering::Relaxed);
+    }
+}
+
+/// Whether the request announces a body larger than `max_request_body`.
+/// Chunked bodies have no announced size and are rejected as well.
+pub fn body_too_large(headers: &HeaderMap) -> bool {
+    if headers.contains_key(header::TRANSFER_ENCODING) {
+        return true;
+    }
+
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+        .is_some_and(|len| len > max_request_body().load(Ordering::Relaxed))
+}
+
+async fn limit_body<B>(request: Request<B>, next: Next<B>) -> Response {
+    if body_too_large(request.headers()) {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    }
+
+    next.run(request).await
+}
+
+/// Applies the request limits shared by every binary: the body size cap and,
+/// if configured, the rate limiter.
+///
+/// The body cap only looks at the request headers. `RequestBodyLimitLayer`
+/// would change the body type the handlers see, and no handler reads a body.
+///
+/// Also installs the SIGHUP handler that reloads them, see `reload`.
+pub fn apply_limits(router: Router) -> Router {
+    reload::spawn_on_sighup();
+    drain::spawn_on_sigusr2();
+
+    #[cfg(feature = "runtime-metrics")]
+    let router = crate::metrics::layer(router);
+
+    #[cfg(feature = "latency-histograms")]
+    let router = crate::latency::layer(router);
+
+    #[cfg(feature = "compression")]
+    let router = crate::compression::layer(router);
+
+    let router = crate::deadline::layer(router);
+
+    let router = crate::circuit_breaker::layer(router);
+
+    let router = crate::micro_cache::layer(router);
+
+    let router = rate_limit::apply(router.layer(middleware::from_fn(limit_body)));
+
+    let router = crate::totals::layer(crate::request_id::layer(router));
+
+    crate::telemetry::layer(drain::layer(router))
 }
 
-fn reuse_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+/// Serves the raw plaintext/json service on `AXUM_TECHEMPOWER_RAW_PORT`, if
+/// set, alongside the router served by the caller.
+pub fn spawn_raw_listener() {
+    let Some(port) =
+        get_optional_environment_variable::<u16>("AXUM_TECHEMPOWER_RAW_PORT")
+    else {
+        return;
+    };
+
+    let make_service = max_requests::make_service(service_fn(raw::handle));
+
+    tokio::spawn(async move {
+        let serving = drain::Serving::start();
+        let listener =
+            reuse_listener(unspecified_addr(port)).expect("couldn't bind to addr");
+        builder_from(listener)
+            .http1_pipeline_flush(true)
+            .serve(make_service)
+            .with_graceful_shutdown(drain::requested())
+            .await
+            .unwrap();
+        serving.finished().await;
+    });
+}
+
+pub fn reuse_listener(addr: SocketAddr) -> io::Result<TcpListener> {
     let socket = match addr {
         SocketAddr::V4(_) => TcpSocket::new_v4()?,
-        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        SocketAddr::V6(_) => {
+            let socket = TcpSocket::new_v6()?;
+            // explicit either way, the OS default depends on a sysctl
+            SockRef::from(&socket).set_only_v6(bind() != Bind::Dual)?;
+            socket
+        }
     };
 
     #[cfg(unix)]
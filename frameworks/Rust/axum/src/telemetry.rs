@@ -0,0 +1,96 @@
+//! Tracing setup shared by every binary.
+//!
+//! With the `tracing` feature, requests are traced and logged to stderr in
+//! the format given by `AXUM_TECHEMPOWER_LOG_FORMAT`: `compact` (the default)
+//! for one line per event, `json` for JSON lines, or `none` to install no
+//! logging at all, which also leaves out the request tracing layer.
+//! `AXUM_TECHEMPOWER_LOG_LEVEL` (default `info`) sets the most verbose level
+//! logged. With `debug-console`, the tokio-console instrumentation is layered
+//! onto the same subscriber.
+
+#[cfg(feature = "tracing")]
+use std::str::FromStr;
+
+use axum::Router;
+#[cfg(feature = "tracing")]
+use tracing_subscriber::{filter::LevelFilter, Layer};
+#[cfg(any(feature = "tracing", feature = "debug-console"))]
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+#[cfg(feature = "tracing")]
+use crate::utils::get_environment_variable_or;
+
+#[cfg(feature = "tracing")]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    Compact,
+    Json,
+    None,
+}
+
+#[cfg(feature = "tracing")]
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "compact" => Ok(LogFormat::Compact),
+            "json" => Ok(LogFormat::Json),
+            "none" => Ok(LogFormat::None),
+            _ => Err(format!("expected compact, json or none, got {format:?}")),
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn log_format() -> LogFormat {
+    get_environment_variable_or("AXUM_TECHEMPOWER_LOG_FORMAT", LogFormat::Compact)
+}
+
+/// Installs the global subscriber. Does nothing without `tracing` or
+/// `debug-console`.
+pub fn init() {
+    #[cfg(feature = "tracing")]
+    let logging = {
+        let level: LevelFilter =
+            get_environment_variable_or("AXUM_TECHEMPOWER_LOG_LEVEL", LevelFilter::INFO);
+        let logging = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+
+        let logging = match log_format() {
+            LogFormat::Compact => Some(logging.compact().boxed()),
+            LogFormat::Json => Some(logging.json().boxed()),
+            LogFormat::None => None,
+        };
+        logging.map(|logging| logging.with_filter(level))
+    };
+
+    #[cfg(feature = "debug-console")]
+    let console = console_subscriber::spawn();
+
+    #[cfg(any(feature = "tracing", feature = "debug-console"))]
+    {
+        let registry = tracing_subscriber::registry();
+        #[cfg(feature = "tracing")]
+        let registry = registry.with(logging);
+        #[cfg(feature = "debug-console")]
+        let registry = registry.with(console);
+        registry.init();
+    }
+}
+
+/// Traces the requests served by `router`, unless logging is off.
+pub fn layer(router: Router) -> Router {
+    #[cfg(feature = "tracing")]
+    if log_format() != LogFormat::None {
+        use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer};
+        use tracing::Level;
+
+        return router.layer(
+            TraceLayer::new_for_http()
+                .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+        );
+    }
+
+    router
+}
@@ -0,0 +1,222 @@
+//! Totals since startup, written out as JSON when the process is stopped, so
+//! benchmark harnesses can collect server-side counters alongside their own.
+//!
+//! Enabled by setting `AXUM_TECHEMPOWER_SHUTDOWN_REPORT` to a file path, or to
+//! `-` for stdout. On SIGTERM or SIGINT the report is written and the process
+//! exits, as it is when a drain (see `drain`) ends:
+//!
+//! ```json
+//! {"uptime_secs": 61.2,
+//!  "endpoints": {"json": {"requests": 1200, "errors": 0}, ...},
+//!  "status": {"2xx": 1200, "3xx": 0, "4xx": 0, "5xx": 0},
+//!  "db": {"reads": 0, "writes": 0}}
+//! ```
+//!
+//! `errors` counts 4xx and 5xx responses. Requests that never got a response,
+//! because their client left or the handler panicked, count towards
+//! `requests` but no status class. Requests to paths outside the benchmark
+//! endpoints are counted under `other`, and the responses of the plaintext
+//! fast path aren't counted at all. `db` counts the statements and commands
+//! sent to the database, including those of startup and cache loads.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::Instant,
+};
+
+use axum::{
+    http::Request,
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use serde::Serialize;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{common::endpoints::ENDPOINTS, utils::get_optional_environment_variable};
+
+/// Counter slots: one per endpoint, then one for every other path.
+const SLOTS: usize = 16;
+const _: () = assert!(ENDPOINTS.len() < SLOTS);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+static REQUESTS: [AtomicU64; SLOTS] = [ZERO; SLOTS];
+static ERRORS: [AtomicU64; SLOTS] = [ZERO; SLOTS];
+static STATUS_CLASSES: [AtomicU64; 5] = [ZERO; 5];
+static DB_READS: AtomicU64 = AtomicU64::new(0);
+static DB_WRITES: AtomicU64 = AtomicU64::new(0);
+
+fn destination() -> Option<&'static str> {
+    static DESTINATION: OnceLock<Option<String>> = OnceLock::new();
+
+    DESTINATION
+        .get_or_init(|| {
+            get_optional_environment_variable("AXUM_TECHEMPOWER_SHUTDOWN_REPORT")
+        })
+        .as_deref()
+}
+
+/// Counts a statement or command reading from the database.
+#[allow(dead_code)]
+pub fn db_read() {
+    DB_READS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a statement or command writing to the database.
+#[allow(dead_code)]
+pub fn db_write() {
+    DB_WRITES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts the requests served by `router` and installs the shutdown handler,
+/// if the report is enabled.
+pub fn layer(router: Router) -> Router {
+    if destination().is_none() {
+        return router;
+    }
+
+    spawn_on_shutdown();
+    router.layer(middleware::from_fn(count))
+}
+
+fn slot(path: &str) -> usize {
+    ENDPOINTS
+        .iter()
+        .position(|endpoint| endpoint.path == path)
+        .unwrap_or(ENDPOINTS.len())
+}
+
+async fn count<B>(request: Request<B>, next: Next<B>) -> Response {
+    let slot = slot(request.uri().path());
+    REQUESTS[slot].fetch_add(1, Ordering::Relaxed);
+
+    let response = next.run(request).await;
+
+    let status = response.status().as_u16();
+    if let Some(class) = STATUS_CLASSES.get(usize::from(status / 100).wrapping_sub(1)) {
+        class.fetch_add(1, Ordering::Relaxed);
+    }
+    if status >= 400 {
+        ERRORS[slot].fetch_add(1, Ordering::Relaxed);
+    }
+
+    response
+}
+
+#[derive(Serialize)]
+struct Report {
+    uptime_secs: f64,
+    endpoints: BTreeMap<&'static str, EndpointTotals>,
+    status: BTreeMap<&'static str, u64>,
+    db: DbTotals,
+}
+
+#[derive(Serialize)]
+struct EndpointTotals {
+    requests: u64,
+    errors: u64,
+}
+
+#[derive(Serialize)]
+struct DbTotals {
+    reads: u64,
+    writes: u64,
+}
+
+fn report(started: Instant) -> Report {
+    let names = ENDPOINTS.iter().map(|endpoint| endpoint.name);
+    let endpoints = names
+        .chain(["other"])
+        .enumerate()
+        .map(|(slot, name)| {
+            let totals = EndpointTotals {
+                requests: REQUESTS[slot].load(Ordering::Relaxed),
+                errors: ERRORS[slot].load(Ordering::Relaxed),
+            };
+            (name, totals)
+        })
+        .collect();
+
+    let status = ["1xx", "2xx", "3xx", "4xx", "5xx"]
+        .into_iter()
+        .zip(&STATUS_CLASSES)
+        .map(|(class, count)| (class, count.load(Ordering::Relaxed)))
+        .collect();
+
+    Report {
+        uptime_secs: started.elapsed().as_secs_f64(),
+        endpoints,
+        status,
+        db: DbTotals {
+            reads: DB_READS.load(Ordering::Relaxed),
+            writes: DB_WRITES.load(Ordering::Relaxed),
+        },
+    }
+}
+
+fn write_report(started: Instant, destination: &str) -> io::Result<()> {
+    let mut json = serde_json::to_string(&report(started))?;
+    json.push('\n');
+
+    if destination == "-" {
+        print!("{json}");
+        Ok(())
+    } else {
+        fs::write(destination, json)
+    }
+}
+
+fn started() -> Instant {
+    static STARTED: OnceLock<Instant> = OnceLock::new();
+
+    *STARTED.get_or_init(Instant::now)
+}
+
+/// Writes the report, if enabled, for a process that is about to exit.
+pub fn write_report_if_enabled() {
+    let Some(destination) = destination() else {
+        return;
+    };
+
+    if let Err(err) = write_report(started(), destination) {
+        eprintln!("totals: could not write the report to {destination}: {err}");
+    }
+}
+
+/// Writes the report and exits on SIGTERM or SIGINT. Only the first call
+/// installs the handler.
+fn spawn_on_shutdown() {
+    static SPAWNED: AtomicBool = AtomicBool::new(false);
+
+    if SPAWNED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let signals = signal(SignalKind::terminate())
+        .and_then(|terminate| Ok((terminate, signal(SignalKind::interrupt())?)));
+    let (mut terminate, mut interrupt) = match signals {
+        Ok(signals) => signals,
+        Err(err) => {
+            eprintln!("totals: could not install shutdown handler: {err}");
+            return;
+        }
+    };
+
+    started();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+        }
+
+        write_report_if_enabled();
+        std::process::exit(0);
+    });
+}
@@ -1,26 +1,162 @@
-use std::{env, fmt::Debug, str::FromStr};
+use std::{
+    cell::RefCell,
+    convert::Infallible,
+    env,
+    fmt::{Debug, Display},
+    ops::{Deref, DerefMut},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
 
 use axum::{
-    body::{Bytes, Full},
-    http::{header, HeaderValue, StatusCode},
+    async_trait,
+    body::{self, Body, Bytes, Full},
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
 };
-use rand::{rngs::SmallRng, Rng};
-use serde::Deserialize;
+use bytes::{BufMut, BytesMut};
+use rand::{rngs::SmallRng, Rng as _, SeedableRng};
+use rand_distr::{Distribution, Zipf};
+use serde::Serialize;
+
+use crate::{
+    common::headers::{APPLICATION_JSON, TEXT_HTML_UTF8},
+    models_common::{WorldId, WORLD_COUNT},
+};
 
+#[allow(dead_code)]
 pub fn get_environment_variable<T: FromStr>(key: &str) -> T
 where
     <T as FromStr>::Err: Debug,
 {
-    env::var(key)
-        .unwrap_or_else(|_| panic!("{key} environment variable was not set"))
+    let value = env::var(key).ok();
+    crate::diagnostics::record(key, value.as_deref());
+
+    value
+        .unwrap_or_else(|| panic!("{key} environment variable was not set"))
         .parse::<T>()
         .unwrap_or_else(|_| panic!("could not parse {key}"))
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Params {
-    queries: Option<String>,
+/// Like `get_environment_variable`, but returns `None` when the variable is
+/// not set.
+#[allow(dead_code)]
+pub fn get_optional_environment_variable<T: FromStr>(key: &str) -> Option<T>
+where
+    <T as FromStr>::Err: Debug,
+{
+    let value = env::var(key).ok();
+    crate::diagnostics::record(key, value.as_deref());
+
+    value.map(|value| {
+        value
+            .parse::<T>()
+            .unwrap_or_else(|_| panic!("could not parse {key}"))
+    })
+}
+
+/// Like `get_environment_variable`, but falls back to `default` when the
+/// variable is not set.
+#[allow(dead_code)]
+pub fn get_environment_variable_or<T: FromStr>(key: &str, default: T) -> T
+where
+    <T as FromStr>::Err: Debug,
+{
+    get_optional_environment_variable(key).unwrap_or(default)
+}
+
+static NEXT_WORKER: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static WORKER_RNG: RefCell<Option<SmallRng>> = RefCell::new(None);
+}
+
+/// Base seed from `AXUM_TECHEMPOWER_RNG_SEED`. When set, every worker derives
+/// its RNG from `seed + worker index`, so runs issue identical id sequences.
+fn rng_seed() -> Option<u64> {
+    static SEED: OnceLock<Option<u64>> = OnceLock::new();
+
+    *SEED.get_or_init(|| get_optional_environment_variable("AXUM_TECHEMPOWER_RNG_SEED"))
+}
+
+/// Seeds the RNG of the current thread as worker `worker`.
+///
+/// Does nothing unless a base seed is configured. Threads that never call this
+/// are assigned the next free worker index on first use.
+#[allow(dead_code)]
+pub fn init_worker_rng(worker: u64) {
+    if let Some(seed) = rng_seed() {
+        NEXT_WORKER.fetch_max(worker + 1, Ordering::Relaxed);
+
+        WORKER_RNG.with(|rng| {
+            *rng.borrow_mut() = Some(SmallRng::seed_from_u64(seed.wrapping_add(worker)));
+        });
+    }
+}
+
+/// Seeds the RNG of the current thread with `seed`, whatever the configured
+/// base seed, so tests draw the same ids on every run.
+#[cfg(test)]
+pub fn seed_worker_rng(seed: u64) {
+    WORKER_RNG.with(|rng| *rng.borrow_mut() = Some(SmallRng::seed_from_u64(seed)));
+}
+
+/// Forks a request RNG off the worker RNG of the current thread, which is
+/// seeded from entropy unless a base seed is configured.
+#[allow(dead_code)]
+pub fn request_rng() -> SmallRng {
+    WORKER_RNG.with(|rng| {
+        let mut rng = rng.borrow_mut();
+        let rng = rng.get_or_insert_with(|| match rng_seed() {
+            Some(seed) => {
+                let worker = NEXT_WORKER.fetch_add(1, Ordering::Relaxed);
+                SmallRng::seed_from_u64(seed.wrapping_add(worker))
+            }
+            None => SmallRng::from_entropy(),
+        });
+
+        SmallRng::from_rng(rng).unwrap()
+    })
+}
+
+/// Extractor handing handlers a `SmallRng` for the current request.
+///
+/// The worker RNG lives in a thread-local `RefCell`, which can't stay borrowed
+/// across `.await`s, so each request gets a cheap fork of it instead of a
+/// freshly entropy-seeded generator.
+pub struct Rng(pub SmallRng);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Rng
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        _parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self(request_rng()))
+    }
+}
+
+impl Deref for Rng {
+    type Target = SmallRng;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Rng {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
 }
 
 #[allow(dead_code)]
@@ -28,13 +164,86 @@ pub fn random_number(rng: &mut SmallRng) -> i32 {
     (rng.gen::<u32>() % 10_000 + 1) as i32
 }
 
+/// Distribution used to pick world ids.
+///
+/// TFB mandates `Uniform`; `Zipf` concentrates lookups on a few hot ids, which
+/// is useful when benchmarking caches. Configured through
+/// `AXUM_TECHEMPOWER_ID_DISTRIBUTION` as `uniform`, `zipf` or `zipf:<exponent>`.
+#[derive(Clone, Copy, Debug)]
+pub enum IdDistribution {
+    Uniform,
+    Zipf(Zipf<f64>),
+}
+
+impl IdDistribution {
+    fn sample(&self, rng: &mut SmallRng) -> i32 {
+        match self {
+            IdDistribution::Uniform => random_number(rng),
+            IdDistribution::Zipf(zipf) => zipf.sample(rng) as i32,
+        }
+    }
+}
+
+impl FromStr for IdDistribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, exponent) = match s.split_once(':') {
+            Some((name, exponent)) => (name, Some(exponent)),
+            None => (s, None),
+        };
+
+        match name {
+            "uniform" if exponent.is_none() => Ok(IdDistribution::Uniform),
+            "zipf" => {
+                let exponent = match exponent {
+                    Some(exponent) => exponent
+                        .parse::<f64>()
+                        .map_err(|_| format!("invalid zipf exponent: {exponent}"))?,
+                    None => 1.0,
+                };
+
+                Zipf::new(WORLD_COUNT as u64, exponent)
+                    .map(IdDistribution::Zipf)
+                    .map_err(|err| err.to_string())
+            }
+            _ => Err(format!("unknown id distribution: {s}")),
+        }
+    }
+}
+
 #[allow(dead_code)]
-pub fn parse_params(params: Params) -> i32 {
-    params
-        .queries
-        .and_then(|q| q.parse().ok())
-        .unwrap_or(1)
-        .clamp(1, 500)
+pub fn id_distribution() -> &'static IdDistribution {
+    static DISTRIBUTION: OnceLock<IdDistribution> = OnceLock::new();
+
+    DISTRIBUTION.get_or_init(|| {
+        get_environment_variable_or(
+            "AXUM_TECHEMPOWER_ID_DISTRIBUTION",
+            IdDistribution::Uniform,
+        )
+    })
+}
+
+#[allow(dead_code)]
+pub fn random_id(rng: &mut SmallRng) -> WorldId {
+    WorldId::try_from(id_distribution().sample(rng)).unwrap()
+}
+
+#[allow(dead_code)]
+pub fn random_ids(rng: &mut SmallRng, count: usize) -> Vec<WorldId> {
+    (0..count).map(|_| random_id(rng)).collect()
+}
+
+/// Exits the process if a startup step failed.
+///
+/// Used instead of `expect` in setup code, as a panic in one of the per-core
+/// worker threads would leave the remaining workers serving.
+#[allow(dead_code)]
+pub fn exit_on_error<T, E: Display>(result: Result<T, E>, context: &str) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("{context}: {err}");
+        std::process::exit(1)
+    })
 }
 
 /// Utility function for mapping any error into a `500 Internal Server Error`
@@ -56,10 +265,8 @@ where
 {
     fn into_response(self) -> Response {
         let mut res = (StatusCode::OK, self.0.into()).into_response();
-        res.headers_mut().insert(
-            header::CONTENT_TYPE,
-            HeaderValue::from_static("text/html; charset=utf-8"),
-        );
+        res.headers_mut()
+            .insert(header::CONTENT_TYPE, TEXT_HTML_UTF8.clone());
         res
     }
 }
@@ -69,3 +276,152 @@ impl<T> From<T> for Utf8Html<T> {
         Self(inner)
     }
 }
+
+/// Body that is already JSON, sent with the `application/json` content type.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub struct RawJson<T>(pub T);
+
+impl<T> IntoResponse for RawJson<T>
+where
+    T: Into<Full<Bytes>>,
+{
+    fn into_response(self) -> Response {
+        let mut res = (StatusCode::OK, self.0.into()).into_response();
+        res.headers_mut()
+            .insert(header::CONTENT_TYPE, APPLICATION_JSON.clone());
+        res
+    }
+}
+
+/// Size of the per-thread buffer that `JsonFast` bodies are split off from.
+const JSON_BUFFER_CAPACITY: usize = 64 * 1024;
+
+/// Upper bound of a serialized `World`, including the separating comma.
+#[allow(dead_code)]
+pub const WORLD_JSON_CAPACITY: usize = 36;
+
+thread_local! {
+    static JSON_BUFFER: RefCell<BytesMut> =
+        RefCell::new(BytesMut::with_capacity(JSON_BUFFER_CAPACITY));
+}
+
+/// JSON response that serializes straight into a thread-local `BytesMut`,
+/// avoiding the intermediate `Vec` allocated by `axum::Json`.
+///
+/// The body is split off the shared buffer, so the allocation is reused once
+/// the previous responses have been written out.
+#[derive(Clone, Copy, Debug)]
+pub struct JsonFast<T> {
+    value: T,
+    capacity: usize,
+}
+
+impl<T> JsonFast<T> {
+    pub fn new(value: T) -> Self {
+        Self::with_capacity(value, 64)
+    }
+
+    /// Creates a response that reserves `capacity` bytes before serializing.
+    #[allow(dead_code)]
+    pub fn with_capacity(value: T, capacity: usize) -> Self {
+        Self { value, capacity }
+    }
+}
+
+impl<T> IntoResponse for JsonFast<T>
+where
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        let body = JSON_BUFFER.with(|buf| {
+            let mut buf = buf.borrow_mut();
+            if buf.capacity() < self.capacity {
+                buf.reserve(self.capacity.max(JSON_BUFFER_CAPACITY));
+            }
+
+            match serde_json::to_writer((&mut *buf).writer(), &self.value) {
+                Ok(()) => Ok(buf.split().freeze()),
+                Err(err) => {
+                    buf.clear();
+                    Err(err)
+                }
+            }
+        });
+
+        match body {
+            Ok(body) => {
+                let len = body.len();
+                let mut res = (StatusCode::OK, Full::from(body)).into_response();
+                let headers = res.headers_mut();
+                headers.insert(header::CONTENT_TYPE, APPLICATION_JSON.clone());
+                headers.insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+                res
+            }
+            Err(err) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+            }
+        }
+    }
+}
+
+/// JSON array response whose elements are written while it is being sent,
+/// for results too large to buffer. The body is chunked, without a
+/// `Content-Length`.
+#[allow(dead_code)]
+pub struct JsonArrayWriter {
+    sender: hyper::body::Sender,
+    started: bool,
+}
+
+#[allow(dead_code)]
+impl JsonArrayWriter {
+    /// The writer and the response it writes to.
+    pub fn response() -> (Self, Response) {
+        let (sender, body) = Body::channel();
+        let mut response = Response::new(body::boxed(body));
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, APPLICATION_JSON.clone());
+
+        (
+            Self {
+                sender,
+                started: false,
+            },
+            response,
+        )
+    }
+
+    /// Appends `items` to the array, waiting while the client is behind.
+    /// Fails once the client is gone.
+    pub async fn write<T: Serialize>(&mut self, items: &[T]) -> Result<(), axum::Error> {
+        let mut buf = BytesMut::new();
+        for item in items {
+            buf.put_u8(if self.started { b',' } else { b'[' });
+            self.started = true;
+            serde_json::to_writer((&mut buf).writer(), item)
+                .map_err(axum::Error::new)?;
+        }
+
+        self.sender
+            .send_data(buf.freeze())
+            .await
+            .map_err(axum::Error::new)
+    }
+
+    /// Closes the array and the response.
+    pub async fn finish(mut self) {
+        let end = if self.started { "]" } else { "[]" };
+        let _ = self
+            .sender
+            .send_data(Bytes::from_static(end.as_bytes()))
+            .await;
+    }
+
+    /// Cuts the response short, so the client sees it fail instead of taking
+    /// a truncated array for the whole result.
+    pub fn abort(self) {
+        self.sender.abort();
+    }
+}
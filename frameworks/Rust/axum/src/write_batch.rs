@@ -0,0 +1,218 @@
+//! Coalesces the writes of concurrent requests into fewer database commands.
+//!
+//! With `AXUM_TECHEMPOWER_UPDATE_BATCH_WINDOW_US` set, the first write opens a
+//! batch that stays open for that long, and every write arriving meanwhile
+//! joins it; the batch is then sent as one command and all of its writers get
+//! the same result. That adds up to one window of latency to each write, in
+//! exchange for far fewer round trips when many requests write at once. A
+//! batch reaching `MAX_BATCH_ITEMS` is sent right away.
+//!
+//! Batches are flushed from a spawned task, so a request dropped while
+//! waiting doesn't take the writes of the others with it.
+//!
+//! Alternatively, `AXUM_TECHEMPOWER_UPDATE_WRITERS` sets a number of writer
+//! tasks, each with a dedicated connection, that all writes of the process
+//! are queued for. A writer that is done with a batch takes every write
+//! queued meanwhile as the next one, so writes coalesce without a window,
+//! and at most that many batches are written at once however many requests
+//! are in flight. The queue holds `QUEUE_CAPACITY` writes, beyond which
+//! requests wait for room. This takes precedence over the window.
+
+use std::{
+    fmt::Display,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::utils::get_environment_variable_or;
+
+const MAX_BATCH_ITEMS: usize = 5_000;
+
+const QUEUE_CAPACITY: usize = 1_024;
+
+/// Error of a batched write, shared by every writer of the batch.
+pub type BatchError = Arc<str>;
+
+type Waiter = oneshot::Sender<Result<(), BatchError>>;
+
+struct Batch<T> {
+    items: Vec<T>,
+    waiters: Vec<Waiter>,
+}
+
+/// Batching window from the environment, `None` if batching is off.
+pub fn window() -> Option<Duration> {
+    let micros: u64 =
+        get_environment_variable_or("AXUM_TECHEMPOWER_UPDATE_BATCH_WINDOW_US", 0);
+
+    (micros > 0).then(|| Duration::from_micros(micros))
+}
+
+/// Number of writer tasks from the environment, `None` if they are off.
+pub fn writers() -> Option<usize> {
+    let writers: usize =
+        get_environment_variable_or("AXUM_TECHEMPOWER_UPDATE_WRITERS", 0);
+
+    (writers > 0).then_some(writers)
+}
+
+pub struct WriteBatcher<T> {
+    window: Duration,
+    pending: Arc<Mutex<Option<Batch<T>>>>,
+}
+
+impl<T> Clone for WriteBatcher<T> {
+    fn clone(&self) -> Self {
+        Self {
+            window: self.window,
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+impl<T: Send + 'static> WriteBatcher<T> {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Adds `items` to the open batch, or opens one, and waits until the batch
+    /// has been written by `flush`.
+    pub async fn write<F, Fut, E>(
+        &self,
+        items: Vec<T>,
+        flush: F,
+    ) -> Result<(), BatchError>
+    where
+        F: Fn(Vec<T>) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: Display + 'static,
+    {
+        let (waiter, done) = oneshot::channel();
+
+        let full = {
+            let mut pending = self.pending.lock().unwrap();
+
+            let batch = pending.get_or_insert_with(|| {
+                let batcher = self.clone();
+                let flush = flush.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(batcher.window).await;
+                    batcher.flush(flush).await;
+                });
+
+                Batch {
+                    items: Vec::new(),
+                    waiters: Vec::new(),
+                }
+            });
+            batch.items.extend(items);
+            batch.waiters.push(waiter);
+
+            batch.items.len() >= MAX_BATCH_ITEMS
+        };
+
+        if full {
+            tokio::spawn(self.clone().flush(flush));
+        }
+
+        done.await
+            .unwrap_or_else(|_| Err("batched write was dropped".into()))
+    }
+
+    /// Writes the open batch, if any. A timer may find a younger batch than the
+    /// one it was started for, which then just goes out a little early.
+    async fn flush<F, Fut, E>(self, flush: F)
+    where
+        F: Fn(Vec<T>) -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+        E: Display,
+    {
+        let Some(batch) = self.pending.lock().unwrap().take() else {
+            return;
+        };
+
+        let result = flush(batch.items)
+            .await
+            .map_err(|err| BatchError::from(err.to_string()));
+
+        for waiter in batch.waiters {
+            let _ = waiter.send(result.clone());
+        }
+    }
+}
+
+type Write<T> = (Vec<T>, Waiter);
+
+/// Queue of the writer tasks.
+pub struct Writers<T> {
+    queue: mpsc::Sender<Write<T>>,
+}
+
+impl<T: Send + 'static> Writers<T> {
+    /// Starts a writer task for each of `flushes`, which write a batch over
+    /// their writer's connection.
+    pub fn spawn<F, Fut, E>(flushes: impl IntoIterator<Item = F>) -> Self
+    where
+        F: Fn(Vec<T>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), E>> + Send,
+        E: Display,
+    {
+        let (queue, writes) = mpsc::channel::<Write<T>>(QUEUE_CAPACITY);
+        let writes = Arc::new(tokio::sync::Mutex::new(writes));
+
+        for flush in flushes {
+            let writes = writes.clone();
+            tokio::spawn(async move {
+                loop {
+                    let batch = {
+                        let mut writes = writes.lock().await;
+                        let Some((items, waiter)) = writes.recv().await else {
+                            return;
+                        };
+
+                        let mut batch = Batch {
+                            items,
+                            waiters: vec![waiter],
+                        };
+                        while batch.items.len() < MAX_BATCH_ITEMS {
+                            let Ok((items, waiter)) = writes.try_recv() else {
+                                break;
+                            };
+                            batch.items.extend(items);
+                            batch.waiters.push(waiter);
+                        }
+                        batch
+                    };
+
+                    let result = flush(batch.items)
+                        .await
+                        .map_err(|err| BatchError::from(err.to_string()));
+
+                    for waiter in batch.waiters {
+                        let _ = waiter.send(result.clone());
+                    }
+                }
+            });
+        }
+
+        Self { queue }
+    }
+
+    /// Queues `items` and waits until a writer has written them.
+    pub async fn write(&self, items: Vec<T>) -> Result<(), BatchError> {
+        let (waiter, done) = oneshot::channel();
+
+        if self.queue.send((items, waiter)).await.is_err() {
+            return Err("writers are gone".into());
+        }
+
+        done.await
+            .unwrap_or_else(|_| Err("queued write was dropped".into()))
+    }
+}
@@ -0,0 +1,45 @@
+//! WebSocket echo endpoint for persistent-connection workloads.
+//!
+//! The `/ws` handler echoes every text and binary message back to the sender. Frames and
+//! messages larger than `AXUM_TECHEMPOWER_WS_MAX_FRAME_SIZE` bytes (default
+//! 64 KiB) close the connection.
+
+use std::sync::OnceLock;
+
+use axum::{
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    response::Response,
+};
+
+use crate::utils::get_environment_variable_or;
+
+fn max_frame_size() -> usize {
+    static MAX: OnceLock<usize> = OnceLock::new();
+
+    *MAX.get_or_init(|| {
+        get_environment_variable_or("AXUM_TECHEMPOWER_WS_MAX_FRAME_SIZE", 64 * 1024)
+    })
+}
+
+pub async fn upgrade(ws: WebSocketUpgrade) -> Response {
+    let max = max_frame_size();
+
+    ws.max_frame_size(max)
+        .max_message_size(max)
+        .on_upgrade(echo)
+}
+
+async fn echo(mut socket: WebSocket) {
+    while let Some(Ok(message)) = socket.recv().await {
+        let reply = match message {
+            Message::Text(_) | Message::Binary(_) => message,
+            // pings are answered by the protocol layer
+            Message::Ping(_) | Message::Pong(_) => continue,
+            Message::Close(_) => break,
+        };
+
+        if socket.send(reply).await.is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Allocation counts of the `/plaintext` and `/json` hot paths.
+//!
+//! The `axum` binary is compiled into this test binary of its own, so that
+//! its global allocator, which counts the allocations made on the current
+//! thread while a measurement runs, wraps nothing else.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+include!("../src/main.rs");
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+fn count_allocation() {
+    let _ = ALLOCATIONS.try_with(|count| {
+        if let Some(allocations) = count.get() {
+            count.set(Some(allocations + 1));
+        }
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+mod allocations {
+    use axum::{body::Body, http::Request, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    /// Allocations made on this thread while serving `uri` through `router`
+    /// and reading the whole response body.
+    async fn allocations_per_request(router: &Router, uri: &str) -> usize {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let router = router.clone();
+
+        ALLOCATIONS.with(|count| count.set(Some(0)));
+        let response = router.oneshot(request).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let allocations = ALLOCATIONS.with(|count| count.take()).unwrap();
+
+        drop(body);
+        allocations
+    }
+
+    /// Allocations of each request to `uri` once warmed up, which must not
+    /// vary between requests.
+    async fn steady_allocations(router: &Router, uri: &str) -> usize {
+        allocations_per_request(router, uri).await;
+
+        let allocations = allocations_per_request(router, uri).await;
+        for _ in 0..10 {
+            assert_eq!(
+                allocations_per_request(router, uri).await,
+                allocations,
+                "{uri}"
+            );
+        }
+        allocations
+    }
+
+    /// The routes of the `axum` binary, next to one answering a static string,
+    /// for what routing and boxing the response body cost on their own.
+    fn router() -> Router {
+        routes()
+            .into_router()
+            .route("/baseline", get(|| async { "Hello, World!" }))
+    }
+
+    #[tokio::test]
+    async fn plaintext_allocates_nothing_beyond_routing() {
+        let router = router();
+        let baseline = steady_allocations(&router, "/baseline").await;
+
+        assert!(steady_allocations(&router, "/plaintext").await <= baseline);
+    }
+
+    #[tokio::test]
+    async fn json_allocates_only_its_buffer() {
+        let router = router();
+        let baseline = steady_allocations(&router, "/baseline").await;
+
+        assert!(steady_allocations(&router, "/json").await <= baseline + 1);
+    }
+}